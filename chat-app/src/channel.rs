@@ -1,227 +1,829 @@
-use std::{io, net::SocketAddr, sync::Arc};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+use std::{net::SocketAddr, sync::Arc};
 
 use dashmap::DashMap;
-use futures::SinkExt;
 use serde::{Deserialize, Serialize};
-use tokio::net::TcpListener;
-use tokio::{net::TcpStream, sync::mpsc};
-use tokio_util::codec::{Framed, LinesCodec};
+use tokio::sync::mpsc;
 
 use anyhow::{Context, Result};
 
-use crate::utils::send_to;
 use crate::{
-    config::SERVER_DEFAULT_IP_ADDRESS,
-    database::ChatDatabase,
-    messages::{ServerMessage, UserMessage},
-    utils::{get_next_user_message, ChatError},
+    config::{MAX_DESCRIPTION_LENGTH, MAX_REACTION_LENGTH, MAX_TOPIC_LENGTH},
+    database::ChatStore,
+    messages::{ChatMessage, MessageId, PinnedMessage, ServerMessage},
+    moderation::SanctionKind,
+    presence::Status,
+    utils::{unix_timestamp, ChatError},
 };
 
-type Tx = mpsc::UnboundedSender<String>;
-type Rx = mpsc::UnboundedReceiver<String>;
+// Outgoing queue of a single client connection, holds already encoded ServerMessages
+pub type Tx = mpsc::UnboundedSender<String>;
+pub type Rx = mpsc::UnboundedReceiver<String>;
 
+// Broadcast group of users currently in the channel.
+// All channels are served over the main server connection, peers are identified by its address,
+// so one connection can be a peer of many channels.
 #[derive(Debug)]
-pub struct Channel {
+pub struct Channel<S: ChatStore> {
     name: String,
-    listener: TcpListener,
-    shared: Arc<Shared>,
+    owner: Option<String>,
+    visibility: Visibility,
+    topic: RwLock<ChannelTopic>,
+    // archived channel is read only, nobody can join it or post in it
+    archived_at: RwLock<Option<i64>>,
+    // set once channel was renamed or deleted and is no longer served
+    closed: AtomicBool,
+    shared: Shared<S>,
 }
 
-impl Channel {
-    pub async fn new(name: String, chat_db: Arc<ChatDatabase>) -> Channel {
-        let free_port = portpicker::pick_unused_port().expect("No ports free");
-        let listener = TcpListener::bind((SERVER_DEFAULT_IP_ADDRESS, free_port))
-            .await
-            .unwrap_or_else(|_| panic!("Error starting channel {}!", name));
+impl<S: ChatStore> Channel<S> {
+    pub fn new(stored: StoredChannel, chat_db: Arc<S>) -> Channel<S> {
         Channel {
-            name,
-            listener,
-            shared: Arc::new(Shared::new(chat_db)),
+            name: stored.name,
+            owner: stored.owner,
+            visibility: stored.visibility,
+            topic: RwLock::new(stored.topic),
+            archived_at: RwLock::new(stored.archived_at),
+            closed: AtomicBool::new(false),
+            shared: Shared::new(chat_db),
         }
     }
 
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn is_owner(&self, user_name: &str) -> bool {
+        self.owner.as_deref() == Some(user_name)
+    }
+
+    pub fn visibility(&self) -> Visibility {
+        self.visibility
+    }
+
+    pub fn archived_at(&self) -> Option<i64> {
+        *self.archived_at.read().unwrap()
+    }
+
+    pub fn topic(&self) -> ChannelTopic {
+        self.topic.read().unwrap().clone()
+    }
+
     pub fn get_channel_info(&self) -> ChannelInfo {
         ChannelInfo {
             name: self.name.clone(),
-            address: self
-                .listener
-                .local_addr()
-                .expect("Error converting channel address to std::net::SocketAddr"),
+            owner: self.owner.clone(),
+            visibility: self.visibility,
+            topic: self.topic(),
+            archived_at: self.archived_at(),
+            activity: ChannelActivity::default(),
         }
     }
 
-    pub async fn listen(self: Arc<Self>) -> Result<()> {
-        loop {
-            let me = Arc::clone(&self);
-            let (stream, addr) = me
-                .to_owned()
-                .listener
-                .accept()
-                .await
-                .context(format!("[channel {}] Error in accept loop", me.name))?;
-
-            let state = Arc::clone(&me.shared);
-
-            tokio::spawn(async move {
-                tracing::debug!("[{}] accepted connection {:?}", me.name, addr);
-                if let Err(e) = Channel::handle_connection(&me.name, state, stream, addr).await {
-                    tracing::info!("[{}] an error occurred; error = {:?}", me.name, e);
-                }
-            });
+    // Fails if channel cannot take new peers or messages
+    fn check_open(&self) -> Result<(), ChatError> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(ChatError::NoSuchChannel);
+        }
+        if self.archived_at().is_some() {
+            return Err(ChatError::ChannelArchived);
         }
+        Ok(())
     }
 
-    async fn handle_connection(
-        name: &str,
-        state: Arc<Shared>,
-        stream: TcpStream,
-        addr: SocketAddr,
+    // Fails with Banned or Muted if user is under such sanction here or on the whole server
+    async fn check_not_sanctioned(
+        &self,
+        user_name: &str,
+        kind: SanctionKind,
     ) -> Result<(), ChatError> {
-        let mut lines = Framed::new(stream, LinesCodec::new());
-
-        let user_name = match get_next_user_message(&mut lines).await {
-            Some(Ok(UserMessage::Join { token })) => {
-                if state.chat_db.authorize_connection(&token) {
-                    tracing::info!("[{}] new authenticated connection from {}", name, addr);
-                    token.user_name.clone()
-                } else {
-                    tracing::info!("[{}] unauthenticated connection from {}", name, addr);
-                    return Err(ChatError::UnauthenticatedConnection);
-                }
+        let sanction = self
+            .shared
+            .chat_db
+            .get_active_sanction(user_name, Some(&self.name), kind)
+            .await?;
+        match sanction {
+            None => Ok(()),
+            Some(sanction) if kind == SanctionKind::Mute => Err(ChatError::Muted {
+                reason: sanction.reason,
+            }),
+            Some(sanction) => Err(ChatError::Banned {
+                reason: sanction.reason,
+            }),
+        }
+    }
+
+    // Public channels are visible to everybody, private ones only to their members
+    pub async fn is_visible_to(&self, user_name: &str) -> Result<bool, ChatError> {
+        match self.visibility {
+            Visibility::Public => Ok(true),
+            Visibility::Private => {
+                self.shared
+                    .chat_db
+                    .is_channel_member(&self.name, user_name)
+                    .await
             }
-            _ => return Err(ChatError::InvalidMessage),
+        }
+    }
+
+    // Sends user what they missed and starts forwarding channel messages to their connection.
+    // Others are told about the user only when it is their first connection in the channel.
+    // Private channel pretends not to exist for users who are not its members.
+    pub async fn join(&self, user_name: &str, addr: SocketAddr, tx: Tx) -> Result<(), ChatError> {
+        if !self.is_visible_to(user_name).await? {
+            return Err(ChatError::NoSuchChannel);
+        }
+        self.check_open()?;
+        self.check_not_sanctioned(user_name, SanctionKind::Ban)
+            .await?;
+        self.send_pins(&tx).await?;
+        self.send_unseen_messages(&tx, user_name).await?;
+        let first_connection = !self.has_member(user_name);
+        self.shared.peers.insert(
+            addr,
+            Peer {
+                user_name: user_name.to_string(),
+                tx,
+            },
+        );
+        tracing::info!("[{}] {} joined from {}", self.name, user_name, addr);
+
+        if first_connection {
+            let message = ServerMessage::MemberJoined {
+                channel: self.name.clone(),
+                user_name: user_name.to_string(),
+            };
+            self.shared.broadcast(Some(addr), &encode(&message)?).await;
+        }
+        Ok(())
+    }
+
+    // Stops forwarding messages to connection.
+    // What user has seen is kept by read markers, so nothing is saved here.
+    pub async fn leave(&self, user_name: &str, addr: SocketAddr) -> Result<(), ChatError> {
+        if self.shared.peers.remove(&addr).is_none() {
+            return Ok(());
+        }
+        tracing::info!("[{}] {} left from {}", self.name, user_name, addr);
+        if !self.has_member(user_name) {
+            let message = ServerMessage::MemberLeft {
+                channel: self.name.clone(),
+                user_name: user_name.to_string(),
+            };
+            self.shared.broadcast(None, &encode(&message)?).await;
+        }
+        Ok(())
+    }
+
+    // Disconnects every connection of the user, caller tells them why.
+    // Returns false if user was not in the channel.
+    pub async fn kick(&self, user_name: &str) -> Result<bool, ChatError> {
+        if !self.has_member(user_name) {
+            return Ok(false);
+        }
+        self.shared
+            .peers
+            .retain(|_, peer| peer.user_name != user_name);
+        tracing::info!("[{}] {} was disconnected", self.name, user_name);
+        let message = ServerMessage::MemberLeft {
+            channel: self.name.clone(),
+            user_name: user_name.to_string(),
         };
+        self.shared.broadcast(None, &encode(&message)?).await;
+        Ok(true)
+    }
 
-        Channel::send_unseen_messages(&mut lines, &state, name, &user_name).await?;
+    // False once the connection left or was disconnected by the channel
+    pub fn has_peer(&self, addr: SocketAddr) -> bool {
+        self.shared.peers.contains_key(&addr)
+    }
 
-        let mut peer = Peer::new(state.clone(), lines)
-            .await
-            .map_err(|_| ChatError::RuntimeError)?;
-        match serde_json::to_string(&ServerMessage::TextMessage {
-            content: format!("{} has joined!", user_name),
-        }) {
-            Ok(msg) => state.broadcast(addr, &msg).await,
-            _ => return Err(ChatError::RuntimeError),
+    pub fn has_member(&self, user_name: &str) -> bool {
+        self.shared
+            .peers
+            .iter()
+            .any(|peer| peer.value().user_name == user_name)
+    }
+
+    // Users with at least one connection in the channel
+    pub fn member_names(&self) -> BTreeSet<String> {
+        self.shared
+            .peers
+            .iter()
+            .map(|peer| peer.value().user_name.clone())
+            .collect()
+    }
+
+    pub async fn notify_status(&self, user_name: &str, status: Status) -> Result<(), ChatError> {
+        let message = ServerMessage::StatusChanged {
+            channel: self.name.clone(),
+            user_name: user_name.to_string(),
+            status,
         };
+        self.shared.broadcast(None, &encode(&message)?).await;
+        Ok(())
+    }
 
-        loop {
-            tokio::select! {
-                Some(channel_member_message) = peer.rx.recv() => {
-                    peer.lines.send(&channel_member_message).await.map_err(|_| ChatError::RuntimeError)?;
-                }
-                user_message = get_next_user_message(&mut peer.lines) => match user_message {
-                    Some(Ok(UserMessage::TextMessage { token , content  })) => {
-                        if state.chat_db.authorize_connection(&token) {
-                            Channel::save_message(&state, name, &user_name, &content).await?;
-                            let formetted_message = format!("[{}] {}", token.user_name, content);
-                            if let Ok(encoded_message) = serde_json::to_string(&ServerMessage::TextMessage{content : formetted_message}) {
-                                state.broadcast(addr, &encoded_message).await;
-                            }
-                        }
-                        else {
-                            tracing::info!("[{}] unauthenticated connection from {}",name, addr);
-                            return Err(ChatError::UnauthenticatedConnection);
-                        }
-                    },
-                    _ => {
-                        Channel::save_history(&state, name, &user_name).await?;
-                        tracing::info!("[{}] invalid message from {}, disconnecting",name, addr);
-                        return Err(ChatError::InvalidMessage);
-                    },
-                },
-            }
+    // Changes given parts of the topic, saves it and tells everybody in the channel.
+    // Caller checks that user may do it.
+    pub async fn set_topic(
+        &self,
+        user_name: &str,
+        topic: Option<String>,
+        description: Option<String>,
+    ) -> Result<(), ChatError> {
+        self.check_open()?;
+        let mut changed = self.topic();
+        if let Some(topic) = topic {
+            changed.topic = topic;
+        }
+        if let Some(description) = description {
+            changed.description = description;
         }
+        if changed.topic.chars().count() > MAX_TOPIC_LENGTH
+            || changed.description.chars().count() > MAX_DESCRIPTION_LENGTH
+        {
+            return Err(ChatError::InvalidMessage);
+        }
+        self.shared
+            .chat_db
+            .set_channel_topic(&self.name, &changed)
+            .await?;
+        *self.topic.write().unwrap() = changed.clone();
+        tracing::info!("[{}] {} changed topic", self.name, user_name);
+
+        let message = ServerMessage::TopicChanged {
+            channel: self.name.clone(),
+            topic: changed,
+            changed_by: user_name.to_string(),
+        };
+        self.shared.broadcast(None, &encode(&message)?).await;
+        Ok(())
     }
 
-    async fn save_message(
-        state: &Arc<Shared>,
-        channel_name: &str,
+    // Message goes back to the sender as well, so they learn its id and can mark it read
+    // returns the saved message
+    pub async fn send_message(
+        &self,
         user_name: &str,
-        message: &str,
+        content: &str,
+    ) -> Result<ChatMessage, ChatError> {
+        self.check_open()?;
+        self.check_not_sanctioned(user_name, SanctionKind::Mute)
+            .await?;
+        let message = self
+            .shared
+            .chat_db
+            .save_message(&self.name, user_name, None, content)
+            .await?;
+        let update = ServerMessage::ChannelMessage {
+            message: message.clone(),
+        };
+        self.shared.broadcast(None, &encode(&update)?).await;
+        Ok(message)
+    }
+
+    // Reply goes to everybody in the channel like any other message,
+    // followed by the first message of its thread with the new reply count. Returns the saved reply.
+    pub async fn reply(
+        &self,
+        user_name: &str,
+        parent_id: MessageId,
+        content: &str,
+    ) -> Result<ChatMessage, ChatError> {
+        self.check_open()?;
+        self.check_not_sanctioned(user_name, SanctionKind::Mute)
+            .await?;
+        let parent = self.get_live_message(parent_id).await?;
+        let thread_id = parent.parent_id.unwrap_or(parent.id);
+        let message = self
+            .shared
+            .chat_db
+            .save_message(&self.name, user_name, Some(thread_id), content)
+            .await?;
+        let reply = message.clone();
+        let message = ServerMessage::ChannelMessage { message };
+        self.shared.broadcast(None, &encode(&message)?).await;
+        if let Some(message) = self
+            .shared
+            .chat_db
+            .get_message(&self.name, thread_id)
+            .await?
+        {
+            let message = ServerMessage::ThreadUpdated { message };
+            self.shared.broadcast(None, &encode(&message)?).await;
+        }
+        Ok(reply)
+    }
+
+    // Authors can edit their messages, whoever may moderate the channel anybody's,
+    // muted users cannot edit at all
+    pub async fn edit_message(
+        &self,
+        user_name: &str,
+        message_id: MessageId,
+        content: &str,
+        may_moderate: bool,
     ) -> Result<(), ChatError> {
-        state
+        self.check_open()?;
+        self.check_not_sanctioned(user_name, SanctionKind::Mute)
+            .await?;
+        if self.get_live_message(message_id).await?.author != user_name && !may_moderate {
+            return Err(ChatError::Forbidden);
+        }
+        let message = self
+            .shared
             .chat_db
-            .save_message(channel_name, user_name, message)
-            .await
+            .edit_message(&self.name, message_id, user_name, content)
+            .await?;
+        tracing::info!(
+            "[{}] {} edited message {}",
+            self.name,
+            user_name,
+            message_id
+        );
+        let message = ServerMessage::MessageEdited { message };
+        self.shared.broadcast(None, &encode(&message)?).await;
+        Ok(())
     }
 
-    async fn save_history(
-        state: &Arc<Shared>,
-        channel_name: &str,
+    // Authors can delete their messages, whoever may moderate the channel anybody's
+    pub async fn delete_message(
+        &self,
         user_name: &str,
+        message_id: MessageId,
+        may_moderate: bool,
     ) -> Result<(), ChatError> {
-        state.chat_db.save_history(channel_name, user_name).await
+        self.check_open()?;
+        if self.get_live_message(message_id).await?.author != user_name && !may_moderate {
+            return Err(ChatError::Forbidden);
+        }
+        self.shared
+            .chat_db
+            .delete_message(&self.name, message_id, user_name)
+            .await?;
+        tracing::info!(
+            "[{}] {} deleted message {}",
+            self.name,
+            user_name,
+            message_id
+        );
+        let message = ServerMessage::MessageDeleted {
+            channel: self.name.clone(),
+            message_id,
+            deleted_by: user_name.to_string(),
+        };
+        self.shared.broadcast(None, &encode(&message)?).await;
+        Ok(())
+    }
+
+    // Only whoever may moderate the channel pins and unpins, everybody in it is told when anything changed
+    pub async fn pin(
+        &self,
+        user_name: &str,
+        message_id: MessageId,
+        pinned: bool,
+        may_moderate: bool,
+    ) -> Result<(), ChatError> {
+        self.check_open()?;
+        if !may_moderate {
+            return Err(ChatError::Forbidden);
+        }
+        let message = if pinned {
+            if !self
+                .shared
+                .chat_db
+                .pin_message(&self.name, message_id, user_name)
+                .await?
+            {
+                return Ok(());
+            }
+            let pin = self
+                .pins()
+                .await?
+                .into_iter()
+                .find(|pin| pin.message.id == message_id)
+                .ok_or(ChatError::NoSuchMessage)?;
+            ServerMessage::MessagePinned {
+                channel: self.name.clone(),
+                pin,
+            }
+        } else {
+            if !self
+                .shared
+                .chat_db
+                .unpin_message(&self.name, message_id)
+                .await?
+            {
+                return Ok(());
+            }
+            ServerMessage::MessageUnpinned {
+                channel: self.name.clone(),
+                message_id,
+                unpinned_by: user_name.to_string(),
+            }
+        };
+        tracing::info!(
+            "[{}] {} {} message {}",
+            self.name,
+            user_name,
+            if pinned { "pinned" } else { "unpinned" },
+            message_id
+        );
+        self.shared.broadcast(None, &encode(&message)?).await;
+        Ok(())
+    }
+
+    pub async fn pins(&self) -> Result<Vec<PinnedMessage>, ChatError> {
+        self.shared.chat_db.get_pinned_messages(&self.name).await
     }
 
-    async fn send_unseen_messages(
-        lines: &mut Framed<TcpStream, LinesCodec>,
-        state: &Arc<Shared>,
-        channel_name: &str,
+    // Tells everybody in the channel how the message is reacted to, if the reaction changed anything.
+    // Muted users cannot add reactions, but they can take theirs back.
+    pub async fn react(
+        &self,
         user_name: &str,
-    ) -> Result<()> {
-        let unseen_messages = state
+        message_id: MessageId,
+        reaction: &str,
+        add: bool,
+    ) -> Result<(), ChatError> {
+        self.check_open()?;
+        let changed = if add {
+            if reaction.is_empty()
+                || reaction.chars().count() > MAX_REACTION_LENGTH
+                || reaction.chars().any(char::is_whitespace)
+            {
+                return Err(ChatError::InvalidMessage);
+            }
+            self.check_not_sanctioned(user_name, SanctionKind::Mute)
+                .await?;
+            self.shared
+                .chat_db
+                .add_reaction(&self.name, message_id, user_name, reaction)
+                .await?
+        } else {
+            self.shared
+                .chat_db
+                .remove_reaction(&self.name, message_id, user_name, reaction)
+                .await?
+        };
+        if !changed {
+            return Ok(());
+        }
+        let reactions = self
+            .shared
+            .chat_db
+            .get_message(&self.name, message_id)
+            .await?
+            .map(|message| message.reactions)
+            .unwrap_or_default();
+        let message = ServerMessage::ReactionsChanged {
+            channel: self.name.clone(),
+            message_id,
+            reactions,
+        };
+        self.shared.broadcast(None, &encode(&message)?).await;
+        Ok(())
+    }
+
+    // Message of the channel which was not deleted
+    async fn get_live_message(&self, message_id: MessageId) -> Result<ChatMessage, ChatError> {
+        self.shared
             .chat_db
-            .get_unseed_messages(channel_name, user_name)
+            .get_message(&self.name, message_id)
+            .await?
+            .filter(|message| message.deleted_at.is_none())
+            .ok_or(ChatError::NoSuchMessage)
+    }
+
+    // Tells every peer why they are no longer in the channel and stops forwarding messages to them
+    async fn disconnect_all(&self, reason: ClosedReason) -> Result<(), ChatError> {
+        let message = ServerMessage::ChannelClosed {
+            channel: self.name.clone(),
+            reason,
+        };
+        self.shared.broadcast(None, &encode(&message)?).await;
+        self.shared.peers.clear();
+        tracing::info!("[{}] disconnected all peers", self.name);
+        Ok(())
+    }
+
+    async fn archive(&self, archived_at: i64) -> Result<(), ChatError> {
+        *self.archived_at.write().unwrap() = Some(archived_at);
+        self.disconnect_all(ClosedReason::Archived).await
+    }
+
+    // Channel is no longer served under its name
+    async fn close(&self, reason: ClosedReason) -> Result<(), ChatError> {
+        self.closed.store(true, Ordering::SeqCst);
+        self.disconnect_all(reason).await
+    }
+
+    async fn send_pins(&self, tx: &Tx) -> Result<(), ChatError> {
+        let message = ServerMessage::Pins {
+            channel: self.name.clone(),
+            pins: self.pins().await?,
+        };
+        tx.send(encode(&message)?)
+            .map_err(|_| ChatError::RuntimeError)
+    }
+
+    async fn send_unseen_messages(&self, tx: &Tx, user_name: &str) -> Result<(), ChatError> {
+        let unseen_messages = self
+            .shared
+            .chat_db
+            .get_unseed_messages(&self.name, user_name)
             .await?;
-        for (user, content) in unseen_messages.into_iter() {
-            let message = format!("[{}] {}", user, content);
-            send_to(lines, &ServerMessage::TextMessage { content: message }).await?
+        for message in unseen_messages.into_iter() {
+            let message = ServerMessage::ChannelMessage { message };
+            tx.send(encode(&message)?)
+                .map_err(|_| ChatError::RuntimeError)?;
         }
         Ok(())
     }
 }
 
+fn encode(message: &ServerMessage) -> Result<String, ChatError> {
+    serde_json::to_string(message).map_err(|_| ChatError::RuntimeError)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Visibility {
+    Public,
+    Private,
+}
+
+impl Visibility {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Visibility::Public => "public",
+            Visibility::Private => "private",
+        }
+    }
+}
+
+impl FromStr for Visibility {
+    type Err = ChatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "public" => Ok(Visibility::Public),
+            "private" => Ok(Visibility::Private),
+            _ => Err(ChatError::InvalidMessage),
+        }
+    }
+}
+
+impl fmt::Display for Visibility {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// Channel as saved in database
+#[derive(Debug, Clone)]
+pub struct StoredChannel {
+    pub name: String,
+    // None for channels created before owners were recorded
+    pub owner: Option<String>,
+    pub visibility: Visibility,
+    pub topic: ChannelTopic,
+    // unix seconds
+    pub archived_at: Option<i64>,
+}
+
+// What channel is about, both parts are empty until somebody sets them
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChannelTopic {
+    // one line shown next to channel name
+    pub topic: String,
+    pub description: String,
+}
+
+// Why peers were disconnected from a channel
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ClosedReason {
+    // channel with its history and members is now served under new_name
+    Renamed { new_name: String },
+    // history can still be read, but nobody can join or post
+    Archived,
+    // channel is gone together with its messages
+    Deleted,
+}
+
+// Pending invitation to a private channel, as seen by the invited user
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChannelInvitation {
+    pub channel: String,
+    pub invited_by: String,
+    // unix seconds
+    pub created_at: i64,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ChannelInfo {
     pub name: String,
-    pub address: std::net::SocketAddr,
+    pub owner: Option<String>,
+    pub visibility: Visibility,
+    pub topic: ChannelTopic,
+    // unix seconds, None for channels that are not archived
+    pub archived_at: Option<i64>,
+    // as seen by the user who asked for channels
+    pub activity: ChannelActivity,
+}
+
+// What happened in channel from the point of view of one user, based on their read marker
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChannelActivity {
+    // replies are not counted, threads have read markers of their own
+    pub unread_count: i64,
+    // unix seconds of the newest message, None for empty channel
+    pub last_activity: Option<i64>,
+    // mentions of the user not read yet
+    pub mention_count: i64,
+}
+
+#[derive(Debug)]
+struct Peer {
+    user_name: String,
+    tx: Tx,
 }
 
 #[derive(Debug)]
-struct Shared {
-    peers: DashMap<SocketAddr, Tx>,
-    chat_db: Arc<ChatDatabase>,
+struct Shared<S: ChatStore> {
+    peers: DashMap<SocketAddr, Peer>,
+    chat_db: Arc<S>,
 }
 
-impl Shared {
-    fn new(chat_db: Arc<ChatDatabase>) -> Self {
+impl<S: ChatStore> Shared<S> {
+    fn new(chat_db: Arc<S>) -> Self {
         Shared {
             peers: DashMap::new(),
             chat_db,
         }
     }
 
-    async fn broadcast(&self, sender: SocketAddr, message: &str) {
+    // Sends message to every peer except the given one
+    async fn broadcast(&self, except: Option<SocketAddr>, message: &str) {
         for peer in self.peers.iter_mut() {
-            if *peer.key() != sender {
-                let _ = peer.value().send(message.into());
+            if Some(*peer.key()) != except {
+                let _ = peer.value().tx.send(message.into());
             }
         }
     }
 }
 
-struct Peer {
-    lines: Framed<TcpStream, LinesCodec>,
-    state: Arc<Shared>,
-    rx: Rx,
+// All channels served by the server, by name
+#[derive(Debug)]
+pub struct Channels<S: ChatStore> {
+    channels: RwLock<BTreeMap<String, Arc<Channel<S>>>>,
+    chat_db: Arc<S>,
 }
 
-impl Peer {
-    async fn new(state: Arc<Shared>, lines: Framed<TcpStream, LinesCodec>) -> io::Result<Peer> {
-        let addr = lines.get_ref().peer_addr()?;
+impl<S: ChatStore> Channels<S> {
+    // Channels saved in database
+    pub async fn load(chat_db: Arc<S>) -> Result<Channels<S>> {
+        let stored_channels = chat_db
+            .get_channels()
+            .await
+            .context("Error getting channels from db")?;
+        let channels = Channels {
+            channels: RwLock::new(BTreeMap::new()),
+            chat_db,
+        };
+        for stored in stored_channels {
+            channels.add(stored);
+        }
+        Ok(channels)
+    }
 
-        let (tx, rx) = mpsc::unbounded_channel();
+    // Saves new channel in database and starts serving it.
+    // Owner of a private channel is its first member.
+    pub async fn create(&self, name: &str, owner: &str, visibility: Visibility) -> Result<()> {
+        self.chat_db.create_channel(name, owner, visibility).await?;
+        self.add(StoredChannel {
+            name: name.to_string(),
+            owner: Some(owner.to_string()),
+            visibility,
+            topic: ChannelTopic::default(),
+            archived_at: None,
+        });
+        Ok(())
+    }
 
-        state.peers.insert(addr, tx);
+    // Moves channel with its history, read markers and members to new_name.
+    // Peers are disconnected and have to join again under the new name.
+    pub async fn rename(&self, name: &str, new_name: &str) -> Result<(), ChatError> {
+        let channel = self.get(name).ok_or(ChatError::NoSuchChannel)?;
+        self.chat_db.rename_channel(name, new_name).await?;
+        let renamed = StoredChannel {
+            name: new_name.to_string(),
+            owner: channel.owner.clone(),
+            visibility: channel.visibility,
+            topic: channel.topic(),
+            archived_at: channel.archived_at(),
+        };
+        {
+            let mut channels = self.channels.write().unwrap();
+            channels.remove(name);
+            channels.insert(
+                new_name.to_string(),
+                Arc::new(Channel::new(renamed, Arc::clone(&self.chat_db))),
+            );
+        }
+        tracing::info!("Renamed channel {} to {}", name, new_name);
+        channel
+            .close(ClosedReason::Renamed {
+                new_name: new_name.to_string(),
+            })
+            .await
+    }
 
-        Ok(Peer { lines, state, rx })
+    // Makes channel read only, its messages are kept
+    pub async fn archive(&self, name: &str) -> Result<(), ChatError> {
+        let channel = self.get(name).ok_or(ChatError::NoSuchChannel)?;
+        if channel.archived_at().is_some() {
+            return Err(ChatError::ChannelArchived);
+        }
+        let archived_at = unix_timestamp();
+        self.chat_db.archive_channel(name, archived_at).await?;
+        tracing::info!("Archived channel {}", name);
+        channel.archive(archived_at).await
     }
-}
 
-impl Drop for Peer {
-    fn drop(&mut self) {
-        self.state
-            .peers
-            .remove(&self.lines.get_ref().peer_addr().unwrap());
+    // Removes channel together with its messages, read markers, members and invitations
+    pub async fn delete(&self, name: &str) -> Result<(), ChatError> {
+        let channel = self.get(name).ok_or(ChatError::NoSuchChannel)?;
+        self.chat_db.delete_channel(name).await?;
+        self.channels.write().unwrap().remove(name);
+        tracing::info!("Deleted channel {}", name);
+        channel.close(ClosedReason::Deleted).await
+    }
+
+    fn add(&self, stored: StoredChannel) {
+        let name = stored.name.clone();
+        let channel = Arc::new(Channel::new(stored, Arc::clone(&self.chat_db)));
+        tracing::info!("Created channel: {:?}", channel.get_channel_info());
+        self.channels.write().unwrap().insert(name, channel);
+    }
+
+    // Tells every channel the user is in about their new status
+    pub async fn notify_status(&self, user_name: &str, status: Status) -> Result<(), ChatError> {
+        let channels: Vec<Arc<Channel<S>>> =
+            self.channels.read().unwrap().values().cloned().collect();
+        for channel in channels {
+            if channel.has_member(user_name) {
+                channel.notify_status(user_name, status).await?;
+            }
+        }
+        Ok(())
+    }
+
+    // Disconnects user from every channel they are in, e.g. after server wide ban
+    pub async fn kick_everywhere(&self, user_name: &str) -> Result<(), ChatError> {
+        let channels: Vec<Arc<Channel<S>>> =
+            self.channels.read().unwrap().values().cloned().collect();
+        for channel in channels {
+            channel.kick(user_name).await?;
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<Channel<S>>> {
+        self.channels.read().unwrap().get(name).cloned()
+    }
+
+    pub fn infos(&self) -> Vec<ChannelInfo> {
+        self.channels
+            .read()
+            .unwrap()
+            .values()
+            .map(|channel| channel.get_channel_info())
+            .collect()
+    }
+
+    // Infos of channels visible to given user, with their activity
+    pub async fn infos_for(&self, user_name: &str) -> Result<Vec<ChannelInfo>, ChatError> {
+        let member_channels: HashSet<String> = self
+            .chat_db
+            .get_member_channels(user_name)
+            .await?
+            .into_iter()
+            .collect();
+        let mut activities = self.chat_db.get_channels_activity(user_name).await?;
+        let mut infos = self.infos();
+        infos.retain(|info| {
+            info.visibility == Visibility::Public || member_channels.contains(&info.name)
+        });
+        for info in infos.iter_mut() {
+            if let Some(activity) = activities.remove(&info.name) {
+                info.activity = activity;
+            }
+        }
+        Ok(infos)
     }
 }
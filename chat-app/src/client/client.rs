@@ -1,15 +1,25 @@
+use chat_app::channel::{ChannelInfo, ChannelInvitation, ChannelTopic, ClosedReason, Visibility};
 use chat_app::config::{SERVER_DEFAULT_IP_ADDRESS, SERVER_DEFAULT_PORT};
 use chat_app::database::AuthenticationToken;
-use chat_app::messages::{ServerMessage, UserMessage};
+use chat_app::direct::{ConversationId, DirectMessage};
+use chat_app::messages::{
+    ChatMessage, ErrorKind, MessageId, ReactionCount, ServerMessage, UserMessage,
+};
+use chat_app::moderation::{Sanction, SanctionKind};
+use chat_app::permissions::Role;
+use chat_app::presence::Status;
+use chat_app::search::SearchQuery;
 use chat_app::utils::{get_next_server_message, send_to, ChatError};
 
+use std::collections::HashMap;
 use std::env;
 use std::net::SocketAddr;
 use std::process::exit;
+use std::time::Duration;
 
 use async_std::io::{self, WriteExt};
-
 use futures::SinkExt;
+
 use tokio::net::{TcpSocket, TcpStream};
 use tokio::sync::mpsc::{self, UnboundedReceiver};
 use tokio_util::codec::{Framed, LinesCodec};
@@ -33,13 +43,30 @@ async fn main() -> Result<()> {
             .context("Error in login")?
             .unwrap();
 
-        handle_config(&mut server_lines, &token, &mut ctrlc_channel, &stdin).await?;
+        if let Err(e) = run_session(&mut server_lines, &token, &mut ctrlc_channel, &stdin).await {
+            match e.downcast_ref::<ChatError>() {
+                Some(ChatError::SessionExpired) => println!("{}", e),
+                _ => return Err(e),
+            }
+        }
+    }
+}
 
-        let channel_addr = choose_channel(&mut server_lines, &stdin, &token).await?;
+// Everything done with a single token, fails with SessionExpired when it is time to log in again
+async fn run_session(
+    server_lines: &mut Framed<TcpStream, LinesCodec>,
+    token: &AuthenticationToken,
+    ctrlc_channel: &mut UnboundedReceiver<()>,
+    stdin: &io::Stdin,
+) -> Result<()> {
+    loop {
+        handle_config(server_lines, token, ctrlc_channel, stdin).await?;
 
-        let channel_lines = connect_to_channel(channel_addr, &token).await?;
+        let channel = choose_channel(server_lines, stdin, token).await?;
 
-        message_loop(channel_lines, &token, &mut ctrlc_channel, &stdin).await?;
+        if join_channel(server_lines, &channel, token).await? {
+            message_loop(server_lines, token, channel, ctrlc_channel, stdin).await?;
+        }
     }
 }
 
@@ -64,7 +91,16 @@ async fn handle_config(
             = 0 - create new user               =\n
             = 1 - create new channel            =\n
             = 2 - choose channel                =\n
-            = 3 - exit                          =\n
+            = 3 - list sessions                 =\n
+            = 4 - revoke session                =\n
+            = 5 - grant role                    =\n
+            = 6 - revoke role                   =\n
+            = 7 - logout                        =\n
+            = 8 - exit                          =\n
+            = 9 - direct conversations          =\n
+            = 10 - channel invitations          =\n
+            = 11 - rename, archive, delete      =\n
+            = 12 - ban user from server         =\n
             =====================================\n
         "
         );
@@ -74,7 +110,16 @@ async fn handle_config(
             0 => create_user(server_lines, token, ctrlc_channel, stdin).await?,
             1 => create_channel(server_lines, token, ctrlc_channel, stdin).await?,
             2 => return Ok(()),
-            3 => exit(0),
+            3 => list_sessions(server_lines, token).await?,
+            4 => revoke_session(server_lines, token, stdin).await?,
+            5 => change_role(server_lines, token, stdin, true).await?,
+            6 => change_role(server_lines, token, stdin, false).await?,
+            7 => logout(server_lines, token).await?,
+            8 => exit(0),
+            9 => list_conversations(server_lines, token).await?,
+            10 => answer_invitations(server_lines, token, stdin).await?,
+            11 => manage_channel(server_lines, token, stdin).await?,
+            12 => ban_from_server(server_lines, token, stdin).await?,
             n => tracing::debug!("Invalid option {}", n),
         }
     }
@@ -87,17 +132,17 @@ async fn create_channel(
     stdin: &io::Stdin,
 ) -> Result<()> {
     clear_screen();
-    let mut name: Option<String> = None;
+    let mut name: Option<(String, Visibility)> = None;
     let mut line = String::new();
     loop {
         clear_screen();
-        if name.is_some() {
+        if let Some((name, visibility)) = &name {
             println!(
-                "Entered name: {}, write OK to continue or CTRL-C to change",
-                name.as_ref().unwrap()
+                "Entered name: {} ({}), write OK to continue or CTRL-C to change",
+                name, visibility
             );
         } else {
-            println!("Enter channel name");
+            println!("Enter channel name, followed by 'private' for a channel only invited users can see");
         }
         tokio::select! {
             _ = ctrlc_channel.recv() => {
@@ -117,31 +162,29 @@ async fn create_channel(
                         name = None;
                     }
                 } else {
-                    name = Some(line.clone());
+                    name = match line.split_whitespace().collect::<Vec<&str>>()[..] {
+                        [name] => Some((name.to_string(), Visibility::Public)),
+                        [name, "private"] => Some((name.to_string(), Visibility::Private)),
+                        _ => None,
+                    };
                 }
             }
         }
         line.clear();
     }
 
+    let (name, visibility) = name.unwrap();
     send_to(
         server_lines,
         &UserMessage::CreateChannel {
             token: token.clone(),
-            name: name.unwrap(),
+            name,
+            visibility,
         },
     )
     .await?;
 
-    if let Some(Ok(ServerMessage::TextMessage { content })) =
-        get_next_server_message(server_lines).await
-    {
-        println!("{}", content);
-    } else {
-        tracing::debug!("Error creating channel");
-    }
-
-    Ok(())
+    print_response(server_lines, token).await
 }
 
 async fn create_user(
@@ -154,11 +197,10 @@ async fn create_user(
     let mut line = String::new();
     loop {
         clear_screen();
-        if user_data.is_some() {
+        if let Some((name, password)) = &user_data {
             println!(
                 "Entered name: {}, password: {}, write OK to continue or CTRL-C to change",
-                user_data.as_ref().unwrap().0,
-                user_data.as_ref().unwrap().1
+                name, password
             );
         } else {
             println!("Enter user name and password");
@@ -204,17 +246,354 @@ async fn create_user(
     )
     .await?;
 
-    if let Some(Ok(ServerMessage::TextMessage { content })) =
-        get_next_server_message(server_lines).await
-    {
-        println!("{}", content);
-    } else {
-        tracing::debug!("Error creating new user");
+    print_response(server_lines, token).await
+}
+
+// Prints server reply to a request, expired session is turned into an error
+async fn print_response(
+    server_lines: &mut Framed<TcpStream, LinesCodec>,
+    token: &AuthenticationToken,
+) -> Result<()> {
+    match next_response(server_lines, token).await {
+        Some(Ok(ServerMessage::TextMessage { content })) => println!("{}", content),
+        Some(Ok(ServerMessage::Error { kind, description })) => {
+            check_session(kind)?;
+            println!("{}", description);
+        }
+        _ => tracing::debug!("Invalid response from server"),
     }
+    Ok(())
+}
+
+// Next message that is not pushed by the server on its own.
+// Direct messages arriving in the meantime are shown and marked read.
+async fn next_response(
+    server_lines: &mut Framed<TcpStream, LinesCodec>,
+    token: &AuthenticationToken,
+) -> Option<Result<ServerMessage>> {
+    loop {
+        match get_next_server_message(server_lines).await {
+            Some(Ok(ServerMessage::DirectMessage { message })) => {
+                if let Err(e) = show_direct_message(server_lines, token, &message).await {
+                    return Some(Err(e));
+                }
+            }
+            Some(Ok(ServerMessage::Invited { invitation })) => {
+                println!("{}", render_invitation(&invitation))
+            }
+            Some(Ok(ServerMessage::Mentioned { message })) => {
+                println!("You were mentioned: {}", render_message(&message))
+            }
+            Some(Ok(ServerMessage::Sanctioned { sanction })) => {
+                println!("{}", render_sanction(&sanction));
+                if sanction.kind == SanctionKind::Ban && sanction.channel.is_none() {
+                    return Some(Err(ChatError::Banned {
+                        reason: sanction.reason,
+                    }
+                    .into()));
+                }
+            }
+            Some(Ok(
+                ServerMessage::ChannelMessage { .. }
+                | ServerMessage::ThreadUpdated { .. }
+                | ServerMessage::ReactionsChanged { .. }
+                | ServerMessage::MessageEdited { .. }
+                | ServerMessage::MessageDeleted { .. }
+                | ServerMessage::MessagePinned { .. }
+                | ServerMessage::MessageUnpinned { .. }
+                | ServerMessage::MemberJoined { .. }
+                | ServerMessage::MemberLeft { .. }
+                | ServerMessage::StatusChanged { .. }
+                | ServerMessage::TopicChanged { .. }
+                | ServerMessage::ChannelClosed { .. },
+            )) => tracing::debug!("Skipping channel event outside of channel"),
+            response => return response,
+        }
+    }
+}
+
+// e.g. "@3 12:03:45 [bob] hi", conversation id first so user can answer it
+async fn show_direct_message(
+    server_lines: &mut Framed<TcpStream, LinesCodec>,
+    token: &AuthenticationToken,
+    message: &DirectMessage,
+) -> Result<()> {
+    println!(
+        "@{} {} [{}] {}",
+        message.conversation_id,
+        format_time(message.created_at),
+        message.author,
+        message.body
+    );
+    send_to(
+        server_lines,
+        &UserMessage::MarkConversationRead {
+            token: token.clone(),
+            conversation_id: message.conversation_id,
+            message_id: message.id,
+        },
+    )
+    .await
+}
+
+fn check_session(kind: ErrorKind) -> Result<()> {
+    if kind == ErrorKind::SessionExpired {
+        bail!(ChatError::SessionExpired);
+    }
+    Ok(())
+}
+
+async fn list_sessions(
+    server_lines: &mut Framed<TcpStream, LinesCodec>,
+    token: &AuthenticationToken,
+) -> Result<()> {
+    send_to(
+        server_lines,
+        &UserMessage::ListSessions {
+            token: token.clone(),
+        },
+    )
+    .await?;
+
+    match next_response(server_lines, token).await {
+        Some(Ok(ServerMessage::Sessions { sessions })) => {
+            for session in sessions {
+                let current = if session.id == token.session_id {
+                    " (this one)"
+                } else {
+                    ""
+                };
+                println!(
+                    "{} issued at {}, last used at {}, expires at {}{}",
+                    session.id,
+                    session.issued_at,
+                    session.last_used_at,
+                    session.expires_at,
+                    current
+                );
+            }
+        }
+        Some(Ok(ServerMessage::Error { kind, description })) => {
+            check_session(kind)?;
+            println!("{}", description);
+        }
+        _ => tracing::debug!("Error listing sessions"),
+    }
+    Ok(())
+}
+
+async fn list_conversations(
+    server_lines: &mut Framed<TcpStream, LinesCodec>,
+    token: &AuthenticationToken,
+) -> Result<()> {
+    send_to(
+        server_lines,
+        &UserMessage::ListConversations {
+            token: token.clone(),
+        },
+    )
+    .await?;
 
+    match next_response(server_lines, token).await {
+        Some(Ok(ServerMessage::Conversations { conversations })) => {
+            for conversation in conversations {
+                let last_activity = conversation
+                    .last_activity
+                    .map_or("never".to_string(), format_time);
+                println!(
+                    "@{} with {} - {} unread, last message at {}",
+                    conversation.id,
+                    conversation.members.join(", "),
+                    conversation.unread_count,
+                    last_activity
+                );
+            }
+        }
+        Some(Ok(ServerMessage::Error { kind, description })) => {
+            check_session(kind)?;
+            println!("{}", description);
+        }
+        _ => tracing::debug!("Error listing conversations"),
+    }
     Ok(())
 }
 
+// Lists pending invitations and lets user answer one of them
+async fn answer_invitations(
+    server_lines: &mut Framed<TcpStream, LinesCodec>,
+    token: &AuthenticationToken,
+    stdin: &io::Stdin,
+) -> Result<()> {
+    send_to(
+        server_lines,
+        &UserMessage::ListInvitations {
+            token: token.clone(),
+        },
+    )
+    .await?;
+
+    match next_response(server_lines, token).await {
+        Some(Ok(ServerMessage::Invitations { invitations })) => {
+            if invitations.is_empty() {
+                println!("No invitations");
+                return Ok(());
+            }
+            for invitation in invitations.iter() {
+                println!("{}", render_invitation(invitation));
+            }
+        }
+        Some(Ok(ServerMessage::Error { kind, description })) => {
+            check_session(kind)?;
+            println!("{}", description);
+            return Ok(());
+        }
+        _ => {
+            tracing::debug!("Error listing invitations");
+            return Ok(());
+        }
+    }
+
+    println!("Enter 'accept NAME' or 'decline NAME', empty line to go back");
+    let mut line = String::new();
+    stdin.read_line(&mut line).await?;
+    let message = match line.split_whitespace().collect::<Vec<&str>>()[..] {
+        ["accept", channel] => UserMessage::AcceptInvitation {
+            token: token.clone(),
+            channel: channel.to_string(),
+        },
+        ["decline", channel] => UserMessage::DeclineInvitation {
+            token: token.clone(),
+            channel: channel.to_string(),
+        },
+        _ => return Ok(()),
+    };
+    send_to(server_lines, &message).await?;
+    print_response(server_lines, token).await
+}
+
+async fn manage_channel(
+    server_lines: &mut Framed<TcpStream, LinesCodec>,
+    token: &AuthenticationToken,
+    stdin: &io::Stdin,
+) -> Result<()> {
+    println!("Enter 'rename NAME NEW_NAME', 'archive NAME' or 'delete NAME'");
+    let mut line = String::new();
+    stdin.read_line(&mut line).await?;
+    let message = match line.split_whitespace().collect::<Vec<&str>>()[..] {
+        ["rename", channel, new_name] => UserMessage::RenameChannel {
+            token: token.clone(),
+            channel: channel.to_string(),
+            new_name: new_name.to_string(),
+        },
+        ["archive", channel] => UserMessage::ArchiveChannel {
+            token: token.clone(),
+            channel: channel.to_string(),
+        },
+        ["delete", channel] => UserMessage::DeleteChannel {
+            token: token.clone(),
+            channel: channel.to_string(),
+        },
+        _ => {
+            println!("Invalid input");
+            return Ok(());
+        }
+    };
+    send_to(server_lines, &message).await?;
+    print_response(server_lines, token).await
+}
+
+async fn ban_from_server(
+    server_lines: &mut Framed<TcpStream, LinesCodec>,
+    token: &AuthenticationToken,
+    stdin: &io::Stdin,
+) -> Result<()> {
+    println!("Enter user name, minutes (0 for good) and reason");
+    let mut line = String::new();
+    stdin.read_line(&mut line).await?;
+    match sanction_request(token, SanctionKind::Ban, None, line.trim()) {
+        Some(message) => send_to(server_lines, &message).await?,
+        None => {
+            println!("Invalid input");
+            return Ok(());
+        }
+    }
+    print_response(server_lines, token).await
+}
+
+async fn revoke_session(
+    server_lines: &mut Framed<TcpStream, LinesCodec>,
+    token: &AuthenticationToken,
+    stdin: &io::Stdin,
+) -> Result<()> {
+    println!("Enter session id");
+    let mut line = String::new();
+    stdin.read_line(&mut line).await?;
+    send_to(
+        server_lines,
+        &UserMessage::RevokeSession {
+            token: token.clone(),
+            session_id: line.trim().to_string(),
+        },
+    )
+    .await?;
+    print_response(server_lines, token).await
+}
+
+async fn change_role(
+    server_lines: &mut Framed<TcpStream, LinesCodec>,
+    token: &AuthenticationToken,
+    stdin: &io::Stdin,
+    grant: bool,
+) -> Result<()> {
+    println!("Enter user name and role (admin, moderator or member)");
+    let mut line = String::new();
+    stdin.read_line(&mut line).await?;
+    let (user_name, role) = match line.split_whitespace().collect::<Vec<&str>>()[..] {
+        [user_name, role] => match role.parse::<Role>() {
+            Ok(role) => (user_name.to_string(), role),
+            Err(_) => {
+                println!("Unknown role {}", role);
+                return Ok(());
+            }
+        },
+        _ => {
+            println!("Invalid input");
+            return Ok(());
+        }
+    };
+
+    let message = if grant {
+        UserMessage::GrantRole {
+            token: token.clone(),
+            user_name,
+            role,
+        }
+    } else {
+        UserMessage::RevokeRole {
+            token: token.clone(),
+            user_name,
+            role,
+        }
+    };
+    send_to(server_lines, &message).await?;
+    print_response(server_lines, token).await
+}
+
+async fn logout(
+    server_lines: &mut Framed<TcpStream, LinesCodec>,
+    token: &AuthenticationToken,
+) -> Result<()> {
+    send_to(
+        server_lines,
+        &UserMessage::Logout {
+            token: token.clone(),
+        },
+    )
+    .await?;
+    print_response(server_lines, token).await?;
+    exit(0)
+}
+
 fn setup_logging() -> Result<()> {
     use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter};
     tracing_subscriber::fmt()
@@ -290,7 +669,7 @@ async fn choose_channel(
     server_lines: &mut Framed<TcpStream, LinesCodec>,
     stdin: &io::Stdin,
     token: &AuthenticationToken,
-) -> Result<SocketAddr> {
+) -> Result<String> {
     send_to(
         server_lines,
         &UserMessage::GetChannels {
@@ -299,79 +678,776 @@ async fn choose_channel(
     )
     .await
     .context("Error receiving channels list from server")?;
-    if let Some(Ok(ServerMessage::ChannelsInfo {
-        channels: channels_infos,
-    })) = get_next_server_message(server_lines).await
-    {
-        println!("Choose channel");
-        for (idx, channel_info) in channels_infos.iter().enumerate() {
-            println!("[{}] {}", idx, channel_info.name);
+    let channels_infos = match next_response(server_lines, token).await {
+        Some(Ok(ServerMessage::ChannelsInfo { channels })) => channels,
+        Some(Ok(ServerMessage::Error { kind, description })) => {
+            check_session(kind)?;
+            println!("{}", description);
+            bail!(ChatError::InvalidMessage);
         }
+        _ => {
+            tracing::info!("Error receiving channels info from server");
+            bail!(ChatError::InvalidMessage);
+        }
+    };
 
-        loop {
-            let mut line = String::new();
-            println!("enter number in [0 ... {}]", channels_infos.len() - 1);
-            stdin.read_line(&mut line).await?;
-            let channel_nr = line.trim().parse::<usize>()?;
-            if channel_nr < channels_infos.len() {
-                clear_screen();
-                _ = io::stdout().flush().await;
-                return Ok(channels_infos[channel_nr].address);
-            }
+    println!("Choose channel");
+    for (idx, channel_info) in channels_infos.iter().enumerate() {
+        println!("[{}] {}", idx, render_channel_info(channel_info));
+    }
+
+    loop {
+        let mut line = String::new();
+        println!("enter number in [0 ... {}]", channels_infos.len() - 1);
+        stdin.read_line(&mut line).await?;
+        let channel_nr = line.trim().parse::<usize>()?;
+        if channel_nr < channels_infos.len() {
+            clear_screen();
+            _ = io::stdout().flush().await;
+            return Ok(channels_infos[channel_nr].name.clone());
         }
-    } else {
-        tracing::info!("Error receiving channels info from server");
-        Err(anyhow::Error::from(ChatError::InvalidMessage))
     }
 }
 
-async fn connect_to_channel(
-    channel_addr: SocketAddr,
+// Returns false if server refused to let us in
+async fn join_channel(
+    server_lines: &mut Framed<TcpStream, LinesCodec>,
+    channel: &str,
     token: &AuthenticationToken,
-) -> Result<Framed<TcpStream, LinesCodec>> {
-    let channel_socket =
-        TcpSocket::new_v4().context("Error opening socket for connection to channel")?;
-    let stream = channel_socket
-        .connect(channel_addr)
-        .await
-        .context("Error connecting to channel!")?;
-    let mut channel_lines = Framed::new(stream, LinesCodec::new());
-    if let Ok(encoded_msg) = serde_json::to_string(&UserMessage::Join {
-        token: token.clone(),
-    }) {
-        channel_lines.send(encoded_msg).await?;
+) -> Result<bool> {
+    send_to(
+        server_lines,
+        &UserMessage::Join {
+            token: token.clone(),
+            channel: channel.to_string(),
+        },
+    )
+    .await?;
+
+    match next_response(server_lines, token).await {
+        Some(Ok(ServerMessage::Joined { channel, topic })) => {
+            clear_screen();
+            print_topic(&channel, &topic);
+            Ok(true)
+        }
+        Some(Ok(ServerMessage::Error { kind, description })) => {
+            check_session(kind)?;
+            println!("{}", description);
+            Ok(false)
+        }
+        _ => bail!(ChatError::InvalidMessage),
     }
+}
 
-    clear_screen();
-    Ok(channel_lines)
+// Reports displayed messages, so they are not sent again on next join
+// and replies are not counted as unread in their thread
+async fn mark_read(
+    server_lines: &mut Framed<TcpStream, LinesCodec>,
+    token: &AuthenticationToken,
+    displayed: &mut HashMap<(String, Option<MessageId>), MessageId>,
+) -> Result<()> {
+    for ((channel, parent_id), message_id) in displayed.drain() {
+        let message = match parent_id {
+            Some(parent_id) => UserMessage::MarkThreadRead {
+                token: token.clone(),
+                channel,
+                parent_id,
+                message_id,
+            },
+            None => UserMessage::MarkRead {
+                token: token.clone(),
+                channel,
+                message_id,
+            },
+        };
+        send_to(server_lines, &message).await?;
+    }
+    Ok(())
+}
+
+fn remember_displayed(
+    displayed: &mut HashMap<(String, Option<MessageId>), MessageId>,
+    message: &ChatMessage,
+) {
+    let id = displayed
+        .entry((message.channel.clone(), message.parent_id))
+        .or_insert(message.id);
+    *id = (*id).max(message.id);
+}
+
+fn remember_oldest(oldest: &mut HashMap<String, MessageId>, message: &ChatMessage) {
+    let id = oldest.entry(message.channel.clone()).or_insert(message.id);
+    *id = (*id).min(message.id);
+}
+
+// e.g. "12 #RED 12:03:45 [ADMIN] hello (edited) (2 replies) [:+1: 2]" or "13 #RED 12:04:10 [bob] re 12: hi",
+// id first so message can be referred to
+fn render_message(message: &ChatMessage) -> String {
+    let body = match message.deleted_at {
+        Some(_) => "(deleted)",
+        None => &message.body,
+    };
+    let mut rendered = format!(
+        "{} #{} {} [{}] ",
+        message.id,
+        message.channel,
+        format_time(message.created_at),
+        message.author,
+    );
+    if let Some(parent_id) = message.parent_id {
+        rendered += &format!("re {}: ", parent_id);
+    }
+    rendered += body;
+    if message.edited_at.is_some() && message.deleted_at.is_none() {
+        rendered += " (edited)";
+    }
+    if message.reply_count > 0 {
+        rendered += &format!(" ({} replies)", message.reply_count);
+    }
+    if !message.reactions.is_empty() {
+        rendered += &format!(" {}", render_reactions(&message.reactions));
+    }
+    rendered
+}
+
+// e.g. "[:+1: 2, :tada: 1]"
+fn render_reactions(reactions: &[ReactionCount]) -> String {
+    let counts: Vec<String> = reactions
+        .iter()
+        .map(|counted| format!("{} {}", counted.reaction, counted.count))
+        .collect();
+    format!("[{}]", counts.join(", "))
+}
+
+// Time of day in UTC
+fn format_time(timestamp: i64) -> String {
+    let seconds_of_day = timestamp.rem_euclid(24 * 60 * 60);
+    format!(
+        "{:02}:{:02}:{:02}",
+        seconds_of_day / 3600,
+        seconds_of_day / 60 % 60,
+        seconds_of_day % 60
+    )
+}
+
+// e.g. "Invitation to SECRET from ADMIN at 12:03:45"
+fn render_invitation(invitation: &ChannelInvitation) -> String {
+    format!(
+        "Invitation to {} from {} at {}",
+        invitation.channel,
+        invitation.invited_by,
+        format_time(invitation.created_at)
+    )
+}
+
+// e.g. "ADMIN muted you in RED until 12:33:45: spam"
+fn render_sanction(sanction: &Sanction) -> String {
+    let place = sanction.channel.as_deref().unwrap_or("the server");
+    let mut description = match sanction.kind {
+        SanctionKind::Kick => format!("{} kicked you from {}", sanction.issued_by, place),
+        SanctionKind::Mute => format!("{} muted you in {}", sanction.issued_by, place),
+        SanctionKind::Ban => format!("{} banned you from {}", sanction.issued_by, place),
+    };
+    match sanction.expires_at {
+        _ if sanction.kind == SanctionKind::Kick => {}
+        Some(expires_at) => description += &format!(" until {}", format_time(expires_at)),
+        None => description += " for good",
+    }
+    if !sanction.reason.is_empty() {
+        description += &format!(": {}", sanction.reason);
+    }
+    description
+}
+
+// Parses "NAME [REASON]" for kicks and "NAME MINUTES [REASON]" for mutes and bans,
+// bans of 0 minutes never expire. Kicks and mutes need a channel.
+fn sanction_request(
+    token: &AuthenticationToken,
+    kind: SanctionKind,
+    channel: Option<String>,
+    args: &str,
+) -> Option<UserMessage> {
+    let mut parts = args.splitn(if kind == SanctionKind::Kick { 2 } else { 3 }, ' ');
+    let user_name = parts.next().filter(|name| !name.is_empty())?.to_string();
+    let duration_secs = match kind {
+        SanctionKind::Kick => None,
+        _ => Some(parts.next()?.parse::<u64>().ok()? * 60).filter(|secs| *secs > 0),
+    };
+    let reason = parts.next().unwrap_or_default().to_string();
+    let token = token.clone();
+    Some(match kind {
+        SanctionKind::Kick => UserMessage::Kick {
+            token,
+            channel: channel?,
+            user_name,
+            reason,
+        },
+        SanctionKind::Mute => UserMessage::Mute {
+            token,
+            channel: channel?,
+            user_name,
+            reason,
+            duration_secs: duration_secs?,
+        },
+        SanctionKind::Ban => UserMessage::Ban {
+            token,
+            channel,
+            user_name,
+            reason,
+            duration_secs,
+        },
+    })
+}
+
+// Stops showing channel and picks another one to write to if it was the current one
+fn forget_channel(joined: &mut Vec<String>, current: &mut String, channel: &str) {
+    joined.retain(|joined_channel| joined_channel != channel);
+    if current == channel {
+        if let Some(channel) = joined.first() {
+            *current = channel.clone();
+            println!("Writing to {}", current);
+        }
+    }
+}
+
+// Topic line followed by description, nothing for channels without them
+fn print_topic(channel: &str, topic: &ChannelTopic) {
+    if !topic.topic.is_empty() {
+        println!("#{} topic: {}", channel, topic.topic);
+    }
+    if !topic.description.is_empty() {
+        println!("{}", topic.description);
+    }
+}
+
+// e.g. "RED [releases] - 3 unread, 1 mention, last message at 12:03:45"
+fn render_channel_info(info: &ChannelInfo) -> String {
+    let activity = &info.activity;
+    let mut description = info.name.clone();
+    if info.visibility == Visibility::Private {
+        description += " (private)";
+    }
+    if info.archived_at.is_some() {
+        description += " (archived)";
+    }
+    if !info.topic.topic.is_empty() {
+        description += &format!(" [{}]", info.topic.topic);
+    }
+    if activity.unread_count > 0 {
+        description += &format!(" - {} unread", activity.unread_count);
+        if activity.mention_count > 0 {
+            description += &format!(", {} mentions", activity.mention_count);
+        }
+    }
+    if let Some(last_activity) = activity.last_activity {
+        description += &format!(", last message at {}", format_time(last_activity));
+    }
+    description
 }
 
+const MESSAGE_LOOP_HELP: &str = "/join NAME - join another channel, /switch NAME - write to joined channel, /history - show older messages of current channel, /search TEXT - search all channels, /context ID - show messages up to found one, /members - show who is in current channel, /thread ID - show replies to a message, /thread ID TEXT - reply to it, /threads - show threads you take part in, /mentions - show messages mentioning you, /edit ID TEXT, /delete ID and /revisions ID - change, remove or show earlier versions of a message in current channel, /react ID REACTION and /unreact ID REACTION - add or take back reaction like :+1: to a message, /pins - show pinned messages of current channel, /pin ID and /unpin ID - pin or unpin a message as moderator, /invite NAME - invite user to current private channel, /topic TEXT and /description TEXT - change what current channel is about, /kick NAME [REASON], /mute NAME MINUTES [REASON] and /ban NAME MINUTES [REASON] - moderate current channel, 0 minutes bans for good, /dm NAME[,NAME...] - write to direct conversation, /reply - write to last direct conversation, /away and /back - set your status, /leave - leave current channel, CTRL-C - leave all";
+const HISTORY_PAGE: u32 = 20;
+const MARK_READ_INTERVAL: Duration = Duration::from_secs(2);
+const SEARCH_PAGE: u32 = 20;
+
+// Client stays in every joined channel and shows messages from all of them,
+// lines are sent to the current channel
 async fn message_loop(
-    mut channel_lines: Framed<TcpStream, LinesCodec>,
+    server_lines: &mut Framed<TcpStream, LinesCodec>,
     token: &AuthenticationToken,
+    channel: String,
     ctrlc_channel: &mut UnboundedReceiver<()>,
     stdin: &io::Stdin,
 ) -> Result<()> {
+    let mut joined = vec![channel.clone()];
+    let mut current = channel;
+    // id of the oldest message shown for each channel, history is fetched from there
+    let mut oldest: HashMap<String, MessageId> = HashMap::new();
+    // channels of messages found by last search
+    let mut found: HashMap<MessageId, String> = HashMap::new();
+    // highest displayed message of each channel and thread not yet reported to server
+    let mut displayed: HashMap<(String, Option<MessageId>), MessageId> = HashMap::new();
+    // direct conversation lines are sent to instead of the current channel
+    let mut conversation: Option<ConversationId> = None;
+    let mut last_conversation: Option<ConversationId> = None;
+    let mut mark_read_interval = tokio::time::interval(MARK_READ_INTERVAL);
     let mut line = String::new();
-    loop {
+    println!("{}", MESSAGE_LOOP_HELP);
+    while !joined.is_empty() {
         tokio::select! {
+            _ = mark_read_interval.tick() => mark_read(server_lines, token, &mut displayed).await?,
             _ = ctrlc_channel.recv() => {
-                tracing::debug!("CTRL-C clicked, changing channel");
+                tracing::debug!("CTRL-C clicked, leaving channels");
                 break;
             },
             _ = stdin.read_line(&mut line) => {
                 line.pop(); // remove end of line
-                if line.is_empty() {
-                    continue;
+                let message = match line.split_once(' ') {
+                    _ if line.is_empty() => None,
+                    Some(("/join", name)) => Some(UserMessage::Join {
+                        token: token.clone(),
+                        channel: name.to_string(),
+                    }),
+                    Some(("/search", text)) => Some(UserMessage::Search {
+                        token: token.clone(),
+                        query: SearchQuery {
+                            text: text.to_string(),
+                            limit: SEARCH_PAGE,
+                            ..Default::default()
+                        },
+                    }),
+                    Some(("/context", id)) => match id.parse::<MessageId>().ok().and_then(|id| found.get(&id).map(|channel| (id, channel))) {
+                        Some((id, channel)) => Some(UserMessage::FetchHistory {
+                            token: token.clone(),
+                            channel: channel.clone(),
+                            before_id: Some(id + 1),
+                            limit: HISTORY_PAGE,
+                        }),
+                        None => {
+                            println!("Search for the message first");
+                            None
+                        }
+                    },
+                    Some(("/topic", topic)) => Some(UserMessage::SetTopic {
+                        token: token.clone(),
+                        channel: current.clone(),
+                        topic: Some(topic.to_string()),
+                        description: None,
+                    }),
+                    Some(("/description", description)) => Some(UserMessage::SetTopic {
+                        token: token.clone(),
+                        channel: current.clone(),
+                        topic: None,
+                        description: Some(description.to_string()),
+                    }),
+                    Some(("/thread", args)) => {
+                        let (id, content) = args.split_once(' ').map_or((args, None), |(id, content)| (id, Some(content)));
+                        match (id.parse::<MessageId>(), content) {
+                            (Ok(parent_id), Some(content)) => Some(UserMessage::Reply {
+                                token: token.clone(),
+                                channel: current.clone(),
+                                parent_id,
+                                content: content.to_string(),
+                            }),
+                            (Ok(parent_id), None) => Some(UserMessage::FetchThread {
+                                token: token.clone(),
+                                channel: current.clone(),
+                                parent_id,
+                                before_id: None,
+                                limit: HISTORY_PAGE,
+                            }),
+                            (Err(_), _) => {
+                                println!("{}", MESSAGE_LOOP_HELP);
+                                None
+                            }
+                        }
+                    }
+                    Some((command @ ("/react" | "/unreact"), args)) => match args.split_once(' ').and_then(|(id, reaction)| Some((id.parse::<MessageId>().ok()?, reaction.trim()))) {
+                        Some((message_id, reaction)) if command == "/react" => Some(UserMessage::AddReaction {
+                            token: token.clone(),
+                            channel: current.clone(),
+                            message_id,
+                            reaction: reaction.to_string(),
+                        }),
+                        Some((message_id, reaction)) => Some(UserMessage::RemoveReaction {
+                            token: token.clone(),
+                            channel: current.clone(),
+                            message_id,
+                            reaction: reaction.to_string(),
+                        }),
+                        None => {
+                            println!("{}", MESSAGE_LOOP_HELP);
+                            None
+                        }
+                    },
+                    Some(("/edit", args)) => match args.split_once(' ').and_then(|(id, content)| Some((id.parse::<MessageId>().ok()?, content))) {
+                        Some((message_id, content)) => Some(UserMessage::EditMessage {
+                            token: token.clone(),
+                            channel: current.clone(),
+                            message_id,
+                            content: content.to_string(),
+                        }),
+                        None => {
+                            println!("{}", MESSAGE_LOOP_HELP);
+                            None
+                        }
+                    },
+                    Some((command @ ("/delete" | "/revisions"), id)) => match id.parse::<MessageId>() {
+                        Ok(message_id) if command == "/delete" => Some(UserMessage::DeleteMessage {
+                            token: token.clone(),
+                            channel: current.clone(),
+                            message_id,
+                        }),
+                        Ok(message_id) => Some(UserMessage::FetchRevisions {
+                            token: token.clone(),
+                            channel: current.clone(),
+                            message_id,
+                        }),
+                        Err(_) => {
+                            println!("{}", MESSAGE_LOOP_HELP);
+                            None
+                        }
+                    },
+                    Some((command @ ("/pin" | "/unpin"), id)) => match id.parse::<MessageId>() {
+                        Ok(message_id) if command == "/pin" => Some(UserMessage::PinMessage {
+                            token: token.clone(),
+                            channel: current.clone(),
+                            message_id,
+                        }),
+                        Ok(message_id) => Some(UserMessage::UnpinMessage {
+                            token: token.clone(),
+                            channel: current.clone(),
+                            message_id,
+                        }),
+                        Err(_) => {
+                            println!("{}", MESSAGE_LOOP_HELP);
+                            None
+                        }
+                    },
+                    Some(("/invite", name)) => Some(UserMessage::InviteToChannel {
+                        token: token.clone(),
+                        channel: current.clone(),
+                        user_name: name.to_string(),
+                    }),
+                    Some((command @ ("/kick" | "/mute" | "/ban"), args)) => {
+                        let kind = match command {
+                            "/kick" => SanctionKind::Kick,
+                            "/mute" => SanctionKind::Mute,
+                            _ => SanctionKind::Ban,
+                        };
+                        let request = sanction_request(token, kind, Some(current.clone()), args);
+                        if request.is_none() {
+                            println!("{}", MESSAGE_LOOP_HELP);
+                        }
+                        request
+                    }
+                    Some(("/dm", names)) => Some(UserMessage::OpenConversation {
+                        token: token.clone(),
+                        members: names.split(',').map(|name| name.trim().to_string()).collect(),
+                    }),
+                    _ if line == "/reply" => {
+                        match last_conversation {
+                            Some(id) => {
+                                conversation = Some(id);
+                                println!("Writing to @{}", id);
+                            }
+                            None => println!("No direct messages yet"),
+                        }
+                        None
+                    }
+                    Some(("/switch", name)) => {
+                        if joined.iter().any(|channel| channel == name) {
+                            conversation = None;
+                            current = name.to_string();
+                            println!("Writing to {}", current);
+                        } else {
+                            println!("Join {} first", name);
+                        }
+                        None
+                    }
+                    _ if line == "/history" => Some(UserMessage::FetchHistory {
+                        token: token.clone(),
+                        channel: current.clone(),
+                        before_id: oldest.get(&current).copied(),
+                        limit: HISTORY_PAGE,
+                    }),
+                    _ if line == "/threads" => Some(UserMessage::ListThreads {
+                        token: token.clone(),
+                        channel: current.clone(),
+                    }),
+                    _ if line == "/pins" => Some(UserMessage::ListPins {
+                        token: token.clone(),
+                        channel: current.clone(),
+                    }),
+                    _ if line == "/mentions" => Some(UserMessage::ListMentions {
+                        token: token.clone(),
+                        unread_only: false,
+                        before_id: None,
+                        limit: HISTORY_PAGE,
+                    }),
+                    _ if line == "/members" => Some(UserMessage::ListMembers {
+                        token: token.clone(),
+                        channel: current.clone(),
+                    }),
+                    _ if line == "/away" || line == "/back" => Some(UserMessage::SetStatus {
+                        token: token.clone(),
+                        status: if line == "/away" { Status::Away } else { Status::Online },
+                    }),
+                    _ if line == "/leave" => Some(UserMessage::Leave {
+                        token: token.clone(),
+                        channel: current.clone(),
+                    }),
+                    _ if line.starts_with('/') => {
+                        println!("{}", MESSAGE_LOOP_HELP);
+                        None
+                    }
+                    _ => match conversation {
+                        Some(conversation_id) => Some(UserMessage::DirectMessage {
+                            token: token.clone(),
+                            conversation_id,
+                            content: line.clone(),
+                        }),
+                        None => Some(UserMessage::TextMessage {
+                            token: token.clone(),
+                            channel: current.clone(),
+                            content: line.clone(),
+                        }),
+                    },
+                };
+                if let Some(message) = message {
+                    if let UserMessage::Leave { .. } = message {
+                        mark_read(server_lines, token, &mut displayed).await?;
+                    }
+                    send_to(server_lines, &message).await?;
                 }
-                if let Ok(encoded_msg) = serde_json::to_string(&UserMessage::TextMessage{token : token.clone(), content : line.clone()}) {
-                    channel_lines.send(encoded_msg).await?;
-                    line.clear();
+                line.clear();
+            }
+            Some(Ok(server_message)) = get_next_server_message(server_lines) => match server_message {
+                ServerMessage::ChannelMessage { message } => {
+                    // messages of left channel can still be on their way
+                    if joined.contains(&message.channel) {
+                        println!("{}", render_message(&message));
+                        if message.parent_id.is_none() {
+                            remember_oldest(&mut oldest, &message);
+                        }
+                        remember_displayed(&mut displayed, &message);
+                    }
+                }
+                // reply itself was already shown
+                ServerMessage::ThreadUpdated { .. } => {}
+                ServerMessage::Thread { channel, parent, replies, has_more } => {
+                    println!("--- thread of message {} in #{} ---", parent.id, channel);
+                    println!("{}", render_message(&parent));
+                    if has_more {
+                        println!("--- older replies not shown ---");
+                    }
+                    for reply in replies.iter() {
+                        println!("{}", render_message(reply));
+                        remember_displayed(&mut displayed, reply);
+                    }
+                    println!("---");
+                }
+                ServerMessage::Threads { channel, threads } => {
+                    println!("--- your threads in #{} ---", channel);
+                    for thread in threads.iter() {
+                        println!("{}, {} unread", render_message(&thread.parent), thread.unread_count);
+                    }
+                    println!("---");
+                }
+                ServerMessage::MessageEdited { message } => {
+                    if joined.contains(&message.channel) {
+                        println!("{}", render_message(&message));
+                    }
+                }
+                ServerMessage::ReactionsChanged { channel, message_id, reactions } => {
+                    if joined.contains(&channel) {
+                        println!("#{} reactions to {}: {}", channel, message_id, render_reactions(&reactions));
+                    }
                 }
+                ServerMessage::MessageDeleted { channel, message_id, deleted_by } => {
+                    if joined.contains(&channel) {
+                        println!("#{} {} deleted message {}", channel, deleted_by, message_id);
+                    }
+                }
+                ServerMessage::MessagePinned { channel, pin } => {
+                    if joined.contains(&channel) {
+                        println!("#{} {} pinned: {}", channel, pin.pinned_by, render_message(&pin.message));
+                    }
+                }
+                ServerMessage::MessageUnpinned { channel, message_id, unpinned_by } => {
+                    if joined.contains(&channel) {
+                        println!("#{} {} unpinned message {}", channel, unpinned_by, message_id);
+                    }
+                }
+                ServerMessage::Pins { channel, pins } => {
+                    if pins.is_empty() {
+                        println!("No pinned messages in #{}", channel);
+                    } else {
+                        println!("--- pinned in #{} ---", channel);
+                        for pin in pins.iter() {
+                            println!("{} (pinned by {})", render_message(&pin.message), pin.pinned_by);
+                        }
+                        println!("---");
+                    }
+                }
+                ServerMessage::Revisions { channel, message_id, revisions } => {
+                    println!("--- earlier versions of message {} in #{} ---", message_id, channel);
+                    for revision in revisions.iter() {
+                        println!("{} {} replaced: {}", format_time(revision.revised_at), revision.revised_by, revision.body);
+                    }
+                    println!("---");
+                }
+                ServerMessage::DirectMessage { message } => {
+                    show_direct_message(server_lines, token, &message).await?;
+                    last_conversation = Some(message.conversation_id);
+                }
+                ServerMessage::Invited { invitation } => println!("{}", render_invitation(&invitation)),
+                ServerMessage::Mentioned { message } => println!("You were mentioned: {}", render_message(&message)),
+                ServerMessage::Mentions { mentions, has_more } => {
+                    println!("--- your mentions ---");
+                    if has_more {
+                        println!("--- older mentions not shown ---");
+                    }
+                    for mention in mentions.iter() {
+                        let new = if mention.read { "" } else { " (new)" };
+                        println!("{}{}", render_message(&mention.message), new);
+                    }
+                    println!("---");
+                    // mentions up to the newest listed one count as seen
+                    if let Some(newest) = mentions.iter().filter(|mention| !mention.read).map(|mention| mention.message.id).max() {
+                        send_to(server_lines, &UserMessage::MarkMentionsRead {
+                            token: token.clone(),
+                            message_id: newest,
+                        })
+                        .await?;
+                    }
+                }
+                ServerMessage::Sanctioned { sanction } => {
+                    println!("{}", render_sanction(&sanction));
+                    match (sanction.kind, &sanction.channel) {
+                        (SanctionKind::Mute, _) => {}
+                        (_, Some(channel)) => forget_channel(&mut joined, &mut current, channel),
+                        // sessions are gone, there is nothing left to leave
+                        (_, None) => bail!(ChatError::Banned { reason: sanction.reason }),
+                    }
+                }
+                ServerMessage::TextMessage { content } => println!("{}", content),
+                ServerMessage::ConversationOpened { id, members } => {
+                    conversation = Some(id);
+                    println!("Writing to @{} with {}", id, members.join(", "));
+                }
+                ServerMessage::SearchResults { hits, has_more } => {
+                    println!("--- search results ---");
+                    found.clear();
+                    for hit in hits.iter() {
+                        println!("{}", render_message(&hit.message));
+                        found.insert(hit.message.id, hit.message.channel.clone());
+                    }
+                    if has_more {
+                        println!("--- more results not shown, refine the search ---");
+                    } else {
+                        println!("---");
+                    }
+                }
+                ServerMessage::History { channel, messages, has_more } => {
+                    println!("--- older messages of #{} ---", channel);
+                    if !has_more {
+                        println!("--- beginning of #{} ---", channel);
+                    }
+                    for message in messages.iter() {
+                        println!("{}", render_message(message));
+                        remember_oldest(&mut oldest, message);
+                    }
+                    println!("---");
+                }
+                ServerMessage::MemberJoined { channel, user_name } => {
+                    if joined.contains(&channel) {
+                        println!("#{} {} has joined!", channel, user_name);
+                    }
+                }
+                ServerMessage::MemberLeft { channel, user_name } => {
+                    if joined.contains(&channel) {
+                        println!("#{} {} has left", channel, user_name);
+                    }
+                }
+                ServerMessage::StatusChanged { channel, user_name, status } => {
+                    if joined.contains(&channel) {
+                        println!("#{} {} is now {:?}", channel, user_name, status);
+                    }
+                }
+                ServerMessage::Members { channel, members } => {
+                    println!("--- members of #{} ---", channel);
+                    for member in members.iter() {
+                        println!("{} - {:?}, last seen at {}", member.user_name, member.status, format_time(member.last_seen));
+                    }
+                    println!("---");
+                }
+                ServerMessage::Joined { channel, topic } => {
+                    if !joined.contains(&channel) {
+                        joined.push(channel.clone());
+                    }
+                    println!("Joined {}, writing to it", channel);
+                    print_topic(&channel, &topic);
+                    current = channel;
+                }
+                ServerMessage::TopicChanged { channel, topic, changed_by } => {
+                    if joined.contains(&channel) {
+                        println!("#{} {} changed the topic", channel, changed_by);
+                        print_topic(&channel, &topic);
+                    }
+                }
+                ServerMessage::Left { channel } => {
+                    joined.retain(|joined_channel| *joined_channel != channel);
+                    println!("Left {}", channel);
+                    if let Some(channel) = joined.first() {
+                        current = channel.clone();
+                        println!("Writing to {}", current);
+                    }
+                }
+                ServerMessage::ChannelClosed { channel, reason } => {
+                    // read markers of the channel cannot be reported under its old name
+                    displayed.retain(|(displayed_channel, _), _| *displayed_channel != channel);
+                    oldest.remove(&channel);
+                    let removed = match reason {
+                        // channel stays joined under its new name, so loop does not end in the meantime
+                        ClosedReason::Renamed { new_name } => {
+                            println!("#{} was renamed to #{}", channel, new_name);
+                            for joined_channel in joined.iter_mut().filter(|joined_channel| **joined_channel == channel) {
+                                *joined_channel = new_name.clone();
+                            }
+                            if current == channel {
+                                current = new_name.clone();
+                            }
+                            send_to(server_lines, &UserMessage::Join {
+                                token: token.clone(),
+                                channel: new_name,
+                            }).await?;
+                            false
+                        }
+                        ClosedReason::Archived => {
+                            println!("#{} was archived", channel);
+                            true
+                        }
+                        ClosedReason::Deleted => {
+                            println!("#{} was deleted", channel);
+                            true
+                        }
+                    };
+                    if removed {
+                        forget_channel(&mut joined, &mut current, &channel);
+                    }
+                }
+                ServerMessage::Error { kind, description } => {
+                    check_session(kind)?;
+                    println!("{}", description);
+                }
+                _ => tracing::debug!("Unexpected message in channel"),
+            }
+        }
+    }
+
+    mark_read(server_lines, token, &mut displayed).await?;
+    for channel in joined.iter() {
+        send_to(
+            server_lines,
+            &UserMessage::Leave {
+                token: token.clone(),
+                channel: channel.clone(),
+            },
+        )
+        .await?;
+    }
+    while !joined.is_empty() {
+        match get_next_server_message(server_lines).await {
+            Some(Ok(ServerMessage::Left { channel })) => {
+                joined.retain(|joined_channel| *joined_channel != channel)
             }
-            Some(Ok(ServerMessage::TextMessage { content })) = get_next_server_message(&mut channel_lines) => {
-                println!("{}", content);
+            Some(Ok(ServerMessage::Error { kind, description })) => {
+                check_session(kind)?;
+                println!("{}", description);
             }
+            Some(Ok(_)) => {}
+            _ => bail!(ChatError::InvalidMessage),
         }
     }
     Ok(())
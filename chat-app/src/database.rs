@@ -1,188 +1,373 @@
-use rand::distributions::Alphanumeric;
-use rand::{thread_rng, Rng};
 use std::collections::HashMap;
-use std::sync::RwLock;
 
+use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
-use tokio_postgres::{Client, Row};
 
-use crate::utils::{self, calculate_hash, ChatError};
-use anyhow::{Context, Result};
+use crate::channel::{ChannelActivity, ChannelInvitation, ChannelTopic, StoredChannel, Visibility};
+use crate::direct::{ConversationId, ConversationInfo, DirectMessage};
+use crate::mentions::Mention;
+use crate::messages::{ChatMessage, MessageId, MessageRevision, PinnedMessage, ThreadInfo};
+use crate::moderation::{Sanction, SanctionKind};
+use crate::password::{self, StoredPassword, Verification};
+use crate::permissions::{Permission, Role};
+use crate::search::{SearchHit, SearchQuery};
+use crate::session::{SessionId, SessionInfo};
+use crate::utils::ChatError;
+use anyhow::Result;
 
-type Cookie = String;
+mod memory;
+mod postgres;
+mod sqlite;
 
-#[derive(Debug)]
-pub struct ChatDatabase {
-    client: Client,
-    tokens: RwLock<HashMap<String, String>>,
-}
+pub use memory::InMemoryDatabase;
+pub use postgres::ChatDatabase;
+pub use sqlite::SqliteDatabase;
 
-impl ChatDatabase {
-    pub fn new(client: Client) -> ChatDatabase {
-        ChatDatabase {
-            client,
-            tokens: RwLock::new(HashMap::new()),
-        }
-    }
+type Cookie = String;
 
-    #[allow(dead_code)]
-    pub async fn authenticate_user(
+// Everything channels and the main server need from persistent storage.
+// Implementations must be cheap to share behind an Arc between connection tasks.
+#[async_trait]
+pub trait ChatStore: std::fmt::Debug + Send + Sync + 'static {
+    async fn authenticate_user(
         &self,
         name: &str,
         password: &str,
     ) -> Result<AuthenticationToken, ChatError> {
-        let password_hash = calculate_hash(&password) as i64;
-        match self
-            .client
-            .query(
-                "SELECT * FROM users WHERE name = ($1) AND password = ($2)",
-                &[&name, &password_hash],
-            )
-            .await
-        {
-            Ok(rows) => {
-                if rows.is_empty() {
-                    return Err(ChatError::InvalidPassword);
-                }
-            }
-            Err(e) => {
-                tracing::debug!("{:?}", e);
-                return Err(utils::ChatError::DatabaseError(e));
+        let stored = self
+            .get_password(name)
+            .await?
+            .ok_or(ChatError::InvalidPassword)?;
+        match password::verify_password(password, stored).await? {
+            Verification::Invalid => return Err(ChatError::InvalidPassword),
+            Verification::Valid => {}
+            Verification::ValidRehashed(hash) => {
+                tracing::info!("Upgrading password hash of user {}", name);
+                self.set_password_hash(name, &hash).await?;
             }
         }
-
-        let token = AuthenticationToken {
-            user_name: name.to_string(),
-            cookie: thread_rng()
-                .sample_iter(&Alphanumeric)
-                .take(32)
-                .map(char::from)
-                .collect(),
-        };
-        self.tokens
-            .write()
-            .unwrap()
-            .insert(name.to_string(), token.cookie.clone());
-        Ok(token)
-    }
-
-    pub fn authorize_connection(&self, token: &AuthenticationToken) -> bool {
-        match self.tokens.read().unwrap().get(&token.user_name) {
-            Some(cookie) => token.cookie.eq(cookie),
-            None => false,
+        if let Some(ban) = self
+            .get_active_sanction(name, None, SanctionKind::Ban)
+            .await?
+        {
+            return Err(ChatError::Banned { reason: ban.reason });
         }
+        self.create_session(name).await
     }
 
-    pub async fn get_channels_names(&self) -> Result<Vec<String>> {
-        let results = self
-            .client
-            .query("SELECT name FROM channels", &[])
-            .await
-            .context("Error selecting channel names from database!")?;
-        let results = results.into_iter().map(ChannelData::from);
-        let channel_names: Vec<String> = results
-            .into_iter()
-            .map(|channel_data| channel_data.name)
-            .collect();
-        Ok(channel_names)
-    }
+    // None when there is no such user
+    async fn get_password(&self, name: &str) -> Result<Option<StoredPassword>, ChatError>;
 
-    pub async fn create_channel(&self, name: &str) -> Result<()> {
-        self.client
-            .execute("INSERT INTO channels (name) VALUES ($1)", &[&name])
-            .await
-            .context("Error inserting new channel to database!")?;
-        Ok(())
-    }
+    // replaces stored hash and drops legacy one
+    async fn set_password_hash(&self, name: &str, hash: &str) -> Result<(), ChatError>;
+
+    async fn create_session(&self, user_name: &str) -> Result<AuthenticationToken, ChatError>;
+
+    // Ok when token belongs to a live session, refreshes its idle timeout
+    async fn authorize_connection(&self, token: &AuthenticationToken) -> Result<(), ChatError>;
+
+    async fn list_sessions(&self, user_name: &str) -> Result<Vec<SessionInfo>, ChatError>;
+
+    // returns false when user has no session with given id
+    async fn revoke_session(&self, user_name: &str, session_id: &str) -> Result<bool, ChatError>;
 
-    pub async fn create_user(&self, name: &str, password: &str) -> Result<()> {
-        let password_hash = calculate_hash(&password) as i64;
-        self.client
-            .execute(
-                "INSERT INTO users (name, password) VALUES ($1, $2)",
-                &[&name, &password_hash],
-            )
-            .await
-            .context("Error inserting new user to database!")?;
+    async fn logout(&self, token: &AuthenticationToken) -> Result<(), ChatError> {
+        self.authorize_connection(token).await?;
+        self.revoke_session(&token.user_name, &token.session_id)
+            .await?;
         Ok(())
     }
 
-    pub async fn get_unseed_messages(
-        &self,
-        channel_name: &str,
-        user_name: &str,
-    ) -> Result<Vec<(String, String)>, ChatError> {
-        let last_seen_message_id: Result<Row, tokio_postgres::Error> = self
-            .client
-            .query_one(
-                "SELECT message_id FROM history WHERE user_name = ($1) AND channel_name = ($2)",
-                &[&user_name, &channel_name],
-            )
-            .await;
-        let last_seen_message_id: i32 = last_seen_message_id.map_or(-1, |row| row.get(0));
-        let results = self
-            .client
-            .query(
-                "SELECT user_name, content FROM messages WHERE channel_name = ($1) AND id > ($2)",
-                &[&channel_name, &last_seen_message_id],
-            )
-            .await?;
-        Ok(results
-            .into_iter()
-            .map(|row| (row.get(0), row.get(1)))
-            .collect())
+    // None when there is no such user
+    async fn get_role(&self, user_name: &str) -> Result<Option<Role>, ChatError>;
+
+    // returns false when there is no such user
+    async fn set_role(&self, user_name: &str, role: Role) -> Result<bool, ChatError>;
+
+    // Forbidden unless user's role grants the permission
+    async fn check_permission(
+        &self,
+        user_name: &str,
+        permission: Permission,
+    ) -> Result<(), ChatError> {
+        match self.get_role(user_name).await? {
+            Some(role) if role.allows(permission) => Ok(()),
+            Some(_) => Err(ChatError::Forbidden),
+            None => Err(ChatError::UnauthenticatedConnection),
+        }
     }
 
-    pub async fn save_message(
+    async fn get_channels(&self) -> Result<Vec<StoredChannel>>;
+
+    // owner of a private channel is added to its members
+    async fn create_channel(&self, name: &str, owner: &str, visibility: Visibility) -> Result<()>;
+
+    // moves messages, read markers, members and invitations along.
+    // Fails with NoSuchChannel if there is no such channel and NameUsed if new_name is taken.
+    async fn rename_channel(&self, name: &str, new_name: &str) -> Result<(), ChatError>;
+
+    // fails with NoSuchChannel if there is no such channel
+    async fn archive_channel(&self, name: &str, archived_at: i64) -> Result<(), ChatError>;
+
+    // fails with NoSuchChannel if there is no such channel
+    async fn set_channel_topic(&self, name: &str, topic: &ChannelTopic) -> Result<(), ChatError>;
+
+    // removes channel with everything that refers to it
+    async fn delete_channel(&self, name: &str) -> Result<(), ChatError>;
+
+    // membership only matters for private channels
+    async fn is_channel_member(
         &self,
         channel_name: &str,
         user_name: &str,
+    ) -> Result<bool, ChatError>;
+
+    // names of private channels user is a member of
+    async fn get_member_channels(&self, user_name: &str) -> Result<Vec<String>, ChatError>;
+
+    // Replaces earlier invitation of the user to the same channel.
+    // Fails with NoSuchUser if there is no such user and AlreadyMember if they are a member.
+    async fn invite_to_channel(
+        &self,
+        channel_name: &str,
+        user_name: &str,
+        invited_by: &str,
+    ) -> Result<ChannelInvitation, ChatError>;
+
+    async fn list_invitations(&self, user_name: &str) -> Result<Vec<ChannelInvitation>, ChatError>;
+
+    // Removes invitation, making user a member if they accept it.
+    // Fails with NoSuchInvitation if user was not invited to the channel.
+    async fn answer_invitation(
+        &self,
+        channel_name: &str,
+        user_name: &str,
+        accept: bool,
+    ) -> Result<(), ChatError>;
+
+    // Records moderation action, whether it is in force is decided by its kind and expiry.
+    // Fails with NoSuchUser if there is no such user.
+    async fn add_sanction(&self, sanction: &Sanction) -> Result<(), ChatError>;
+
+    // Sanction of given kind in force against user, the one lasting longest if there are more.
+    // Server wide sanctions count in every channel, with channel_name None only they count.
+    async fn get_active_sanction(
+        &self,
+        user_name: &str,
+        channel_name: Option<&str>,
+        kind: SanctionKind,
+    ) -> Result<Option<Sanction>, ChatError>;
+
+    // activity of every channel for given user, by channel name
+    async fn get_channels_activity(
+        &self,
+        user_name: &str,
+    ) -> Result<HashMap<String, ChannelActivity>, ChatError>;
+
+    async fn create_user(&self, name: &str, password: &str) -> Result<()>;
+
+    // returns messages posted to the channel itself after the last one user has seen,
+    // oldest first, without deleted ones and replies
+    async fn get_unseed_messages(
+        &self,
+        channel_name: &str,
+        user_name: &str,
+    ) -> Result<Vec<ChatMessage>, ChatError>;
+
+    // returns at most limit messages with id lower than before_id (all if None), oldest first.
+    // Those are replies in the thread started by parent_id, or messages of the channel itself if None.
+    async fn get_messages_before(
+        &self,
+        channel_name: &str,
+        parent_id: Option<MessageId>,
+        before_id: Option<MessageId>,
+        limit: u32,
+    ) -> Result<Vec<ChatMessage>, ChatError>;
+
+    // returns hits from channels visible to user_name ordered by rank,
+    // skipping query.offset of them and at most query.limit
+    async fn search_messages(
+        &self,
+        query: &SearchQuery,
+        user_name: &str,
+    ) -> Result<Vec<SearchHit>, ChatError>;
+
+    // returns message as stored, with its id and timestamp.
+    // parent_id is the first message of the thread for replies, caller checks it is in the channel.
+    async fn save_message(
+        &self,
+        channel_name: &str,
+        user_name: &str,
+        parent_id: Option<MessageId>,
         message: &str,
-    ) -> Result<(), ChatError> {
-        self.client
-            .execute(
-                "INSERT INTO messages (channel_name, user_name, content) VALUES ($1, $2, $3)",
-                &[&channel_name, &user_name, &message],
-            )
-            .await?;
-        Ok(())
-    }
+    ) -> Result<ChatMessage, ChatError>;
 
-    pub async fn save_history(&self, channel_name: &str, user_name: &str) -> Result<(), ChatError> {
-        self.client
-            .execute(
-                "
-            INSERT INTO history (user_name, channel_name, message_id)
-            VALUES (
-                ($1),
-                ($2),
-                (SELECT coalesce(MAX(id),-1) as message_id FROM messages 
-                WHERE channel_name = ($2)))
-            ON CONFLICT (user_name, channel_name) DO UPDATE
-                SET message_id = excluded.message_id;",
-                &[&user_name, &channel_name],
-            )
-            .await?;
-        Ok(())
-    }
+    // None if there is no such message in the channel, deleted one is returned as tombstone
+    async fn get_message(
+        &self,
+        channel_name: &str,
+        message_id: MessageId,
+    ) -> Result<Option<ChatMessage>, ChatError>;
+
+    // Replaces content of the message, previous one is kept as a revision.
+    // Fails with NoSuchMessage if message is not in the channel or was deleted.
+    async fn edit_message(
+        &self,
+        channel_name: &str,
+        message_id: MessageId,
+        edited_by: &str,
+        content: &str,
+    ) -> Result<ChatMessage, ChatError>;
+
+    // Turns message into a tombstone, its last content is kept as a revision.
+    // Fails with NoSuchMessage if message is not in the channel or was already deleted.
+    async fn delete_message(
+        &self,
+        channel_name: &str,
+        message_id: MessageId,
+        deleted_by: &str,
+    ) -> Result<(), ChatError>;
+
+    // Puts user's reaction on a message of the channel which was not deleted.
+    // Returns false if user already put it there, fails with NoSuchMessage if there is no such message.
+    async fn add_reaction(
+        &self,
+        channel_name: &str,
+        message_id: MessageId,
+        user_name: &str,
+        reaction: &str,
+    ) -> Result<bool, ChatError>;
+
+    // returns false if user did not put such reaction on the message
+    async fn remove_reaction(
+        &self,
+        channel_name: &str,
+        message_id: MessageId,
+        user_name: &str,
+        reaction: &str,
+    ) -> Result<bool, ChatError>;
+
+    // content the message had before each of its edits and its deletion, oldest first
+    async fn get_message_revisions(
+        &self,
+        message_id: MessageId,
+    ) -> Result<Vec<MessageRevision>, ChatError>;
+
+    // threads of the channel user started, replied to or marked read, most recently active first
+    async fn list_threads(
+        &self,
+        channel_name: &str,
+        user_name: &str,
+    ) -> Result<Vec<ThreadInfo>, ChatError>;
+
+    // same as mark_read, for replies in the thread started by parent_id.
+    // Fails with NoSuchMessage if message is not a reply in that thread.
+    async fn mark_thread_read(
+        &self,
+        channel_name: &str,
+        user_name: &str,
+        parent_id: MessageId,
+        message_id: MessageId,
+    ) -> Result<(), ChatError>;
+
+    // Fails with NoSuchMessage if message is not in the channel or was deleted.
+    // Returns false if it was pinned already.
+    async fn pin_message(
+        &self,
+        channel_name: &str,
+        message_id: MessageId,
+        pinned_by: &str,
+    ) -> Result<bool, ChatError>;
+
+    // returns false if message was not pinned in the channel
+    async fn unpin_message(
+        &self,
+        channel_name: &str,
+        message_id: MessageId,
+    ) -> Result<bool, ChatError>;
+
+    // pins of messages that were not deleted, most recently pinned first
+    async fn get_pinned_messages(
+        &self,
+        channel_name: &str,
+    ) -> Result<Vec<PinnedMessage>, ChatError>;
+
+    // Records that the message mentions given users, skipping names of users who do not exist
+    // or cannot see its channel. With whole_channel also everybody who has a read marker
+    // in the channel or is its member. Author is never mentioned, returns names of mentioned users.
+    async fn save_mentions(
+        &self,
+        message_id: MessageId,
+        user_names: &[String],
+        whole_channel: bool,
+    ) -> Result<Vec<String>, ChatError>;
+
+    // returns at most limit mentions of user with message id lower than before_id (all if None),
+    // oldest first, without deleted messages and ones in channels user cannot see anymore
+    async fn list_mentions(
+        &self,
+        user_name: &str,
+        unread_only: bool,
+        before_id: Option<MessageId>,
+        limit: u32,
+    ) -> Result<Vec<Mention>, ChatError>;
+
+    // marks all user's mentions up to message_id read
+    async fn mark_mentions_read(
+        &self,
+        user_name: &str,
+        message_id: MessageId,
+    ) -> Result<(), ChatError>;
+
+    // id of the conversation of exactly these members, created if there is none yet.
+    // Fails with NoSuchUser if any of the members does not exist.
+    async fn open_conversation(&self, members: &[String]) -> Result<ConversationId, ChatError>;
+
+    // None if there is no such conversation
+    async fn get_conversation_members(
+        &self,
+        conversation_id: ConversationId,
+    ) -> Result<Option<Vec<String>>, ChatError>;
+
+    async fn list_conversations(&self, user_name: &str)
+        -> Result<Vec<ConversationInfo>, ChatError>;
+
+    async fn save_direct_message(
+        &self,
+        conversation_id: ConversationId,
+        user_name: &str,
+        message: &str,
+    ) -> Result<DirectMessage, ChatError>;
+
+    // unread messages of all user's conversations, oldest first
+    async fn get_unseen_direct_messages(
+        &self,
+        user_name: &str,
+    ) -> Result<Vec<DirectMessage>, ChatError>;
+
+    // same as mark_read, for a conversation user is member of
+    async fn mark_conversation_read(
+        &self,
+        conversation_id: ConversationId,
+        user_name: &str,
+        message_id: i32,
+    ) -> Result<(), ChatError>;
+
+    // moves user's read marker in channel forward to message_id, never backwards.
+    // Mentions of the user in the channel up to it are read as well.
+    // Fails with NoSuchMessage if message is not in the channel.
+    async fn mark_read(
+        &self,
+        channel_name: &str,
+        user_name: &str,
+        message_id: MessageId,
+    ) -> Result<(), ChatError>;
 }
 
-// for now cookie it is always empty, but will be usefull later to introduce remembering the state
+// Handed to user after login, identifies one of their sessions
 #[derive(Serialize, Deserialize, Debug, Clone, Hash)]
 pub struct AuthenticationToken {
     pub user_name: String,
-    cookie: Cookie,
-}
-
-#[derive(Debug)]
-struct ChannelData {
-    pub name: String,
-}
-
-impl From<Row> for ChannelData {
-    fn from(row: Row) -> Self {
-        Self {
-            name: row.get("name"),
-        }
-    }
+    pub session_id: SessionId,
+    pub(crate) cookie: Cookie,
 }
@@ -0,0 +1,1321 @@
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::sync::RwLock;
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+
+use super::{AuthenticationToken, ChatStore};
+use crate::channel::{ChannelActivity, ChannelInvitation, ChannelTopic, StoredChannel, Visibility};
+use crate::direct::{ConversationId, ConversationInfo, DirectMessage};
+use crate::mentions::Mention;
+use crate::messages::{
+    ChatMessage, MessageId, MessageRevision, PinnedMessage, ReactionCount, ThreadInfo,
+};
+use crate::migrations::{DEFAULT_ADMIN_NAME, DEFAULT_ADMIN_PASSWORD, DEFAULT_CHANNELS};
+use crate::moderation::{Sanction, SanctionKind};
+use crate::password::{self, StoredPassword};
+use crate::permissions::Role;
+use crate::search::{self, SearchHit, SearchQuery};
+use crate::session::{SessionInfo, Sessions};
+use crate::utils::{unix_timestamp, ChatError};
+
+// Store keeping everything in process memory, lost on restart.
+// Useful for local runs and smoke tests without a database server.
+#[derive(Debug, Default)]
+pub struct InMemoryDatabase {
+    data: RwLock<Data>,
+    sessions: Sessions,
+}
+
+#[derive(Debug, Default)]
+struct Data {
+    channels: Vec<StoredChannel>,
+    // (channel_name, user_name) of private channel members
+    channel_members: HashSet<(String, String)>,
+    // user_name -> pending invitations, oldest first
+    invitations: HashMap<String, Vec<ChannelInvitation>>,
+    // oldest first, expired ones are kept as well
+    sanctions: Vec<Sanction>,
+    users: HashMap<String, UserData>,
+    messages: Vec<ChatMessage>,
+    // ids are never reused, even after the messages they were given to are gone
+    next_message_id: MessageId,
+    // content replaced by edits and deletes, oldest first
+    revisions: Vec<MessageRevision>,
+    // (message_id, user_name, reaction), counts are kept on the messages themselves
+    reactions: HashSet<(MessageId, String, String)>,
+    // (user_name, channel_name) -> id of last seen message
+    history: HashMap<(String, String), i32>,
+    // (user_name, parent_id) -> id of last seen reply in the thread
+    thread_history: HashMap<(String, MessageId), MessageId>,
+    // message_id -> (pinned_by, pinned_at)
+    pins: HashMap<MessageId, (String, i64)>,
+    // (message_id, user_name) -> when the mention was read
+    mentions: HashMap<(MessageId, String), Option<i64>>,
+    conversations: Vec<ConversationData>,
+    next_conversation_id: ConversationId,
+    direct_messages: Vec<DirectMessage>,
+    next_direct_message_id: i32,
+}
+
+// Hands out the id a counter points at, ids start at 1 like database serials do
+fn take_id(next_id: &mut i32) -> i32 {
+    let id = (*next_id).max(1);
+    *next_id = id + 1;
+    id
+}
+
+#[derive(Debug)]
+struct ConversationData {
+    id: ConversationId,
+    // sorted
+    members: Vec<String>,
+    // user_name -> id of last read message
+    last_read: HashMap<String, i32>,
+}
+
+impl ConversationData {
+    fn last_read_id(&self, user_name: &str) -> i32 {
+        self.last_read.get(user_name).copied().unwrap_or(0)
+    }
+}
+
+impl Data {
+    fn has_channel(&self, channel_name: &str) -> bool {
+        self.channels
+            .iter()
+            .any(|channel| channel.name == channel_name)
+    }
+
+    fn is_channel_member(&self, channel_name: &str, user_name: &str) -> bool {
+        self.channel_members
+            .contains(&(channel_name.to_string(), user_name.to_string()))
+    }
+
+    // Message of the channel that was not deleted, with its content saved as a revision
+    fn revise_message(
+        &mut self,
+        channel_name: &str,
+        message_id: MessageId,
+        revised_by: &str,
+        revised_at: i64,
+    ) -> Result<&mut ChatMessage, ChatError> {
+        let message = self
+            .messages
+            .iter_mut()
+            .find(|message| {
+                message.id == message_id
+                    && message.channel == channel_name
+                    && message.deleted_at.is_none()
+            })
+            .ok_or(ChatError::NoSuchMessage)?;
+        self.revisions.push(MessageRevision {
+            message_id,
+            body: message.body.clone(),
+            revised_by: revised_by.to_string(),
+            revised_at,
+        });
+        Ok(message)
+    }
+
+    // Marks user's mentions in messages up to message_id matching the filter read
+    fn read_mentions<F>(&mut self, user_name: &str, message_id: MessageId, filter: F)
+    where
+        F: Fn(&ChatMessage) -> bool,
+    {
+        let now = unix_timestamp();
+        for ((mention_id, mention_user), read_at) in self.mentions.iter_mut() {
+            if *mention_id <= message_id
+                && mention_user == user_name
+                && read_at.is_none()
+                && self
+                    .messages
+                    .iter()
+                    .any(|message| message.id == *mention_id && filter(message))
+            {
+                *read_at = Some(now);
+            }
+        }
+    }
+
+    fn is_visible(&self, channel_name: &str, user_name: &str) -> bool {
+        self.channels.iter().any(|channel| {
+            channel.name == channel_name
+                && (channel.visibility == Visibility::Public
+                    || self.is_channel_member(channel_name, user_name))
+        })
+    }
+}
+
+#[derive(Debug)]
+struct UserData {
+    password: StoredPassword,
+    role: Role,
+}
+
+impl InMemoryDatabase {
+    pub fn new() -> InMemoryDatabase {
+        InMemoryDatabase::default()
+    }
+
+    // Same initial content as a freshly seeded database
+    pub async fn with_defaults() -> Result<InMemoryDatabase> {
+        let admin_password = StoredPassword {
+            hash: Some(password::hash_password(DEFAULT_ADMIN_PASSWORD).await?),
+            legacy: None,
+        };
+        let chat_db = InMemoryDatabase::new();
+        {
+            let mut data = chat_db.data.write().unwrap();
+            data.channels = DEFAULT_CHANNELS
+                .iter()
+                .map(|name| StoredChannel {
+                    name: name.to_string(),
+                    owner: None,
+                    visibility: Visibility::Public,
+                    topic: ChannelTopic::default(),
+                    archived_at: None,
+                })
+                .collect();
+            data.users.insert(
+                DEFAULT_ADMIN_NAME.to_string(),
+                UserData {
+                    password: admin_password,
+                    role: Role::Admin,
+                },
+            );
+        }
+        Ok(chat_db)
+    }
+}
+
+#[async_trait]
+impl ChatStore for InMemoryDatabase {
+    async fn get_password(&self, name: &str) -> Result<Option<StoredPassword>, ChatError> {
+        Ok(self
+            .data
+            .read()
+            .unwrap()
+            .users
+            .get(name)
+            .map(|user| user.password.clone()))
+    }
+
+    async fn set_password_hash(&self, name: &str, hash: &str) -> Result<(), ChatError> {
+        if let Some(user) = self.data.write().unwrap().users.get_mut(name) {
+            user.password.hash = Some(hash.to_string());
+            user.password.legacy = None;
+        }
+        Ok(())
+    }
+
+    async fn create_session(&self, user_name: &str) -> Result<AuthenticationToken, ChatError> {
+        Ok(self.sessions.create(user_name))
+    }
+
+    async fn authorize_connection(&self, token: &AuthenticationToken) -> Result<(), ChatError> {
+        self.sessions.validate(token)
+    }
+
+    async fn list_sessions(&self, user_name: &str) -> Result<Vec<SessionInfo>, ChatError> {
+        Ok(self.sessions.list(user_name))
+    }
+
+    async fn revoke_session(&self, user_name: &str, session_id: &str) -> Result<bool, ChatError> {
+        Ok(self.sessions.revoke(user_name, session_id))
+    }
+
+    async fn get_role(&self, user_name: &str) -> Result<Option<Role>, ChatError> {
+        Ok(self
+            .data
+            .read()
+            .unwrap()
+            .users
+            .get(user_name)
+            .map(|user| user.role))
+    }
+
+    async fn set_role(&self, user_name: &str, role: Role) -> Result<bool, ChatError> {
+        match self.data.write().unwrap().users.get_mut(user_name) {
+            Some(user) => {
+                user.role = role;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn get_channels(&self) -> Result<Vec<StoredChannel>> {
+        Ok(self.data.read().unwrap().channels.clone())
+    }
+
+    async fn create_channel(&self, name: &str, owner: &str, visibility: Visibility) -> Result<()> {
+        let mut data = self.data.write().unwrap();
+        if data.has_channel(name) {
+            bail!(ChatError::NameUsed);
+        }
+        data.channels.push(StoredChannel {
+            name: name.to_string(),
+            owner: Some(owner.to_string()),
+            visibility,
+            topic: ChannelTopic::default(),
+            archived_at: None,
+        });
+        if visibility == Visibility::Private {
+            data.channel_members
+                .insert((name.to_string(), owner.to_string()));
+        }
+        Ok(())
+    }
+
+    async fn rename_channel(&self, name: &str, new_name: &str) -> Result<(), ChatError> {
+        let mut data = self.data.write().unwrap();
+        if data.has_channel(new_name) {
+            return Err(ChatError::NameUsed);
+        }
+        let channel = data
+            .channels
+            .iter_mut()
+            .find(|channel| channel.name == name)
+            .ok_or(ChatError::NoSuchChannel)?;
+        channel.name = new_name.to_string();
+        for message in data.messages.iter_mut() {
+            if message.channel == name {
+                message.channel = new_name.to_string();
+            }
+        }
+        data.history = std::mem::take(&mut data.history)
+            .into_iter()
+            .map(|((user_name, channel_name), message_id)| {
+                let channel_name = if channel_name == name {
+                    new_name.to_string()
+                } else {
+                    channel_name
+                };
+                ((user_name, channel_name), message_id)
+            })
+            .collect();
+        data.channel_members = std::mem::take(&mut data.channel_members)
+            .into_iter()
+            .map(|(channel_name, user_name)| {
+                let channel_name = if channel_name == name {
+                    new_name.to_string()
+                } else {
+                    channel_name
+                };
+                (channel_name, user_name)
+            })
+            .collect();
+        for invitation in data.invitations.values_mut().flatten() {
+            if invitation.channel == name {
+                invitation.channel = new_name.to_string();
+            }
+        }
+        for sanction in data.sanctions.iter_mut() {
+            if sanction.channel.as_deref() == Some(name) {
+                sanction.channel = Some(new_name.to_string());
+            }
+        }
+        Ok(())
+    }
+
+    async fn archive_channel(&self, name: &str, archived_at: i64) -> Result<(), ChatError> {
+        let mut data = self.data.write().unwrap();
+        let channel = data
+            .channels
+            .iter_mut()
+            .find(|channel| channel.name == name)
+            .ok_or(ChatError::NoSuchChannel)?;
+        channel.archived_at = Some(archived_at);
+        Ok(())
+    }
+
+    async fn set_channel_topic(&self, name: &str, topic: &ChannelTopic) -> Result<(), ChatError> {
+        let mut data = self.data.write().unwrap();
+        let channel = data
+            .channels
+            .iter_mut()
+            .find(|channel| channel.name == name)
+            .ok_or(ChatError::NoSuchChannel)?;
+        channel.topic = topic.clone();
+        Ok(())
+    }
+
+    async fn delete_channel(&self, name: &str) -> Result<(), ChatError> {
+        let mut data = self.data.write().unwrap();
+        if !data.has_channel(name) {
+            return Err(ChatError::NoSuchChannel);
+        }
+        data.channels.retain(|channel| channel.name != name);
+        let deleted_ids: HashSet<MessageId> = data
+            .messages
+            .iter()
+            .filter(|message| message.channel == name)
+            .map(|message| message.id)
+            .collect();
+        data.revisions
+            .retain(|revision| !deleted_ids.contains(&revision.message_id));
+        data.reactions
+            .retain(|(message_id, _, _)| !deleted_ids.contains(message_id));
+        data.messages.retain(|message| message.channel != name);
+        data.history
+            .retain(|(_, channel_name), _| channel_name != name);
+        data.thread_history
+            .retain(|(_, parent_id), _| !deleted_ids.contains(parent_id));
+        data.mentions
+            .retain(|(message_id, _), _| !deleted_ids.contains(message_id));
+        data.pins
+            .retain(|message_id, _| !deleted_ids.contains(message_id));
+        data.channel_members
+            .retain(|(channel_name, _)| channel_name != name);
+        for invitations in data.invitations.values_mut() {
+            invitations.retain(|invitation| invitation.channel != name);
+        }
+        data.sanctions
+            .retain(|sanction| sanction.channel.as_deref() != Some(name));
+        Ok(())
+    }
+
+    async fn is_channel_member(
+        &self,
+        channel_name: &str,
+        user_name: &str,
+    ) -> Result<bool, ChatError> {
+        Ok(self
+            .data
+            .read()
+            .unwrap()
+            .is_channel_member(channel_name, user_name))
+    }
+
+    async fn get_member_channels(&self, user_name: &str) -> Result<Vec<String>, ChatError> {
+        Ok(self
+            .data
+            .read()
+            .unwrap()
+            .channel_members
+            .iter()
+            .filter(|(_, member)| member == user_name)
+            .map(|(channel_name, _)| channel_name.clone())
+            .collect())
+    }
+
+    async fn invite_to_channel(
+        &self,
+        channel_name: &str,
+        user_name: &str,
+        invited_by: &str,
+    ) -> Result<ChannelInvitation, ChatError> {
+        let mut data = self.data.write().unwrap();
+        if !data.users.contains_key(user_name) {
+            return Err(ChatError::NoSuchUser);
+        }
+        if data.is_channel_member(channel_name, user_name) {
+            return Err(ChatError::AlreadyMember);
+        }
+        let invitation = ChannelInvitation {
+            channel: channel_name.to_string(),
+            invited_by: invited_by.to_string(),
+            created_at: unix_timestamp(),
+        };
+        let invitations = data.invitations.entry(user_name.to_string()).or_default();
+        invitations.retain(|invitation| invitation.channel != channel_name);
+        invitations.push(invitation.clone());
+        Ok(invitation)
+    }
+
+    async fn list_invitations(&self, user_name: &str) -> Result<Vec<ChannelInvitation>, ChatError> {
+        Ok(self
+            .data
+            .read()
+            .unwrap()
+            .invitations
+            .get(user_name)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn answer_invitation(
+        &self,
+        channel_name: &str,
+        user_name: &str,
+        accept: bool,
+    ) -> Result<(), ChatError> {
+        let mut data = self.data.write().unwrap();
+        let invitations = data
+            .invitations
+            .get_mut(user_name)
+            .ok_or(ChatError::NoSuchInvitation)?;
+        let count = invitations.len();
+        invitations.retain(|invitation| invitation.channel != channel_name);
+        if invitations.len() == count {
+            return Err(ChatError::NoSuchInvitation);
+        }
+        if accept {
+            data.channel_members
+                .insert((channel_name.to_string(), user_name.to_string()));
+        }
+        Ok(())
+    }
+
+    async fn add_sanction(&self, sanction: &Sanction) -> Result<(), ChatError> {
+        let mut data = self.data.write().unwrap();
+        if !data.users.contains_key(&sanction.user_name) {
+            return Err(ChatError::NoSuchUser);
+        }
+        data.sanctions.push(sanction.clone());
+        Ok(())
+    }
+
+    async fn get_active_sanction(
+        &self,
+        user_name: &str,
+        channel_name: Option<&str>,
+        kind: SanctionKind,
+    ) -> Result<Option<Sanction>, ChatError> {
+        let now = unix_timestamp();
+        Ok(self
+            .data
+            .read()
+            .unwrap()
+            .sanctions
+            .iter()
+            .filter(|sanction| {
+                sanction.user_name == user_name
+                    && sanction.kind == kind
+                    && (sanction.channel.is_none() || sanction.channel.as_deref() == channel_name)
+                    && sanction.is_active(now)
+            })
+            // None never expires, so it lasts longest
+            .max_by_key(|sanction| sanction.expires_at.unwrap_or(i64::MAX))
+            .cloned())
+    }
+
+    async fn get_channels_activity(
+        &self,
+        user_name: &str,
+    ) -> Result<HashMap<String, ChannelActivity>, ChatError> {
+        let data = self.data.read().unwrap();
+        let mut activities: HashMap<String, ChannelActivity> = data
+            .channels
+            .iter()
+            .map(|channel| (channel.name.clone(), ChannelActivity::default()))
+            .collect();
+        for message in data.messages.iter() {
+            let activity = match activities.get_mut(&message.channel) {
+                Some(activity) => activity,
+                None => continue,
+            };
+            activity.last_activity = activity.last_activity.max(Some(message.created_at));
+            let last_seen_message_id = data
+                .history
+                .get(&(user_name.to_string(), message.channel.clone()))
+                .copied()
+                .unwrap_or(-1);
+            if message.id > last_seen_message_id
+                && message.parent_id.is_none()
+                && message.deleted_at.is_none()
+            {
+                activity.unread_count += 1;
+            }
+            if message.deleted_at.is_none()
+                && data
+                    .mentions
+                    .get(&(message.id, user_name.to_string()))
+                    .is_some_and(|read_at| read_at.is_none())
+            {
+                activity.mention_count += 1;
+            }
+        }
+        Ok(activities)
+    }
+
+    async fn create_user(&self, name: &str, password: &str) -> Result<()> {
+        let stored = StoredPassword {
+            hash: Some(password::hash_password(password).await?),
+            legacy: None,
+        };
+        let mut data = self.data.write().unwrap();
+        if data.users.contains_key(name) {
+            bail!(ChatError::NameUsed);
+        }
+        data.users.insert(
+            name.to_string(),
+            UserData {
+                password: stored,
+                role: Role::Member,
+            },
+        );
+        Ok(())
+    }
+
+    async fn get_unseed_messages(
+        &self,
+        channel_name: &str,
+        user_name: &str,
+    ) -> Result<Vec<ChatMessage>, ChatError> {
+        let data = self.data.read().unwrap();
+        let last_seen_message_id = data
+            .history
+            .get(&(user_name.to_string(), channel_name.to_string()))
+            .copied()
+            .unwrap_or(-1);
+        Ok(data
+            .messages
+            .iter()
+            .filter(|message| {
+                message.channel == channel_name
+                    && message.id > last_seen_message_id
+                    && message.deleted_at.is_none()
+                    && message.parent_id.is_none()
+            })
+            .cloned()
+            .collect())
+    }
+
+    async fn get_messages_before(
+        &self,
+        channel_name: &str,
+        parent_id: Option<MessageId>,
+        before_id: Option<MessageId>,
+        limit: u32,
+    ) -> Result<Vec<ChatMessage>, ChatError> {
+        let data = self.data.read().unwrap();
+        let before_id = before_id.unwrap_or(MessageId::MAX);
+        let mut messages: Vec<ChatMessage> = data
+            .messages
+            .iter()
+            .rev()
+            .filter(|message| {
+                message.channel == channel_name
+                    && message.parent_id == parent_id
+                    && message.id < before_id
+            })
+            .take(limit as usize)
+            .cloned()
+            .collect();
+        messages.reverse();
+        Ok(messages)
+    }
+
+    async fn search_messages(
+        &self,
+        query: &SearchQuery,
+        user_name: &str,
+    ) -> Result<Vec<SearchHit>, ChatError> {
+        let data = self.data.read().unwrap();
+        let query_terms = search::terms(&query.text);
+        let mut hits: Vec<SearchHit> = data
+            .messages
+            .iter()
+            .filter(|message| message.deleted_at.is_none() && query.matches_filters(message))
+            .filter(|message| data.is_visible(&message.channel, user_name))
+            .filter_map(|message| {
+                search::rank(&query_terms, &message.body).map(|rank| SearchHit {
+                    message: message.clone(),
+                    rank,
+                })
+            })
+            .collect();
+        hits.sort_by(|a, b| {
+            b.rank
+                .total_cmp(&a.rank)
+                .then(b.message.id.cmp(&a.message.id))
+        });
+        Ok(hits
+            .into_iter()
+            .skip(query.offset as usize)
+            .take(query.limit as usize)
+            .collect())
+    }
+
+    async fn save_message(
+        &self,
+        channel_name: &str,
+        user_name: &str,
+        parent_id: Option<MessageId>,
+        message: &str,
+    ) -> Result<ChatMessage, ChatError> {
+        let mut data = self.data.write().unwrap();
+        if !data.has_channel(channel_name) {
+            return Err(anyhow!("No such channel {}", channel_name).into());
+        }
+        if !data.users.contains_key(user_name) {
+            return Err(anyhow!("No such user {}", user_name).into());
+        }
+        let id = take_id(&mut data.next_message_id);
+        let message = ChatMessage {
+            id,
+            channel: channel_name.to_string(),
+            author: user_name.to_string(),
+            created_at: unix_timestamp(),
+            body: message.to_string(),
+            edited_at: None,
+            deleted_at: None,
+            parent_id,
+            reply_count: 0,
+            reactions: Vec::new(),
+        };
+        if let Some(parent) = data
+            .messages
+            .iter_mut()
+            .find(|parent| Some(parent.id) == parent_id)
+        {
+            parent.reply_count += 1;
+        }
+        data.messages.push(message.clone());
+        Ok(message)
+    }
+
+    async fn get_message(
+        &self,
+        channel_name: &str,
+        message_id: MessageId,
+    ) -> Result<Option<ChatMessage>, ChatError> {
+        Ok(self
+            .data
+            .read()
+            .unwrap()
+            .messages
+            .iter()
+            .find(|message| message.id == message_id && message.channel == channel_name)
+            .cloned())
+    }
+
+    async fn edit_message(
+        &self,
+        channel_name: &str,
+        message_id: MessageId,
+        edited_by: &str,
+        content: &str,
+    ) -> Result<ChatMessage, ChatError> {
+        let now = unix_timestamp();
+        let mut data = self.data.write().unwrap();
+        let message = data.revise_message(channel_name, message_id, edited_by, now)?;
+        message.body = content.to_string();
+        message.edited_at = Some(now);
+        Ok(message.clone())
+    }
+
+    async fn delete_message(
+        &self,
+        channel_name: &str,
+        message_id: MessageId,
+        deleted_by: &str,
+    ) -> Result<(), ChatError> {
+        let now = unix_timestamp();
+        let mut data = self.data.write().unwrap();
+        let message = data.revise_message(channel_name, message_id, deleted_by, now)?;
+        message.body.clear();
+        message.deleted_at = Some(now);
+        Ok(())
+    }
+
+    async fn add_reaction(
+        &self,
+        channel_name: &str,
+        message_id: MessageId,
+        user_name: &str,
+        reaction: &str,
+    ) -> Result<bool, ChatError> {
+        let mut data = self.data.write().unwrap();
+        if !data.messages.iter().any(|message| {
+            message.id == message_id
+                && message.channel == channel_name
+                && message.deleted_at.is_none()
+        }) {
+            return Err(ChatError::NoSuchMessage);
+        }
+        if !data
+            .reactions
+            .insert((message_id, user_name.to_string(), reaction.to_string()))
+        {
+            return Ok(false);
+        }
+        let message = data
+            .messages
+            .iter_mut()
+            .find(|message| message.id == message_id)
+            .ok_or(ChatError::NoSuchMessage)?;
+        match message
+            .reactions
+            .iter_mut()
+            .find(|counted| counted.reaction == reaction)
+        {
+            Some(counted) => counted.count += 1,
+            None => message.reactions.push(ReactionCount {
+                reaction: reaction.to_string(),
+                count: 1,
+            }),
+        }
+        Ok(true)
+    }
+
+    async fn remove_reaction(
+        &self,
+        channel_name: &str,
+        message_id: MessageId,
+        user_name: &str,
+        reaction: &str,
+    ) -> Result<bool, ChatError> {
+        let mut data = self.data.write().unwrap();
+        if !data
+            .messages
+            .iter()
+            .any(|message| message.id == message_id && message.channel == channel_name)
+            || !data
+                .reactions
+                .remove(&(message_id, user_name.to_string(), reaction.to_string()))
+        {
+            return Ok(false);
+        }
+        let message = data
+            .messages
+            .iter_mut()
+            .find(|message| message.id == message_id)
+            .ok_or(ChatError::NoSuchMessage)?;
+        for counted in message
+            .reactions
+            .iter_mut()
+            .filter(|counted| counted.reaction == reaction)
+        {
+            counted.count -= 1;
+        }
+        message.reactions.retain(|counted| counted.count > 0);
+        Ok(true)
+    }
+
+    async fn pin_message(
+        &self,
+        channel_name: &str,
+        message_id: MessageId,
+        pinned_by: &str,
+    ) -> Result<bool, ChatError> {
+        let mut data = self.data.write().unwrap();
+        if !data.messages.iter().any(|message| {
+            message.id == message_id
+                && message.channel == channel_name
+                && message.deleted_at.is_none()
+        }) {
+            return Err(ChatError::NoSuchMessage);
+        }
+        if data.pins.contains_key(&message_id) {
+            return Ok(false);
+        }
+        data.pins
+            .insert(message_id, (pinned_by.to_string(), unix_timestamp()));
+        Ok(true)
+    }
+
+    async fn unpin_message(
+        &self,
+        channel_name: &str,
+        message_id: MessageId,
+    ) -> Result<bool, ChatError> {
+        let mut data = self.data.write().unwrap();
+        if !data
+            .messages
+            .iter()
+            .any(|message| message.id == message_id && message.channel == channel_name)
+        {
+            return Ok(false);
+        }
+        Ok(data.pins.remove(&message_id).is_some())
+    }
+
+    async fn get_pinned_messages(
+        &self,
+        channel_name: &str,
+    ) -> Result<Vec<PinnedMessage>, ChatError> {
+        let data = self.data.read().unwrap();
+        let mut pins: Vec<PinnedMessage> = data
+            .messages
+            .iter()
+            .filter(|message| message.channel == channel_name && message.deleted_at.is_none())
+            .filter_map(|message| {
+                let (pinned_by, pinned_at) = data.pins.get(&message.id)?;
+                Some(PinnedMessage {
+                    message: message.clone(),
+                    pinned_by: pinned_by.clone(),
+                    pinned_at: *pinned_at,
+                })
+            })
+            .collect();
+        pins.sort_by_key(|pin| Reverse((pin.pinned_at, pin.message.id)));
+        Ok(pins)
+    }
+
+    async fn get_message_revisions(
+        &self,
+        message_id: MessageId,
+    ) -> Result<Vec<MessageRevision>, ChatError> {
+        Ok(self
+            .data
+            .read()
+            .unwrap()
+            .revisions
+            .iter()
+            .filter(|revision| revision.message_id == message_id)
+            .cloned()
+            .collect())
+    }
+
+    async fn list_threads(
+        &self,
+        channel_name: &str,
+        user_name: &str,
+    ) -> Result<Vec<ThreadInfo>, ChatError> {
+        let data = self.data.read().unwrap();
+        let mut threads: Vec<ThreadInfo> = data
+            .messages
+            .iter()
+            .filter(|parent| parent.channel == channel_name && parent.reply_count > 0)
+            .filter_map(|parent| {
+                let last_seen_message_id = data
+                    .thread_history
+                    .get(&(user_name.to_string(), parent.id))
+                    .copied();
+                let replies = data
+                    .messages
+                    .iter()
+                    .filter(|message| message.parent_id == Some(parent.id));
+                let followed = parent.author == user_name
+                    || last_seen_message_id.is_some()
+                    || replies.clone().any(|message| message.author == user_name);
+                if !followed {
+                    return None;
+                }
+                Some(ThreadInfo {
+                    parent: parent.clone(),
+                    unread_count: replies
+                        .clone()
+                        .filter(|message| {
+                            message.id > last_seen_message_id.unwrap_or(-1)
+                                && message.deleted_at.is_none()
+                        })
+                        .count() as i64,
+                    last_activity: replies.map(|message| message.created_at).max()?,
+                })
+            })
+            .collect();
+        threads.sort_by_key(|thread| Reverse((thread.last_activity, thread.parent.id)));
+        Ok(threads)
+    }
+
+    async fn mark_thread_read(
+        &self,
+        channel_name: &str,
+        user_name: &str,
+        parent_id: MessageId,
+        message_id: MessageId,
+    ) -> Result<(), ChatError> {
+        let mut data = self.data.write().unwrap();
+        if !data.messages.iter().any(|message| {
+            message.id == message_id
+                && message.parent_id == Some(parent_id)
+                && message.channel == channel_name
+        }) {
+            return Err(ChatError::NoSuchMessage);
+        }
+        let marker = data
+            .thread_history
+            .entry((user_name.to_string(), parent_id))
+            .or_insert(message_id);
+        *marker = (*marker).max(message_id);
+        data.read_mentions(user_name, message_id, |message| {
+            message.parent_id == Some(parent_id)
+        });
+        Ok(())
+    }
+
+    async fn save_mentions(
+        &self,
+        message_id: MessageId,
+        user_names: &[String],
+        whole_channel: bool,
+    ) -> Result<Vec<String>, ChatError> {
+        let mut data = self.data.write().unwrap();
+        let message = match data
+            .messages
+            .iter()
+            .find(|message| message.id == message_id)
+        {
+            Some(message) => message,
+            None => return Ok(Vec::new()),
+        };
+        let channel_name = message.channel.clone();
+        let mut candidates: Vec<String> = user_names.to_vec();
+        if whole_channel {
+            candidates.extend(
+                data.history
+                    .keys()
+                    .filter(|(_, history_channel)| *history_channel == channel_name)
+                    .map(|(user_name, _)| user_name.clone()),
+            );
+            candidates.extend(
+                data.channel_members
+                    .iter()
+                    .filter(|(member_channel, _)| *member_channel == channel_name)
+                    .map(|(_, user_name)| user_name.clone()),
+            );
+        }
+        let mut mentioned: Vec<String> = candidates
+            .into_iter()
+            .filter(|user_name| {
+                *user_name != message.author
+                    && data.users.contains_key(user_name)
+                    && data.is_visible(&channel_name, user_name)
+                    && !data.mentions.contains_key(&(message_id, user_name.clone()))
+            })
+            .collect();
+        mentioned.sort();
+        mentioned.dedup();
+        for user_name in &mentioned {
+            data.mentions.insert((message_id, user_name.clone()), None);
+        }
+        Ok(mentioned)
+    }
+
+    async fn list_mentions(
+        &self,
+        user_name: &str,
+        unread_only: bool,
+        before_id: Option<MessageId>,
+        limit: u32,
+    ) -> Result<Vec<Mention>, ChatError> {
+        let data = self.data.read().unwrap();
+        let before_id = before_id.unwrap_or(MessageId::MAX);
+        let mut mentions: Vec<Mention> = data
+            .messages
+            .iter()
+            .rev()
+            .filter(|message| message.id < before_id && message.deleted_at.is_none())
+            .filter_map(|message| {
+                let read_at = data.mentions.get(&(message.id, user_name.to_string()))?;
+                if unread_only && read_at.is_some() {
+                    return None;
+                }
+                Some(Mention {
+                    message: message.clone(),
+                    read: read_at.is_some(),
+                })
+            })
+            .filter(|mention| data.is_visible(&mention.message.channel, user_name))
+            .take(limit as usize)
+            .collect();
+        mentions.reverse();
+        Ok(mentions)
+    }
+
+    async fn mark_mentions_read(
+        &self,
+        user_name: &str,
+        message_id: MessageId,
+    ) -> Result<(), ChatError> {
+        let mut data = self.data.write().unwrap();
+        data.read_mentions(user_name, message_id, |_| true);
+        Ok(())
+    }
+
+    async fn open_conversation(&self, members: &[String]) -> Result<ConversationId, ChatError> {
+        let mut data = self.data.write().unwrap();
+        if !members.iter().all(|member| data.users.contains_key(member)) {
+            return Err(ChatError::NoSuchUser);
+        }
+        if let Some(conversation) = data
+            .conversations
+            .iter()
+            .find(|conversation| conversation.members == members)
+        {
+            return Ok(conversation.id);
+        }
+        let id = take_id(&mut data.next_conversation_id);
+        data.conversations.push(ConversationData {
+            id,
+            members: members.to_vec(),
+            last_read: HashMap::new(),
+        });
+        Ok(id)
+    }
+
+    async fn get_conversation_members(
+        &self,
+        conversation_id: ConversationId,
+    ) -> Result<Option<Vec<String>>, ChatError> {
+        let data = self.data.read().unwrap();
+        Ok(data
+            .conversations
+            .iter()
+            .find(|conversation| conversation.id == conversation_id)
+            .map(|conversation| conversation.members.clone()))
+    }
+
+    async fn list_conversations(
+        &self,
+        user_name: &str,
+    ) -> Result<Vec<ConversationInfo>, ChatError> {
+        let data = self.data.read().unwrap();
+        let mut conversations: Vec<ConversationInfo> = data
+            .conversations
+            .iter()
+            .filter(|conversation| {
+                conversation
+                    .members
+                    .iter()
+                    .any(|member| member == user_name)
+            })
+            .map(|conversation| {
+                let last_read_id = conversation.last_read_id(user_name);
+                let messages = data
+                    .direct_messages
+                    .iter()
+                    .filter(|message| message.conversation_id == conversation.id);
+                ConversationInfo {
+                    id: conversation.id,
+                    members: conversation.members.clone(),
+                    unread_count: messages
+                        .clone()
+                        .filter(|message| message.id > last_read_id)
+                        .count() as i64,
+                    last_activity: messages.map(|message| message.created_at).max(),
+                }
+            })
+            .collect();
+        conversations.sort_by(|a, b| {
+            (b.last_activity.is_some(), b.last_activity, b.id).cmp(&(
+                a.last_activity.is_some(),
+                a.last_activity,
+                a.id,
+            ))
+        });
+        Ok(conversations)
+    }
+
+    async fn save_direct_message(
+        &self,
+        conversation_id: ConversationId,
+        user_name: &str,
+        message: &str,
+    ) -> Result<DirectMessage, ChatError> {
+        let mut data = self.data.write().unwrap();
+        let id = take_id(&mut data.next_direct_message_id);
+        let message = DirectMessage {
+            id,
+            conversation_id,
+            author: user_name.to_string(),
+            created_at: unix_timestamp(),
+            body: message.to_string(),
+        };
+        data.direct_messages.push(message.clone());
+        Ok(message)
+    }
+
+    async fn get_unseen_direct_messages(
+        &self,
+        user_name: &str,
+    ) -> Result<Vec<DirectMessage>, ChatError> {
+        let data = self.data.read().unwrap();
+        let last_read: HashMap<ConversationId, i32> = data
+            .conversations
+            .iter()
+            .filter(|conversation| {
+                conversation
+                    .members
+                    .iter()
+                    .any(|member| member == user_name)
+            })
+            .map(|conversation| (conversation.id, conversation.last_read_id(user_name)))
+            .collect();
+        Ok(data
+            .direct_messages
+            .iter()
+            .filter(|message| {
+                last_read
+                    .get(&message.conversation_id)
+                    .is_some_and(|last_read_id| message.id > *last_read_id)
+            })
+            .cloned()
+            .collect())
+    }
+
+    async fn mark_conversation_read(
+        &self,
+        conversation_id: ConversationId,
+        user_name: &str,
+        message_id: i32,
+    ) -> Result<(), ChatError> {
+        let mut data = self.data.write().unwrap();
+        if !data
+            .direct_messages
+            .iter()
+            .any(|message| message.id == message_id && message.conversation_id == conversation_id)
+        {
+            return Err(ChatError::NoSuchMessage);
+        }
+        let conversation = data
+            .conversations
+            .iter_mut()
+            .find(|conversation| conversation.id == conversation_id)
+            .filter(|conversation| {
+                conversation
+                    .members
+                    .iter()
+                    .any(|member| member == user_name)
+            })
+            .ok_or(ChatError::NoSuchMessage)?;
+        let last_read_id = conversation
+            .last_read
+            .entry(user_name.to_string())
+            .or_insert(message_id);
+        *last_read_id = (*last_read_id).max(message_id);
+        Ok(())
+    }
+
+    async fn mark_read(
+        &self,
+        channel_name: &str,
+        user_name: &str,
+        message_id: MessageId,
+    ) -> Result<(), ChatError> {
+        let mut data = self.data.write().unwrap();
+        if !data
+            .messages
+            .iter()
+            .any(|message| message.id == message_id && message.channel == channel_name)
+        {
+            return Err(ChatError::NoSuchMessage);
+        }
+        let marker = data
+            .history
+            .entry((user_name.to_string(), channel_name.to_string()))
+            .or_insert(message_id);
+        *marker = (*marker).max(message_id);
+        data.read_mentions(user_name, message_id, |message| {
+            message.channel == channel_name && message.parent_id.is_none()
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHANNEL: &str = DEFAULT_CHANNELS[0];
+
+    async fn store_with_user(user_name: &str) -> InMemoryDatabase {
+        let chat_db = InMemoryDatabase::with_defaults().await.unwrap();
+        chat_db.create_user(user_name, "secret").await.unwrap();
+        chat_db
+    }
+
+    async fn post(chat_db: &InMemoryDatabase, channel_name: &str, body: &str) -> MessageId {
+        chat_db
+            .save_message(channel_name, DEFAULT_ADMIN_NAME, None, body)
+            .await
+            .unwrap()
+            .id
+    }
+
+    fn ids(messages: &[ChatMessage]) -> Vec<MessageId> {
+        messages.iter().map(|message| message.id).collect()
+    }
+
+    #[tokio::test]
+    async fn created_user_can_authenticate() {
+        let chat_db = store_with_user("alice").await;
+        let token = chat_db.authenticate_user("alice", "secret").await.unwrap();
+        assert_eq!(token.user_name, "alice");
+        chat_db.authorize_connection(&token).await.unwrap();
+        assert!(matches!(
+            chat_db.authenticate_user("alice", "wrong").await,
+            Err(ChatError::InvalidPassword)
+        ));
+        assert!(chat_db.create_user("alice", "other").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn message_ids_start_at_one_and_increase() {
+        let chat_db = InMemoryDatabase::with_defaults().await.unwrap();
+        let first = post(&chat_db, CHANNEL, "first").await;
+        let second = post(&chat_db, DEFAULT_CHANNELS[1], "second").await;
+        assert_eq!((first, second), (1, 2));
+    }
+
+    #[tokio::test]
+    async fn deleted_channel_message_ids_are_not_reused() {
+        let chat_db = InMemoryDatabase::with_defaults().await.unwrap();
+        chat_db
+            .create_channel("doomed", DEFAULT_ADMIN_NAME, Visibility::Public)
+            .await
+            .unwrap();
+        post(&chat_db, CHANNEL, "kept").await;
+        let deleted = post(&chat_db, "doomed", "gone").await;
+        chat_db.delete_channel("doomed").await.unwrap();
+        assert!(post(&chat_db, CHANNEL, "next").await > deleted);
+    }
+
+    #[tokio::test]
+    async fn unseen_messages_start_after_read_marker() {
+        let chat_db = store_with_user("alice").await;
+        let mut posted = Vec::new();
+        for body in ["one", "two", "three"] {
+            posted.push(post(&chat_db, CHANNEL, body).await);
+        }
+        let unseen = chat_db.get_unseed_messages(CHANNEL, "alice").await.unwrap();
+        assert_eq!(ids(&unseen), posted);
+        chat_db
+            .mark_read(CHANNEL, "alice", posted[1])
+            .await
+            .unwrap();
+        // marker never moves backwards
+        chat_db
+            .mark_read(CHANNEL, "alice", posted[0])
+            .await
+            .unwrap();
+        let unseen = chat_db.get_unseed_messages(CHANNEL, "alice").await.unwrap();
+        assert_eq!(ids(&unseen), posted[2..]);
+    }
+
+    #[tokio::test]
+    async fn deleted_messages_are_not_unread() {
+        let chat_db = store_with_user("alice").await;
+        let mut posted = Vec::new();
+        for body in ["one", "two", "three"] {
+            posted.push(post(&chat_db, CHANNEL, body).await);
+        }
+        chat_db
+            .delete_message(CHANNEL, posted[1], DEFAULT_ADMIN_NAME)
+            .await
+            .unwrap();
+        let activities = chat_db.get_channels_activity("alice").await.unwrap();
+        assert_eq!(activities[CHANNEL].unread_count, 2);
+        let unseen = chat_db.get_unseed_messages(CHANNEL, "alice").await.unwrap();
+        assert_eq!(ids(&unseen), [posted[0], posted[2]]);
+    }
+
+    #[tokio::test]
+    async fn history_pages_go_backwards() {
+        let chat_db = InMemoryDatabase::with_defaults().await.unwrap();
+        for body in ["1", "2", "3", "4", "5"] {
+            post(&chat_db, CHANNEL, body).await;
+        }
+        post(&chat_db, DEFAULT_CHANNELS[1], "elsewhere").await;
+        let page = chat_db
+            .get_messages_before(CHANNEL, None, None, 2)
+            .await
+            .unwrap();
+        assert_eq!(ids(&page), [4, 5]);
+        let page = chat_db
+            .get_messages_before(CHANNEL, None, Some(4), 2)
+            .await
+            .unwrap();
+        assert_eq!(ids(&page), [2, 3]);
+        let page = chat_db
+            .get_messages_before(CHANNEL, None, Some(2), 2)
+            .await
+            .unwrap();
+        assert_eq!(ids(&page), [1]);
+    }
+}
@@ -0,0 +1,1333 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use tokio_postgres::{Client, Row};
+
+use super::{AuthenticationToken, ChatStore};
+use crate::channel::{ChannelActivity, ChannelInvitation, ChannelTopic, StoredChannel, Visibility};
+use crate::direct::{self, ConversationId, ConversationInfo, DirectMessage};
+use crate::mentions::Mention;
+use crate::messages::{
+    ChatMessage, MessageId, MessageRevision, PinnedMessage, ReactionCount, ThreadInfo,
+};
+use crate::moderation::{Sanction, SanctionKind};
+use crate::password::{self, StoredPassword};
+use crate::permissions::Role;
+use crate::search::{SearchHit, SearchQuery};
+use crate::session::{Session, SessionInfo};
+use crate::utils::{unix_timestamp, ChatError};
+use anyhow::{Context, Result};
+
+#[derive(Debug)]
+pub struct ChatDatabase {
+    client: Client,
+}
+
+impl ChatDatabase {
+    pub fn new(client: Client) -> ChatDatabase {
+        ChatDatabase { client }
+    }
+
+    // Fills reactions of all given messages with a single query
+    async fn load_reactions(&self, messages: Vec<&mut ChatMessage>) -> Result<(), ChatError> {
+        if messages.is_empty() {
+            return Ok(());
+        }
+        let ids: Vec<MessageId> = messages.iter().map(|message| message.id).collect();
+        let rows = self
+            .client
+            .query(
+                "SELECT message_id, reaction, count(*) AS count FROM reactions
+                WHERE message_id = ANY($1)
+                GROUP BY message_id, reaction
+                ORDER BY min(created_at), reaction",
+                &[&ids],
+            )
+            .await?;
+        let mut reactions: HashMap<MessageId, Vec<ReactionCount>> = HashMap::new();
+        for row in rows {
+            reactions
+                .entry(row.get("message_id"))
+                .or_default()
+                .push(ReactionCount {
+                    reaction: row.get("reaction"),
+                    count: row.get("count"),
+                });
+        }
+        for message in messages {
+            message.reactions = reactions.remove(&message.id).unwrap_or_default();
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ChatStore for ChatDatabase {
+    async fn get_password(&self, name: &str) -> Result<Option<StoredPassword>, ChatError> {
+        let row = self
+            .client
+            .query_opt(
+                "SELECT password_hash, password FROM users WHERE name = ($1)",
+                &[&name],
+            )
+            .await?;
+        Ok(row.map(|row| StoredPassword {
+            hash: row.get(0),
+            legacy: row.get(1),
+        }))
+    }
+
+    async fn set_password_hash(&self, name: &str, hash: &str) -> Result<(), ChatError> {
+        self.client
+            .execute(
+                "UPDATE users SET password_hash = ($2), password = NULL WHERE name = ($1)",
+                &[&name, &hash],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn create_session(&self, user_name: &str) -> Result<AuthenticationToken, ChatError> {
+        let (session, token) = Session::new(user_name);
+        self.client
+            .execute(
+                "INSERT INTO sessions (id, user_name, cookie_hash, issued_at, last_used_at, expires_at)
+                VALUES ($1, $2, $3, $4, $5, $6)",
+                &[
+                    &session.id,
+                    &session.user_name,
+                    &session.cookie_hash,
+                    &session.issued_at,
+                    &session.last_used_at,
+                    &session.expires_at(),
+                ],
+            )
+            .await?;
+        Ok(token)
+    }
+
+    async fn authorize_connection(&self, token: &AuthenticationToken) -> Result<(), ChatError> {
+        let mut session = self
+            .client
+            .query_opt(
+                "SELECT id, user_name, cookie_hash, issued_at, last_used_at FROM sessions WHERE id = ($1)",
+                &[&token.session_id],
+            )
+            .await?
+            .map(SessionData::from)
+            .ok_or(ChatError::UnauthenticatedConnection)?
+            .0;
+
+        let now = unix_timestamp();
+        if let Err(e) = session.check(token, now) {
+            if let ChatError::SessionExpired = e {
+                self.client
+                    .execute("DELETE FROM sessions WHERE id = ($1)", &[&session.id])
+                    .await?;
+            }
+            return Err(e);
+        }
+
+        session.last_used_at = now;
+        self.client
+            .execute(
+                "UPDATE sessions SET last_used_at = ($2), expires_at = ($3) WHERE id = ($1)",
+                &[&session.id, &session.last_used_at, &session.expires_at()],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn list_sessions(&self, user_name: &str) -> Result<Vec<SessionInfo>, ChatError> {
+        self.client
+            .execute(
+                "DELETE FROM sessions WHERE expires_at <= ($1)",
+                &[&unix_timestamp()],
+            )
+            .await?;
+        let rows = self
+            .client
+            .query(
+                "SELECT id, user_name, cookie_hash, issued_at, last_used_at FROM sessions
+                WHERE user_name = ($1) ORDER BY issued_at",
+                &[&user_name],
+            )
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| SessionData::from(row).0.info())
+            .collect())
+    }
+
+    async fn revoke_session(&self, user_name: &str, session_id: &str) -> Result<bool, ChatError> {
+        let deleted = self
+            .client
+            .execute(
+                "DELETE FROM sessions WHERE id = ($1) AND user_name = ($2)",
+                &[&session_id, &user_name],
+            )
+            .await?;
+        Ok(deleted > 0)
+    }
+
+    async fn get_role(&self, user_name: &str) -> Result<Option<Role>, ChatError> {
+        let row = self
+            .client
+            .query_opt("SELECT role FROM users WHERE name = ($1)", &[&user_name])
+            .await?;
+        row.map(|row| row.get::<_, String>(0).parse()).transpose()
+    }
+
+    async fn set_role(&self, user_name: &str, role: Role) -> Result<bool, ChatError> {
+        let updated = self
+            .client
+            .execute(
+                "UPDATE users SET role = ($2) WHERE name = ($1)",
+                &[&user_name, &role.as_str()],
+            )
+            .await?;
+        Ok(updated > 0)
+    }
+
+    async fn get_channels(&self) -> Result<Vec<StoredChannel>> {
+        let results = self
+            .client
+            .query(
+                "SELECT name, owner, visibility, topic, description, archived_at FROM channels",
+                &[],
+            )
+            .await
+            .context("Error selecting channels from database!")?;
+        results
+            .into_iter()
+            .map(|row| Ok(ChannelData::try_from(row)?.0))
+            .collect()
+    }
+
+    async fn create_channel(&self, name: &str, owner: &str, visibility: Visibility) -> Result<()> {
+        // single statement, so private channel is never left without its owner as member
+        self.client
+            .execute(
+                "WITH inserted AS (
+                    INSERT INTO channels (name, owner, visibility) VALUES ($1, $2, $3)
+                    RETURNING name, owner, visibility
+                )
+                INSERT INTO channel_members (channel_name, user_name, joined_at)
+                SELECT name, owner, ($4) FROM inserted WHERE visibility = 'private'",
+                &[&name, &owner, &visibility.as_str(), &unix_timestamp()],
+            )
+            .await
+            .context("Error inserting new channel to database!")?;
+        Ok(())
+    }
+
+    async fn rename_channel(&self, name: &str, new_name: &str) -> Result<(), ChatError> {
+        let taken = self
+            .client
+            .query_opt("SELECT 1 FROM channels WHERE name = ($1)", &[&new_name])
+            .await?;
+        if taken.is_some() {
+            return Err(ChatError::NameUsed);
+        }
+        // messages, members and invitations follow by ON UPDATE CASCADE
+        let renamed = self
+            .client
+            .query_opt(
+                "WITH renamed AS (
+                    UPDATE channels SET name = ($2) WHERE name = ($1) RETURNING name
+                ), moved_history AS (
+                    UPDATE history SET channel_name = ($2)
+                    WHERE channel_name = ($1) AND EXISTS (SELECT 1 FROM renamed)
+                )
+                SELECT name FROM renamed",
+                &[&name, &new_name],
+            )
+            .await?;
+        renamed.map(|_| ()).ok_or(ChatError::NoSuchChannel)
+    }
+
+    async fn archive_channel(&self, name: &str, archived_at: i64) -> Result<(), ChatError> {
+        let archived = self
+            .client
+            .execute(
+                "UPDATE channels SET archived_at = ($2) WHERE name = ($1)",
+                &[&name, &archived_at],
+            )
+            .await?;
+        if archived == 0 {
+            return Err(ChatError::NoSuchChannel);
+        }
+        Ok(())
+    }
+
+    async fn set_channel_topic(&self, name: &str, topic: &ChannelTopic) -> Result<(), ChatError> {
+        let updated = self
+            .client
+            .execute(
+                "UPDATE channels SET topic = ($2), description = ($3) WHERE name = ($1)",
+                &[&name, &topic.topic, &topic.description],
+            )
+            .await?;
+        if updated == 0 {
+            return Err(ChatError::NoSuchChannel);
+        }
+        Ok(())
+    }
+
+    async fn delete_channel(&self, name: &str) -> Result<(), ChatError> {
+        // messages, members and invitations go by ON DELETE CASCADE
+        let deleted = self
+            .client
+            .query_opt(
+                "WITH deleted AS (
+                    DELETE FROM channels WHERE name = ($1) RETURNING name
+                ), deleted_history AS (
+                    DELETE FROM history WHERE channel_name = ($1)
+                )
+                SELECT name FROM deleted",
+                &[&name],
+            )
+            .await?;
+        deleted.map(|_| ()).ok_or(ChatError::NoSuchChannel)
+    }
+
+    async fn is_channel_member(
+        &self,
+        channel_name: &str,
+        user_name: &str,
+    ) -> Result<bool, ChatError> {
+        let row = self
+            .client
+            .query_opt(
+                "SELECT 1 FROM channel_members WHERE channel_name = ($1) AND user_name = ($2)",
+                &[&channel_name, &user_name],
+            )
+            .await?;
+        Ok(row.is_some())
+    }
+
+    async fn get_member_channels(&self, user_name: &str) -> Result<Vec<String>, ChatError> {
+        let rows = self
+            .client
+            .query(
+                "SELECT channel_name FROM channel_members WHERE user_name = ($1)",
+                &[&user_name],
+            )
+            .await?;
+        Ok(rows.into_iter().map(|row| row.get(0)).collect())
+    }
+
+    async fn invite_to_channel(
+        &self,
+        channel_name: &str,
+        user_name: &str,
+        invited_by: &str,
+    ) -> Result<ChannelInvitation, ChatError> {
+        if self.get_role(user_name).await?.is_none() {
+            return Err(ChatError::NoSuchUser);
+        }
+        if self.is_channel_member(channel_name, user_name).await? {
+            return Err(ChatError::AlreadyMember);
+        }
+        let row = self
+            .client
+            .query_one(
+                "INSERT INTO channel_invitations (channel_name, user_name, invited_by, created_at)
+                VALUES ($1, $2, $3, $4)
+                ON CONFLICT (channel_name, user_name) DO UPDATE
+                    SET invited_by = excluded.invited_by, created_at = excluded.created_at
+                RETURNING channel_name, invited_by, created_at",
+                &[&channel_name, &user_name, &invited_by, &unix_timestamp()],
+            )
+            .await?;
+        Ok(InvitationData::from(row).0)
+    }
+
+    async fn list_invitations(&self, user_name: &str) -> Result<Vec<ChannelInvitation>, ChatError> {
+        let rows = self
+            .client
+            .query(
+                "SELECT channel_name, invited_by, created_at FROM channel_invitations
+                WHERE user_name = ($1) ORDER BY created_at, channel_name",
+                &[&user_name],
+            )
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| InvitationData::from(row).0)
+            .collect())
+    }
+
+    async fn answer_invitation(
+        &self,
+        channel_name: &str,
+        user_name: &str,
+        accept: bool,
+    ) -> Result<(), ChatError> {
+        // single statement, so accepted invitation always turns into membership
+        let row = self
+            .client
+            .query_opt(
+                "WITH deleted AS (
+                    DELETE FROM channel_invitations WHERE channel_name = ($1) AND user_name = ($2)
+                    RETURNING channel_name, user_name
+                ), inserted AS (
+                    INSERT INTO channel_members (channel_name, user_name, joined_at)
+                    SELECT channel_name, user_name, ($4) FROM deleted WHERE ($3)
+                    ON CONFLICT DO NOTHING
+                )
+                SELECT 1 FROM deleted",
+                &[&channel_name, &user_name, &accept, &unix_timestamp()],
+            )
+            .await?;
+        if row.is_none() {
+            return Err(ChatError::NoSuchInvitation);
+        }
+        Ok(())
+    }
+
+    async fn add_sanction(&self, sanction: &Sanction) -> Result<(), ChatError> {
+        if self.get_role(&sanction.user_name).await?.is_none() {
+            return Err(ChatError::NoSuchUser);
+        }
+        self.client
+            .execute(
+                "INSERT INTO sanctions
+                    (kind, user_name, channel_name, issued_by, reason, created_at, expires_at)
+                VALUES ($1, $2, $3, $4, $5, $6, $7)",
+                &[
+                    &sanction.kind.as_str(),
+                    &sanction.user_name,
+                    &sanction.channel,
+                    &sanction.issued_by,
+                    &sanction.reason,
+                    &sanction.created_at,
+                    &sanction.expires_at,
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn get_active_sanction(
+        &self,
+        user_name: &str,
+        channel_name: Option<&str>,
+        kind: SanctionKind,
+    ) -> Result<Option<Sanction>, ChatError> {
+        let row = self
+            .client
+            .query_opt(
+                "SELECT kind, user_name, channel_name, issued_by, reason, created_at, expires_at
+                FROM sanctions
+                WHERE user_name = ($1) AND kind = ($3)
+                    AND (channel_name IS NULL OR channel_name = ($2))
+                    AND (expires_at IS NULL OR expires_at > ($4))
+                ORDER BY expires_at DESC NULLS FIRST
+                LIMIT 1",
+                &[&user_name, &channel_name, &kind.as_str(), &unix_timestamp()],
+            )
+            .await?;
+        row.map(|row| Ok(SanctionData::try_from(row)?.0))
+            .transpose()
+    }
+
+    async fn get_channels_activity(
+        &self,
+        user_name: &str,
+    ) -> Result<HashMap<String, ChannelActivity>, ChatError> {
+        let rows = self
+            .client
+            .query(
+                "SELECT channels.name,
+                    count(messages.id) FILTER (WHERE messages.id > coalesce(history.message_id, -1)
+                        AND messages.parent_id IS NULL AND messages.deleted_at IS NULL) AS unread_count,
+                    max(messages.created_at) AS last_activity,
+                    count(mentions.message_id) FILTER (WHERE mentions.read_at IS NULL
+                        AND messages.deleted_at IS NULL) AS mention_count
+                FROM channels
+                LEFT JOIN history ON history.channel_name = channels.name AND history.user_name = ($1)
+                LEFT JOIN messages ON messages.channel_name = channels.name
+                LEFT JOIN mentions ON mentions.message_id = messages.id AND mentions.user_name = ($1)
+                GROUP BY channels.name",
+                &[&user_name],
+            )
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| {
+                (
+                    row.get("name"),
+                    ChannelActivity {
+                        unread_count: row.get("unread_count"),
+                        last_activity: row.get("last_activity"),
+                        mention_count: row.get("mention_count"),
+                    },
+                )
+            })
+            .collect())
+    }
+
+    async fn create_user(&self, name: &str, password: &str) -> Result<()> {
+        let password_hash = password::hash_password(password).await?;
+        self.client
+            .execute(
+                "INSERT INTO users (name, password_hash) VALUES ($1, $2)",
+                &[&name, &password_hash],
+            )
+            .await
+            .context("Error inserting new user to database!")?;
+        Ok(())
+    }
+
+    async fn get_unseed_messages(
+        &self,
+        channel_name: &str,
+        user_name: &str,
+    ) -> Result<Vec<ChatMessage>, ChatError> {
+        let last_seen_message_id: Result<Row, tokio_postgres::Error> = self
+            .client
+            .query_one(
+                "SELECT message_id FROM history WHERE user_name = ($1) AND channel_name = ($2)",
+                &[&user_name, &channel_name],
+            )
+            .await;
+        let last_seen_message_id: i32 = last_seen_message_id.map_or(-1, |row| row.get(0));
+        let results = self
+            .client
+            .query(
+                "SELECT id, channel_name, user_name, created_at, coalesce(content, '') AS content,
+                    edited_at, deleted_at, parent_id,
+                    (SELECT count(*) FROM messages replies WHERE replies.parent_id = messages.id) AS reply_count
+                FROM messages
+                WHERE channel_name = ($1) AND id > ($2) AND deleted_at IS NULL AND parent_id IS NULL
+                ORDER BY id",
+                &[&channel_name, &last_seen_message_id],
+            )
+            .await?;
+        let mut messages: Vec<ChatMessage> = results
+            .into_iter()
+            .map(|row| MessageData::from(row).0)
+            .collect();
+        self.load_reactions(messages.iter_mut().collect()).await?;
+        Ok(messages)
+    }
+
+    async fn get_messages_before(
+        &self,
+        channel_name: &str,
+        parent_id: Option<MessageId>,
+        before_id: Option<MessageId>,
+        limit: u32,
+    ) -> Result<Vec<ChatMessage>, ChatError> {
+        let results = self
+            .client
+            .query(
+                "SELECT id, channel_name, user_name, created_at, coalesce(content, '') AS content,
+                    edited_at, deleted_at, parent_id,
+                    (SELECT count(*) FROM messages replies WHERE replies.parent_id = messages.id) AS reply_count
+                FROM messages
+                WHERE channel_name = ($1) AND parent_id IS NOT DISTINCT FROM ($2) AND id < ($3)
+                ORDER BY id DESC LIMIT ($4)",
+                &[
+                    &channel_name,
+                    &parent_id,
+                    &before_id.unwrap_or(MessageId::MAX),
+                    &i64::from(limit),
+                ],
+            )
+            .await?;
+        let mut messages: Vec<ChatMessage> = results
+            .into_iter()
+            .rev()
+            .map(|row| MessageData::from(row).0)
+            .collect();
+        self.load_reactions(messages.iter_mut().collect()).await?;
+        Ok(messages)
+    }
+
+    async fn search_messages(
+        &self,
+        query: &SearchQuery,
+        user_name: &str,
+    ) -> Result<Vec<SearchHit>, ChatError> {
+        let results = self
+            .client
+            .query(
+                "SELECT id, channel_name, user_name, created_at, coalesce(content, '') AS content,
+                    edited_at, deleted_at, parent_id,
+                    (SELECT count(*) FROM messages replies WHERE replies.parent_id = messages.id) AS reply_count,
+                    CASE WHEN ($1) = '' THEN 0
+                        ELSE ts_rank(search_vector, plainto_tsquery('simple', ($1))) END AS rank
+                FROM messages
+                WHERE (($1) = '' OR search_vector @@ plainto_tsquery('simple', ($1)))
+                    AND deleted_at IS NULL
+                    AND (($2)::TEXT IS NULL OR channel_name = ($2))
+                    AND (($3)::TEXT IS NULL OR user_name = ($3))
+                    AND (($4)::BIGINT IS NULL OR created_at >= ($4))
+                    AND (($5)::BIGINT IS NULL OR created_at < ($5))
+                    AND channel_name IN (
+                        SELECT name FROM channels WHERE visibility = 'public'
+                        UNION
+                        SELECT channel_name FROM channel_members WHERE user_name = ($8))
+                ORDER BY rank DESC, id DESC
+                OFFSET ($6) LIMIT ($7)",
+                &[
+                    &query.text,
+                    &query.channel,
+                    &query.author,
+                    &query.since,
+                    &query.until,
+                    &i64::from(query.offset),
+                    &i64::from(query.limit),
+                    &user_name,
+                ],
+            )
+            .await?;
+        let mut hits: Vec<SearchHit> = results
+            .into_iter()
+            .map(|row| SearchHit {
+                rank: f64::from(row.get::<_, f32>("rank")),
+                message: MessageData::from(row).0,
+            })
+            .collect();
+        self.load_reactions(hits.iter_mut().map(|hit| &mut hit.message).collect())
+            .await?;
+        Ok(hits)
+    }
+
+    async fn save_message(
+        &self,
+        channel_name: &str,
+        user_name: &str,
+        parent_id: Option<MessageId>,
+        message: &str,
+    ) -> Result<ChatMessage, ChatError> {
+        let row = self
+            .client
+            .query_one(
+                "INSERT INTO messages (channel_name, user_name, content, created_at, parent_id)
+                VALUES ($1, $2, $3, $4, $5)
+                RETURNING id, channel_name, user_name, created_at, content, edited_at, deleted_at,
+                    parent_id, 0::BIGINT AS reply_count",
+                &[
+                    &channel_name,
+                    &user_name,
+                    &message,
+                    &unix_timestamp(),
+                    &parent_id,
+                ],
+            )
+            .await?;
+        Ok(MessageData::from(row).0)
+    }
+
+    async fn get_message(
+        &self,
+        channel_name: &str,
+        message_id: MessageId,
+    ) -> Result<Option<ChatMessage>, ChatError> {
+        let row = self
+            .client
+            .query_opt(
+                "SELECT id, channel_name, user_name, created_at, coalesce(content, '') AS content,
+                    edited_at, deleted_at, parent_id,
+                    (SELECT count(*) FROM messages replies WHERE replies.parent_id = messages.id) AS reply_count
+                FROM messages WHERE channel_name = ($1) AND id = ($2)",
+                &[&channel_name, &message_id],
+            )
+            .await?;
+        let mut message = row.map(|row| MessageData::from(row).0);
+        self.load_reactions(message.iter_mut().collect()).await?;
+        Ok(message)
+    }
+
+    async fn edit_message(
+        &self,
+        channel_name: &str,
+        message_id: MessageId,
+        edited_by: &str,
+        content: &str,
+    ) -> Result<ChatMessage, ChatError> {
+        // single statement, so no edit is left without its revision
+        let row = self
+            .client
+            .query_opt(
+                "WITH previous AS (
+                    SELECT id, content FROM messages
+                    WHERE channel_name = ($1) AND id = ($2) AND deleted_at IS NULL
+                    FOR UPDATE
+                ), revision AS (
+                    INSERT INTO message_revisions (message_id, content, revised_by, revised_at)
+                    SELECT id, content, ($3), ($5) FROM previous
+                )
+                UPDATE messages SET content = ($4), edited_at = ($5)
+                FROM previous WHERE messages.id = previous.id
+                RETURNING messages.id, channel_name, user_name, created_at, messages.content,
+                    edited_at, deleted_at, parent_id,
+                    (SELECT count(*) FROM messages replies WHERE replies.parent_id = messages.id) AS reply_count",
+                &[
+                    &channel_name,
+                    &message_id,
+                    &edited_by,
+                    &content,
+                    &unix_timestamp(),
+                ],
+            )
+            .await?;
+        let mut message = row
+            .map(|row| MessageData::from(row).0)
+            .ok_or(ChatError::NoSuchMessage)?;
+        self.load_reactions(vec![&mut message]).await?;
+        Ok(message)
+    }
+
+    async fn delete_message(
+        &self,
+        channel_name: &str,
+        message_id: MessageId,
+        deleted_by: &str,
+    ) -> Result<(), ChatError> {
+        let row = self
+            .client
+            .query_opt(
+                "WITH previous AS (
+                    SELECT id, content FROM messages
+                    WHERE channel_name = ($1) AND id = ($2) AND deleted_at IS NULL
+                    FOR UPDATE
+                ), revision AS (
+                    INSERT INTO message_revisions (message_id, content, revised_by, revised_at)
+                    SELECT id, content, ($3), ($4) FROM previous
+                )
+                UPDATE messages SET content = NULL, deleted_at = ($4)
+                FROM previous WHERE messages.id = previous.id
+                RETURNING messages.id",
+                &[&channel_name, &message_id, &deleted_by, &unix_timestamp()],
+            )
+            .await?;
+        row.map(|_| ()).ok_or(ChatError::NoSuchMessage)
+    }
+
+    async fn add_reaction(
+        &self,
+        channel_name: &str,
+        message_id: MessageId,
+        user_name: &str,
+        reaction: &str,
+    ) -> Result<bool, ChatError> {
+        let exists = self
+            .client
+            .query_opt(
+                "SELECT 1 FROM messages WHERE channel_name = ($1) AND id = ($2) AND deleted_at IS NULL",
+                &[&channel_name, &message_id],
+            )
+            .await?;
+        if exists.is_none() {
+            return Err(ChatError::NoSuchMessage);
+        }
+        let added = self
+            .client
+            .execute(
+                "INSERT INTO reactions (message_id, user_name, reaction, created_at)
+                VALUES ($1, $2, $3, $4)
+                ON CONFLICT DO NOTHING",
+                &[&message_id, &user_name, &reaction, &unix_timestamp()],
+            )
+            .await?;
+        Ok(added > 0)
+    }
+
+    async fn remove_reaction(
+        &self,
+        channel_name: &str,
+        message_id: MessageId,
+        user_name: &str,
+        reaction: &str,
+    ) -> Result<bool, ChatError> {
+        let removed = self
+            .client
+            .execute(
+                "DELETE FROM reactions USING messages
+                WHERE reactions.message_id = messages.id AND messages.channel_name = ($1)
+                    AND reactions.message_id = ($2) AND reactions.user_name = ($3)
+                    AND reactions.reaction = ($4)",
+                &[&channel_name, &message_id, &user_name, &reaction],
+            )
+            .await?;
+        Ok(removed > 0)
+    }
+
+    async fn pin_message(
+        &self,
+        channel_name: &str,
+        message_id: MessageId,
+        pinned_by: &str,
+    ) -> Result<bool, ChatError> {
+        let exists = self
+            .client
+            .query_opt(
+                "SELECT 1 FROM messages WHERE channel_name = ($1) AND id = ($2) AND deleted_at IS NULL",
+                &[&channel_name, &message_id],
+            )
+            .await?;
+        if exists.is_none() {
+            return Err(ChatError::NoSuchMessage);
+        }
+        let pinned = self
+            .client
+            .execute(
+                "INSERT INTO pinned_messages (message_id, pinned_by, pinned_at) VALUES ($1, $2, $3)
+                ON CONFLICT DO NOTHING",
+                &[&message_id, &pinned_by, &unix_timestamp()],
+            )
+            .await?;
+        Ok(pinned > 0)
+    }
+
+    async fn unpin_message(
+        &self,
+        channel_name: &str,
+        message_id: MessageId,
+    ) -> Result<bool, ChatError> {
+        let unpinned = self
+            .client
+            .execute(
+                "DELETE FROM pinned_messages USING messages
+                WHERE pinned_messages.message_id = messages.id AND messages.channel_name = ($1)
+                    AND pinned_messages.message_id = ($2)",
+                &[&channel_name, &message_id],
+            )
+            .await?;
+        Ok(unpinned > 0)
+    }
+
+    async fn get_pinned_messages(
+        &self,
+        channel_name: &str,
+    ) -> Result<Vec<PinnedMessage>, ChatError> {
+        let rows = self
+            .client
+            .query(
+                "SELECT messages.id, messages.channel_name, messages.user_name, messages.created_at,
+                    coalesce(messages.content, '') AS content, messages.edited_at, messages.deleted_at,
+                    messages.parent_id,
+                    (SELECT count(*) FROM messages replies WHERE replies.parent_id = messages.id) AS reply_count,
+                    pinned_messages.pinned_by, pinned_messages.pinned_at
+                FROM pinned_messages
+                JOIN messages ON messages.id = pinned_messages.message_id
+                WHERE messages.channel_name = ($1) AND messages.deleted_at IS NULL
+                ORDER BY pinned_messages.pinned_at DESC, messages.id DESC",
+                &[&channel_name],
+            )
+            .await?;
+        let mut pins: Vec<PinnedMessage> = rows
+            .into_iter()
+            .map(|row| PinnedMessage {
+                pinned_by: row.get("pinned_by"),
+                pinned_at: row.get("pinned_at"),
+                message: MessageData::from(row).0,
+            })
+            .collect();
+        self.load_reactions(pins.iter_mut().map(|pin| &mut pin.message).collect())
+            .await?;
+        Ok(pins)
+    }
+
+    async fn get_message_revisions(
+        &self,
+        message_id: MessageId,
+    ) -> Result<Vec<MessageRevision>, ChatError> {
+        let rows = self
+            .client
+            .query(
+                "SELECT message_id, coalesce(content, '') AS content, revised_by, revised_at
+                FROM message_revisions WHERE message_id = ($1) ORDER BY id",
+                &[&message_id],
+            )
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| RevisionData::from(row).0)
+            .collect())
+    }
+
+    async fn list_threads(
+        &self,
+        channel_name: &str,
+        user_name: &str,
+    ) -> Result<Vec<ThreadInfo>, ChatError> {
+        let rows = self
+            .client
+            .query(
+                "SELECT messages.id, messages.channel_name, messages.user_name, messages.created_at,
+                    coalesce(messages.content, '') AS content, messages.edited_at, messages.deleted_at,
+                    messages.parent_id, count(replies.id) AS reply_count,
+                    count(replies.id) FILTER (WHERE replies.id > coalesce(thread_history.message_id, -1)
+                        AND replies.deleted_at IS NULL) AS unread_count,
+                    max(replies.created_at) AS last_activity
+                FROM messages
+                JOIN messages replies ON replies.parent_id = messages.id
+                LEFT JOIN thread_history ON thread_history.parent_id = messages.id
+                    AND thread_history.user_name = ($2)
+                WHERE messages.channel_name = ($1)
+                GROUP BY messages.id, thread_history.message_id
+                HAVING messages.user_name = ($2) OR bool_or(replies.user_name = ($2))
+                    OR thread_history.message_id IS NOT NULL
+                ORDER BY last_activity DESC, messages.id DESC",
+                &[&channel_name, &user_name],
+            )
+            .await?;
+        let mut threads: Vec<ThreadInfo> = rows
+            .into_iter()
+            .map(|row| ThreadInfo {
+                unread_count: row.get("unread_count"),
+                last_activity: row.get("last_activity"),
+                parent: MessageData::from(row).0,
+            })
+            .collect();
+        self.load_reactions(
+            threads
+                .iter_mut()
+                .map(|thread| &mut thread.parent)
+                .collect(),
+        )
+        .await?;
+        Ok(threads)
+    }
+
+    async fn mark_thread_read(
+        &self,
+        channel_name: &str,
+        user_name: &str,
+        parent_id: MessageId,
+        message_id: MessageId,
+    ) -> Result<(), ChatError> {
+        // single statement, so mentions are read together with the thread
+        let marked = self
+            .client
+            .query_opt(
+                "WITH marked AS (
+                    INSERT INTO thread_history (user_name, parent_id, message_id)
+                    SELECT ($1), parent_id, id FROM messages
+                    WHERE id = ($4) AND parent_id = ($3) AND channel_name = ($2)
+                    ON CONFLICT (user_name, parent_id) DO UPDATE
+                        SET message_id = GREATEST(thread_history.message_id, excluded.message_id)
+                    RETURNING 1
+                ), read_mentions AS (
+                    UPDATE mentions SET read_at = ($5)
+                    FROM messages
+                    WHERE mentions.message_id = messages.id AND mentions.user_name = ($1)
+                        AND mentions.read_at IS NULL AND messages.parent_id = ($3)
+                        AND messages.id <= ($4) AND EXISTS (SELECT 1 FROM marked)
+                )
+                SELECT 1 FROM marked",
+                &[
+                    &user_name,
+                    &channel_name,
+                    &parent_id,
+                    &message_id,
+                    &unix_timestamp(),
+                ],
+            )
+            .await?;
+        if marked.is_none() {
+            return Err(ChatError::NoSuchMessage);
+        }
+        Ok(())
+    }
+
+    async fn save_mentions(
+        &self,
+        message_id: MessageId,
+        user_names: &[String],
+        whole_channel: bool,
+    ) -> Result<Vec<String>, ChatError> {
+        let rows = self
+            .client
+            .query(
+                "WITH message AS (
+                    SELECT messages.id, messages.channel_name, messages.user_name, channels.visibility
+                    FROM messages JOIN channels ON channels.name = messages.channel_name
+                    WHERE messages.id = ($1)
+                ), named AS (
+                    SELECT unnest($2::TEXT[]) AS user_name
+                    UNION
+                    SELECT history.user_name FROM history, message
+                    WHERE ($3) AND history.channel_name = message.channel_name
+                    UNION
+                    SELECT channel_members.user_name FROM channel_members, message
+                    WHERE ($3) AND channel_members.channel_name = message.channel_name
+                )
+                INSERT INTO mentions (message_id, user_name)
+                SELECT message.id, users.name
+                FROM message, users
+                WHERE users.name IN (SELECT user_name FROM named)
+                    AND users.name <> message.user_name
+                    AND (message.visibility = 'public' OR EXISTS (
+                        SELECT 1 FROM channel_members
+                        WHERE channel_name = message.channel_name AND user_name = users.name))
+                ON CONFLICT DO NOTHING
+                RETURNING user_name",
+                &[&message_id, &user_names, &whole_channel],
+            )
+            .await?;
+        Ok(rows.into_iter().map(|row| row.get(0)).collect())
+    }
+
+    async fn list_mentions(
+        &self,
+        user_name: &str,
+        unread_only: bool,
+        before_id: Option<MessageId>,
+        limit: u32,
+    ) -> Result<Vec<Mention>, ChatError> {
+        let rows = self
+            .client
+            .query(
+                "SELECT messages.id, messages.channel_name, messages.user_name, messages.created_at,
+                    coalesce(messages.content, '') AS content, messages.edited_at, messages.deleted_at,
+                    messages.parent_id,
+                    (SELECT count(*) FROM messages replies WHERE replies.parent_id = messages.id) AS reply_count,
+                    mentions.read_at IS NOT NULL AS read
+                FROM mentions
+                JOIN messages ON messages.id = mentions.message_id
+                JOIN channels ON channels.name = messages.channel_name
+                WHERE mentions.user_name = ($1) AND (NOT ($2) OR mentions.read_at IS NULL)
+                    AND mentions.message_id < ($3) AND messages.deleted_at IS NULL
+                    AND (channels.visibility = 'public' OR EXISTS (
+                        SELECT 1 FROM channel_members
+                        WHERE channel_name = channels.name AND user_name = ($1)))
+                ORDER BY mentions.message_id DESC LIMIT ($4)",
+                &[
+                    &user_name,
+                    &unread_only,
+                    &before_id.unwrap_or(MessageId::MAX),
+                    &i64::from(limit),
+                ],
+            )
+            .await?;
+        let mut mentions: Vec<Mention> = rows
+            .into_iter()
+            .rev()
+            .map(|row| Mention {
+                read: row.get("read"),
+                message: MessageData::from(row).0,
+            })
+            .collect();
+        self.load_reactions(
+            mentions
+                .iter_mut()
+                .map(|mention| &mut mention.message)
+                .collect(),
+        )
+        .await?;
+        Ok(mentions)
+    }
+
+    async fn mark_mentions_read(
+        &self,
+        user_name: &str,
+        message_id: MessageId,
+    ) -> Result<(), ChatError> {
+        self.client
+            .execute(
+                "UPDATE mentions SET read_at = ($3)
+                WHERE user_name = ($1) AND message_id <= ($2) AND read_at IS NULL",
+                &[&user_name, &message_id, &unix_timestamp()],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn open_conversation(&self, members: &[String]) -> Result<ConversationId, ChatError> {
+        let members = members.to_vec();
+        let existing: i64 = self
+            .client
+            .query_one(
+                "SELECT count(*) FROM users WHERE name = ANY($1)",
+                &[&members],
+            )
+            .await?
+            .get(0);
+        if existing != members.len() as i64 {
+            return Err(ChatError::NoSuchUser);
+        }
+        // single statement, so conversation is never left without members.
+        // The no-op update returns the row even when a concurrent open inserted it first,
+        // xmax is 0 only for a row this statement inserted.
+        let row = self
+            .client
+            .query_one(
+                "WITH upserted AS (
+                    INSERT INTO conversations (member_key, created_at) VALUES ($1, $2)
+                    ON CONFLICT (member_key) DO UPDATE SET member_key = excluded.member_key
+                    RETURNING id, xmax = 0 AS created
+                ), inserted_members AS (
+                    INSERT INTO conversation_members (conversation_id, user_name)
+                    SELECT upserted.id, member FROM upserted, unnest($3::TEXT[]) AS member
+                    WHERE upserted.created
+                )
+                SELECT id FROM upserted",
+                &[&direct::member_key(&members), &unix_timestamp(), &members],
+            )
+            .await?;
+        Ok(row.get(0))
+    }
+
+    async fn get_conversation_members(
+        &self,
+        conversation_id: ConversationId,
+    ) -> Result<Option<Vec<String>>, ChatError> {
+        let row = self
+            .client
+            .query_opt(
+                "SELECT member_key FROM conversations WHERE id = ($1)",
+                &[&conversation_id],
+            )
+            .await?;
+        Ok(row.map(|row| direct::members_from_key(row.get(0))))
+    }
+
+    async fn list_conversations(
+        &self,
+        user_name: &str,
+    ) -> Result<Vec<ConversationInfo>, ChatError> {
+        let rows = self
+            .client
+            .query(
+                "SELECT conversations.id, conversations.member_key,
+                    count(direct_messages.id) FILTER (WHERE direct_messages.id > me.last_read_id)
+                        AS unread_count,
+                    max(direct_messages.created_at) AS last_activity
+                FROM conversation_members me
+                JOIN conversations ON conversations.id = me.conversation_id
+                LEFT JOIN direct_messages ON direct_messages.conversation_id = conversations.id
+                WHERE me.user_name = ($1)
+                GROUP BY conversations.id, me.last_read_id
+                ORDER BY last_activity DESC NULLS LAST, conversations.id DESC",
+                &[&user_name],
+            )
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| ConversationInfo {
+                id: row.get("id"),
+                members: direct::members_from_key(row.get("member_key")),
+                unread_count: row.get("unread_count"),
+                last_activity: row.get("last_activity"),
+            })
+            .collect())
+    }
+
+    async fn save_direct_message(
+        &self,
+        conversation_id: ConversationId,
+        user_name: &str,
+        message: &str,
+    ) -> Result<DirectMessage, ChatError> {
+        let row = self
+            .client
+            .query_one(
+                "INSERT INTO direct_messages (conversation_id, user_name, created_at, content)
+                VALUES ($1, $2, $3, $4)
+                RETURNING id, conversation_id, user_name, created_at, content",
+                &[&conversation_id, &user_name, &unix_timestamp(), &message],
+            )
+            .await?;
+        Ok(DirectMessageData::from(row).0)
+    }
+
+    async fn get_unseen_direct_messages(
+        &self,
+        user_name: &str,
+    ) -> Result<Vec<DirectMessage>, ChatError> {
+        let rows = self
+            .client
+            .query(
+                "SELECT direct_messages.id, direct_messages.conversation_id, direct_messages.user_name,
+                    direct_messages.created_at, direct_messages.content
+                FROM direct_messages
+                JOIN conversation_members me ON me.conversation_id = direct_messages.conversation_id
+                WHERE me.user_name = ($1) AND direct_messages.id > me.last_read_id
+                ORDER BY direct_messages.id",
+                &[&user_name],
+            )
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| DirectMessageData::from(row).0)
+            .collect())
+    }
+
+    async fn mark_conversation_read(
+        &self,
+        conversation_id: ConversationId,
+        user_name: &str,
+        message_id: i32,
+    ) -> Result<(), ChatError> {
+        let marked = self
+            .client
+            .execute(
+                "UPDATE conversation_members SET last_read_id = GREATEST(last_read_id, ($3))
+                WHERE conversation_id = ($1) AND user_name = ($2) AND EXISTS (
+                    SELECT 1 FROM direct_messages WHERE id = ($3) AND conversation_id = ($1))",
+                &[&conversation_id, &user_name, &message_id],
+            )
+            .await?;
+        if marked == 0 {
+            return Err(ChatError::NoSuchMessage);
+        }
+        Ok(())
+    }
+
+    async fn mark_read(
+        &self,
+        channel_name: &str,
+        user_name: &str,
+        message_id: MessageId,
+    ) -> Result<(), ChatError> {
+        let exists = self
+            .client
+            .query_opt(
+                "SELECT 1 FROM messages WHERE id = ($1) AND channel_name = ($2)",
+                &[&message_id, &channel_name],
+            )
+            .await?;
+        if exists.is_none() {
+            return Err(ChatError::NoSuchMessage);
+        }
+        // single statement, so mentions are read together with the channel
+        self.client
+            .execute(
+                "WITH marked AS (
+                    INSERT INTO history (user_name, channel_name, message_id) VALUES ($1, $2, $3)
+                    ON CONFLICT (user_name, channel_name) DO UPDATE
+                        SET message_id = GREATEST(history.message_id, excluded.message_id)
+                )
+                UPDATE mentions SET read_at = ($4)
+                FROM messages
+                WHERE mentions.message_id = messages.id AND mentions.user_name = ($1)
+                    AND mentions.read_at IS NULL AND messages.channel_name = ($2)
+                    AND messages.parent_id IS NULL AND messages.id <= ($3)",
+                &[&user_name, &channel_name, &message_id, &unix_timestamp()],
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct ChannelData(StoredChannel);
+
+impl TryFrom<Row> for ChannelData {
+    type Error = ChatError;
+
+    fn try_from(row: Row) -> Result<Self, Self::Error> {
+        Ok(Self(StoredChannel {
+            name: row.get("name"),
+            owner: row.get("owner"),
+            visibility: row.get::<_, String>("visibility").parse()?,
+            topic: ChannelTopic {
+                topic: row.get("topic"),
+                description: row.get("description"),
+            },
+            archived_at: row.get("archived_at"),
+        }))
+    }
+}
+
+#[derive(Debug)]
+struct InvitationData(ChannelInvitation);
+
+impl From<Row> for InvitationData {
+    fn from(row: Row) -> Self {
+        Self(ChannelInvitation {
+            channel: row.get("channel_name"),
+            invited_by: row.get("invited_by"),
+            created_at: row.get("created_at"),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct SanctionData(Sanction);
+
+impl TryFrom<Row> for SanctionData {
+    type Error = ChatError;
+
+    fn try_from(row: Row) -> Result<Self, Self::Error> {
+        Ok(Self(Sanction {
+            kind: row.get::<_, String>("kind").parse()?,
+            user_name: row.get("user_name"),
+            channel: row.get("channel_name"),
+            issued_by: row.get("issued_by"),
+            reason: row.get("reason"),
+            created_at: row.get("created_at"),
+            expires_at: row.get("expires_at"),
+        }))
+    }
+}
+
+#[derive(Debug)]
+struct SessionData(Session);
+
+impl From<Row> for SessionData {
+    fn from(row: Row) -> Self {
+        Self(Session {
+            id: row.get("id"),
+            user_name: row.get("user_name"),
+            cookie_hash: row.get("cookie_hash"),
+            issued_at: row.get("issued_at"),
+            last_used_at: row.get("last_used_at"),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct MessageData(ChatMessage);
+
+impl From<Row> for MessageData {
+    fn from(row: Row) -> Self {
+        Self(ChatMessage {
+            id: row.get("id"),
+            channel: row.get("channel_name"),
+            author: row.get("user_name"),
+            created_at: row.get("created_at"),
+            body: row.get("content"),
+            edited_at: row.get("edited_at"),
+            deleted_at: row.get("deleted_at"),
+            parent_id: row.get("parent_id"),
+            reply_count: row.get("reply_count"),
+            reactions: Vec::new(),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct RevisionData(MessageRevision);
+
+impl From<Row> for RevisionData {
+    fn from(row: Row) -> Self {
+        Self(MessageRevision {
+            message_id: row.get("message_id"),
+            body: row.get("content"),
+            revised_by: row.get("revised_by"),
+            revised_at: row.get("revised_at"),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct DirectMessageData(DirectMessage);
+
+impl From<Row> for DirectMessageData {
+    fn from(row: Row) -> Self {
+        Self(DirectMessage {
+            id: row.get("id"),
+            conversation_id: row.get("conversation_id"),
+            author: row.get("user_name"),
+            created_at: row.get("created_at"),
+            body: row.get("content"),
+        })
+    }
+}
@@ -0,0 +1,1469 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use rusqlite::{types::Type, Connection, OptionalExtension, Row, Transaction};
+
+use super::{AuthenticationToken, ChatStore};
+use crate::channel::{ChannelActivity, ChannelInvitation, ChannelTopic, StoredChannel, Visibility};
+use crate::direct::{self, ConversationId, ConversationInfo, DirectMessage};
+use crate::mentions::Mention;
+use crate::messages::{
+    ChatMessage, MessageId, MessageRevision, PinnedMessage, ReactionCount, ThreadInfo,
+};
+use crate::migrations::{self, DEFAULT_ADMIN_PASSWORD};
+use crate::moderation::{Sanction, SanctionKind};
+use crate::password::{self, StoredPassword};
+use crate::permissions::Role;
+use crate::search::{self, SearchHit, SearchQuery};
+use crate::session::{Session, SessionInfo};
+use crate::utils::{unix_timestamp, ChatError};
+use anyhow::{Context, Result};
+
+// Store backed by a single SQLite file, for deployments without a database server.
+// rusqlite is blocking, so every query runs on tokio's blocking thread pool.
+#[derive(Debug)]
+pub struct SqliteDatabase {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SqliteDatabase {
+    // Opens (or creates) database file, migrates it and seeds default content
+    pub async fn open<P: AsRef<Path>>(path: P) -> Result<SqliteDatabase> {
+        let admin_password_hash = password::hash_password(DEFAULT_ADMIN_PASSWORD).await?;
+        let mut connection = Connection::open(path).context("Error opening sqlite database!")?;
+        connection.pragma_update(None, "foreign_keys", "ON")?;
+        migrations::migrate_sqlite(&mut connection).context("Error migrating database schema!")?;
+        migrations::seed_sqlite(&connection, &admin_password_hash)?;
+        Ok(SqliteDatabase {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    async fn call<T, F>(&self, query: F) -> Result<T, ChatError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let connection = Arc::clone(&self.connection);
+        tokio::task::spawn_blocking(move || query(&mut connection.lock().unwrap()))
+            .await
+            .map_err(|_| ChatError::RuntimeError)?
+            .map_err(ChatError::from)
+    }
+}
+
+#[async_trait]
+impl ChatStore for SqliteDatabase {
+    async fn get_password(&self, name: &str) -> Result<Option<StoredPassword>, ChatError> {
+        let name = name.to_string();
+        self.call(move |connection| {
+            connection
+                .query_row(
+                    "SELECT password_hash, password FROM users WHERE name = ?1",
+                    [name],
+                    |row| {
+                        Ok(StoredPassword {
+                            hash: row.get(0)?,
+                            legacy: row.get(1)?,
+                        })
+                    },
+                )
+                .optional()
+        })
+        .await
+    }
+
+    async fn set_password_hash(&self, name: &str, hash: &str) -> Result<(), ChatError> {
+        let params = (name.to_string(), hash.to_string());
+        self.call(move |connection| {
+            connection.execute(
+                "UPDATE users SET password_hash = ?2, password = NULL WHERE name = ?1",
+                params,
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn create_session(&self, user_name: &str) -> Result<AuthenticationToken, ChatError> {
+        let (session, token) = Session::new(user_name);
+        self.call(move |connection| {
+            connection.execute(
+                "INSERT INTO sessions (id, user_name, cookie_hash, issued_at, last_used_at, expires_at)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                (
+                    &session.id,
+                    &session.user_name,
+                    &session.cookie_hash,
+                    session.issued_at,
+                    session.last_used_at,
+                    session.expires_at(),
+                ),
+            )
+        })
+        .await?;
+        Ok(token)
+    }
+
+    async fn authorize_connection(&self, token: &AuthenticationToken) -> Result<(), ChatError> {
+        let token = token.clone();
+        self.call(move |connection| {
+            let session = connection
+                .query_row(
+                    "SELECT id, user_name, cookie_hash, issued_at, last_used_at FROM sessions WHERE id = ?1",
+                    [&token.session_id],
+                    session_from_row,
+                )
+                .optional()?;
+            let mut session = match session {
+                Some(session) => session,
+                None => return Ok(Err(ChatError::UnauthenticatedConnection)),
+            };
+
+            let now = unix_timestamp();
+            if let Err(e) = session.check(&token, now) {
+                if let ChatError::SessionExpired = e {
+                    connection.execute("DELETE FROM sessions WHERE id = ?1", [&session.id])?;
+                }
+                return Ok(Err(e));
+            }
+
+            session.last_used_at = now;
+            connection.execute(
+                "UPDATE sessions SET last_used_at = ?2, expires_at = ?3 WHERE id = ?1",
+                (&session.id, session.last_used_at, session.expires_at()),
+            )?;
+            Ok(Ok(()))
+        })
+        .await?
+    }
+
+    async fn list_sessions(&self, user_name: &str) -> Result<Vec<SessionInfo>, ChatError> {
+        let user_name = user_name.to_string();
+        self.call(move |connection| {
+            connection.execute(
+                "DELETE FROM sessions WHERE expires_at <= ?1",
+                [unix_timestamp()],
+            )?;
+            let mut statement = connection.prepare(
+                "SELECT id, user_name, cookie_hash, issued_at, last_used_at FROM sessions
+                WHERE user_name = ?1 ORDER BY issued_at",
+            )?;
+            let sessions = statement.query_map([&user_name], |row| {
+                session_from_row(row).map(|session| session.info())
+            })?;
+            sessions.collect()
+        })
+        .await
+    }
+
+    async fn revoke_session(&self, user_name: &str, session_id: &str) -> Result<bool, ChatError> {
+        let params = (session_id.to_string(), user_name.to_string());
+        let deleted = self
+            .call(move |connection| {
+                connection.execute(
+                    "DELETE FROM sessions WHERE id = ?1 AND user_name = ?2",
+                    params,
+                )
+            })
+            .await?;
+        Ok(deleted > 0)
+    }
+
+    async fn get_role(&self, user_name: &str) -> Result<Option<Role>, ChatError> {
+        let user_name = user_name.to_string();
+        let role: Option<String> = self
+            .call(move |connection| {
+                connection
+                    .query_row(
+                        "SELECT role FROM users WHERE name = ?1",
+                        [user_name],
+                        |row| row.get(0),
+                    )
+                    .optional()
+            })
+            .await?;
+        role.map(|role| role.parse()).transpose()
+    }
+
+    async fn set_role(&self, user_name: &str, role: Role) -> Result<bool, ChatError> {
+        let params = (user_name.to_string(), role.as_str());
+        let updated = self
+            .call(move |connection| {
+                connection.execute("UPDATE users SET role = ?2 WHERE name = ?1", params)
+            })
+            .await?;
+        Ok(updated > 0)
+    }
+
+    async fn get_channels(&self) -> Result<Vec<StoredChannel>> {
+        let channels = self
+            .call(|connection| {
+                let mut statement = connection.prepare(
+                    "SELECT name, owner, visibility, topic, description, archived_at FROM channels",
+                )?;
+                let channels = statement.query_map([], channel_from_row)?;
+                channels.collect()
+            })
+            .await
+            .context("Error selecting channels from database!")?;
+        Ok(channels)
+    }
+
+    async fn create_channel(&self, name: &str, owner: &str, visibility: Visibility) -> Result<()> {
+        let name = name.to_string();
+        let owner = owner.to_string();
+        self.call(move |connection| {
+            let transaction = connection.transaction()?;
+            transaction.execute(
+                "INSERT INTO channels (name, owner, visibility) VALUES (?1, ?2, ?3)",
+                (&name, &owner, visibility.as_str()),
+            )?;
+            if visibility == Visibility::Private {
+                transaction.execute(
+                    "INSERT INTO channel_members (channel_name, user_name, joined_at)
+                    VALUES (?1, ?2, ?3)",
+                    (&name, &owner, unix_timestamp()),
+                )?;
+            }
+            transaction.commit()
+        })
+        .await
+        .context("Error inserting new channel to database!")?;
+        Ok(())
+    }
+
+    async fn rename_channel(&self, name: &str, new_name: &str) -> Result<(), ChatError> {
+        let params = (name.to_string(), new_name.to_string());
+        self.call(move |connection| {
+            let transaction = connection.transaction()?;
+            let taken = transaction
+                .query_row(
+                    "SELECT 1 FROM channels WHERE name = ?1",
+                    [&params.1],
+                    |_| Ok(()),
+                )
+                .optional()?;
+            if taken.is_some() {
+                return Ok(Err(ChatError::NameUsed));
+            }
+            // rows referencing the channel are moved one by one, so they are checked only at commit
+            transaction.pragma_update(None, "defer_foreign_keys", "ON")?;
+            let renamed = transaction.execute(
+                "UPDATE channels SET name = ?2 WHERE name = ?1",
+                (&params.0, &params.1),
+            )?;
+            if renamed == 0 {
+                return Ok(Err(ChatError::NoSuchChannel));
+            }
+            for table in [
+                "messages",
+                "history",
+                "channel_members",
+                "channel_invitations",
+                "sanctions",
+            ] {
+                transaction.execute(
+                    &format!(
+                        "UPDATE {} SET channel_name = ?2 WHERE channel_name = ?1",
+                        table
+                    ),
+                    (&params.0, &params.1),
+                )?;
+            }
+            transaction.commit()?;
+            Ok(Ok(()))
+        })
+        .await?
+    }
+
+    async fn archive_channel(&self, name: &str, archived_at: i64) -> Result<(), ChatError> {
+        let params = (name.to_string(), archived_at);
+        let archived = self
+            .call(move |connection| {
+                connection.execute(
+                    "UPDATE channels SET archived_at = ?2 WHERE name = ?1",
+                    params,
+                )
+            })
+            .await?;
+        if archived == 0 {
+            return Err(ChatError::NoSuchChannel);
+        }
+        Ok(())
+    }
+
+    async fn set_channel_topic(&self, name: &str, topic: &ChannelTopic) -> Result<(), ChatError> {
+        let params = (
+            name.to_string(),
+            topic.topic.clone(),
+            topic.description.clone(),
+        );
+        let updated = self
+            .call(move |connection| {
+                connection.execute(
+                    "UPDATE channels SET topic = ?2, description = ?3 WHERE name = ?1",
+                    params,
+                )
+            })
+            .await?;
+        if updated == 0 {
+            return Err(ChatError::NoSuchChannel);
+        }
+        Ok(())
+    }
+
+    async fn delete_channel(&self, name: &str) -> Result<(), ChatError> {
+        let name = name.to_string();
+        self.call(move |connection| {
+            let transaction = connection.transaction()?;
+            // members, invitations and sanctions go by ON DELETE CASCADE
+            transaction.execute("DELETE FROM history WHERE channel_name = ?1", [&name])?;
+            transaction.execute("DELETE FROM messages WHERE channel_name = ?1", [&name])?;
+            let deleted = transaction.execute("DELETE FROM channels WHERE name = ?1", [&name])?;
+            if deleted == 0 {
+                return Ok(Err(ChatError::NoSuchChannel));
+            }
+            transaction.commit()?;
+            Ok(Ok(()))
+        })
+        .await?
+    }
+
+    async fn is_channel_member(
+        &self,
+        channel_name: &str,
+        user_name: &str,
+    ) -> Result<bool, ChatError> {
+        let params = (channel_name.to_string(), user_name.to_string());
+        let member = self
+            .call(move |connection| {
+                connection
+                    .query_row(
+                        "SELECT 1 FROM channel_members WHERE channel_name = ?1 AND user_name = ?2",
+                        params,
+                        |_| Ok(()),
+                    )
+                    .optional()
+            })
+            .await?;
+        Ok(member.is_some())
+    }
+
+    async fn get_member_channels(&self, user_name: &str) -> Result<Vec<String>, ChatError> {
+        let user_name = user_name.to_string();
+        self.call(move |connection| {
+            let mut statement = connection
+                .prepare("SELECT channel_name FROM channel_members WHERE user_name = ?1")?;
+            let names = statement.query_map([&user_name], |row| row.get(0))?;
+            names.collect()
+        })
+        .await
+    }
+
+    async fn invite_to_channel(
+        &self,
+        channel_name: &str,
+        user_name: &str,
+        invited_by: &str,
+    ) -> Result<ChannelInvitation, ChatError> {
+        let params = (
+            channel_name.to_string(),
+            user_name.to_string(),
+            invited_by.to_string(),
+            unix_timestamp(),
+        );
+        self.call(move |connection| {
+            let transaction = connection.transaction()?;
+            let user_exists = transaction
+                .query_row("SELECT 1 FROM users WHERE name = ?1", [&params.1], |_| {
+                    Ok(())
+                })
+                .optional()?;
+            if user_exists.is_none() {
+                return Ok(Err(ChatError::NoSuchUser));
+            }
+            let member = transaction
+                .query_row(
+                    "SELECT 1 FROM channel_members WHERE channel_name = ?1 AND user_name = ?2",
+                    (&params.0, &params.1),
+                    |_| Ok(()),
+                )
+                .optional()?;
+            if member.is_some() {
+                return Ok(Err(ChatError::AlreadyMember));
+            }
+            let invitation = transaction.query_row(
+                "INSERT INTO channel_invitations (channel_name, user_name, invited_by, created_at)
+                VALUES (?1, ?2, ?3, ?4)
+                ON CONFLICT (channel_name, user_name) DO UPDATE
+                    SET invited_by = excluded.invited_by, created_at = excluded.created_at
+                RETURNING channel_name, invited_by, created_at",
+                (&params.0, &params.1, &params.2, params.3),
+                invitation_from_row,
+            )?;
+            transaction.commit()?;
+            Ok(Ok(invitation))
+        })
+        .await?
+    }
+
+    async fn list_invitations(&self, user_name: &str) -> Result<Vec<ChannelInvitation>, ChatError> {
+        let user_name = user_name.to_string();
+        self.call(move |connection| {
+            let mut statement = connection.prepare(
+                "SELECT channel_name, invited_by, created_at FROM channel_invitations
+                WHERE user_name = ?1 ORDER BY created_at, channel_name",
+            )?;
+            let invitations = statement.query_map([&user_name], invitation_from_row)?;
+            invitations.collect()
+        })
+        .await
+    }
+
+    async fn answer_invitation(
+        &self,
+        channel_name: &str,
+        user_name: &str,
+        accept: bool,
+    ) -> Result<(), ChatError> {
+        let params = (channel_name.to_string(), user_name.to_string());
+        self.call(move |connection| {
+            let transaction = connection.transaction()?;
+            let deleted = transaction.execute(
+                "DELETE FROM channel_invitations WHERE channel_name = ?1 AND user_name = ?2",
+                (&params.0, &params.1),
+            )?;
+            if deleted == 0 {
+                return Ok(Err(ChatError::NoSuchInvitation));
+            }
+            if accept {
+                transaction.execute(
+                    "INSERT INTO channel_members (channel_name, user_name, joined_at)
+                    VALUES (?1, ?2, ?3) ON CONFLICT DO NOTHING",
+                    (&params.0, &params.1, unix_timestamp()),
+                )?;
+            }
+            transaction.commit()?;
+            Ok(Ok(()))
+        })
+        .await?
+    }
+
+    async fn add_sanction(&self, sanction: &Sanction) -> Result<(), ChatError> {
+        let sanction = sanction.clone();
+        self.call(move |connection| {
+            let transaction = connection.transaction()?;
+            let user_exists = transaction
+                .query_row(
+                    "SELECT 1 FROM users WHERE name = ?1",
+                    [&sanction.user_name],
+                    |_| Ok(()),
+                )
+                .optional()?;
+            if user_exists.is_none() {
+                return Ok(Err(ChatError::NoSuchUser));
+            }
+            transaction.execute(
+                "INSERT INTO sanctions
+                    (kind, user_name, channel_name, issued_by, reason, created_at, expires_at)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                (
+                    sanction.kind.as_str(),
+                    &sanction.user_name,
+                    &sanction.channel,
+                    &sanction.issued_by,
+                    &sanction.reason,
+                    sanction.created_at,
+                    sanction.expires_at,
+                ),
+            )?;
+            transaction.commit()?;
+            Ok(Ok(()))
+        })
+        .await?
+    }
+
+    async fn get_active_sanction(
+        &self,
+        user_name: &str,
+        channel_name: Option<&str>,
+        kind: SanctionKind,
+    ) -> Result<Option<Sanction>, ChatError> {
+        let params = (
+            user_name.to_string(),
+            channel_name.map(str::to_string),
+            kind.as_str(),
+            unix_timestamp(),
+        );
+        self.call(move |connection| {
+            connection
+                .query_row(
+                    "SELECT kind, user_name, channel_name, issued_by, reason, created_at, expires_at
+                    FROM sanctions
+                    WHERE user_name = ?1 AND kind = ?3
+                        AND (channel_name IS NULL OR channel_name = ?2)
+                        AND (expires_at IS NULL OR expires_at > ?4)
+                    ORDER BY expires_at DESC NULLS FIRST
+                    LIMIT 1",
+                    params,
+                    sanction_from_row,
+                )
+                .optional()
+        })
+        .await
+    }
+
+    async fn get_channels_activity(
+        &self,
+        user_name: &str,
+    ) -> Result<HashMap<String, ChannelActivity>, ChatError> {
+        let user_name = user_name.to_string();
+        self.call(move |connection| {
+            let mut statement = connection.prepare(
+                "SELECT channels.name,
+                    count(messages.id) FILTER (WHERE messages.id > coalesce(history.message_id, -1)
+                        AND messages.parent_id IS NULL AND messages.deleted_at IS NULL) AS unread_count,
+                    max(messages.created_at) AS last_activity,
+                    count(mentions.message_id) FILTER (WHERE mentions.read_at IS NULL
+                        AND messages.deleted_at IS NULL) AS mention_count
+                FROM channels
+                LEFT JOIN history ON history.channel_name = channels.name AND history.user_name = ?1
+                LEFT JOIN messages ON messages.channel_name = channels.name
+                LEFT JOIN mentions ON mentions.message_id = messages.id AND mentions.user_name = ?1
+                GROUP BY channels.name",
+            )?;
+            let activities = statement.query_map([&user_name], |row| {
+                Ok((
+                    row.get("name")?,
+                    ChannelActivity {
+                        unread_count: row.get("unread_count")?,
+                        last_activity: row.get("last_activity")?,
+                        mention_count: row.get("mention_count")?,
+                    },
+                ))
+            })?;
+            activities.collect()
+        })
+        .await
+    }
+
+    async fn create_user(&self, name: &str, password: &str) -> Result<()> {
+        let password_hash = password::hash_password(password).await?;
+        let name = name.to_string();
+        self.call(move |connection| {
+            connection.execute(
+                "INSERT INTO users (name, password_hash) VALUES (?1, ?2)",
+                (name, password_hash),
+            )
+        })
+        .await
+        .context("Error inserting new user to database!")?;
+        Ok(())
+    }
+
+    async fn get_unseed_messages(
+        &self,
+        channel_name: &str,
+        user_name: &str,
+    ) -> Result<Vec<ChatMessage>, ChatError> {
+        let channel_name = channel_name.to_string();
+        let user_name = user_name.to_string();
+        self.call(move |connection| {
+            let last_seen_message_id: i32 = connection
+                .query_row(
+                    "SELECT message_id FROM history WHERE user_name = ?1 AND channel_name = ?2",
+                    (&user_name, &channel_name),
+                    |row| row.get(0),
+                )
+                .optional()?
+                .unwrap_or(-1);
+            let mut statement = connection.prepare(
+                "SELECT id, channel_name, user_name, created_at, coalesce(content, '') AS content,
+                    edited_at, deleted_at, parent_id,
+                    (SELECT count(*) FROM messages replies WHERE replies.parent_id = messages.id) AS reply_count
+                FROM messages
+                WHERE channel_name = ?1 AND id > ?2 AND deleted_at IS NULL AND parent_id IS NULL
+                ORDER BY id",
+            )?;
+            let mut messages = statement
+                .query_map((&channel_name, last_seen_message_id), message_from_row)?
+                .collect::<rusqlite::Result<Vec<ChatMessage>>>()?;
+            load_reactions(connection, messages.iter_mut().collect())?;
+            Ok(messages)
+        })
+        .await
+    }
+
+    async fn get_messages_before(
+        &self,
+        channel_name: &str,
+        parent_id: Option<MessageId>,
+        before_id: Option<MessageId>,
+        limit: u32,
+    ) -> Result<Vec<ChatMessage>, ChatError> {
+        let params = (
+            channel_name.to_string(),
+            parent_id,
+            before_id.unwrap_or(MessageId::MAX),
+            limit,
+        );
+        let mut messages = self
+            .call(move |connection| {
+                let mut statement = connection.prepare(
+                    "SELECT id, channel_name, user_name, created_at, coalesce(content, '') AS content,
+                        edited_at, deleted_at, parent_id,
+                        (SELECT count(*) FROM messages replies WHERE replies.parent_id = messages.id) AS reply_count
+                    FROM messages WHERE channel_name = ?1 AND parent_id IS ?2 AND id < ?3
+                    ORDER BY id DESC LIMIT ?4",
+                )?;
+                let mut messages = statement
+                    .query_map(params, message_from_row)?
+                    .collect::<rusqlite::Result<Vec<ChatMessage>>>()?;
+                load_reactions(connection, messages.iter_mut().collect())?;
+                Ok(messages)
+            })
+            .await?;
+        messages.reverse();
+        Ok(messages)
+    }
+
+    async fn search_messages(
+        &self,
+        query: &SearchQuery,
+        user_name: &str,
+    ) -> Result<Vec<SearchHit>, ChatError> {
+        let query = query.clone();
+        let user_name = user_name.to_string();
+        let fts_query = search::fts5_query(&query.text);
+        self.call(move |connection| {
+            // bm25 is lower for better matches
+            let (source, text_filter, rank) = if fts_query.is_empty() {
+                ("messages", "", "0.0")
+            } else {
+                (
+                    "messages_fts JOIN messages ON messages.id = messages_fts.rowid",
+                    "messages_fts MATCH ?1 AND",
+                    "-bm25(messages_fts)",
+                )
+            };
+            let mut statement = connection.prepare(&format!(
+                "SELECT id, channel_name, user_name, created_at, coalesce(messages.content, '') AS content,
+                    edited_at, deleted_at, parent_id,
+                    (SELECT count(*) FROM messages replies WHERE replies.parent_id = messages.id) AS reply_count, {} AS rank
+                FROM {}
+                WHERE {} deleted_at IS NULL
+                    AND (?2 IS NULL OR channel_name = ?2)
+                    AND (?3 IS NULL OR user_name = ?3)
+                    AND (?4 IS NULL OR created_at >= ?4)
+                    AND (?5 IS NULL OR created_at < ?5)
+                    AND channel_name IN (
+                        SELECT name FROM channels WHERE visibility = 'public'
+                        UNION
+                        SELECT channel_name FROM channel_members WHERE user_name = ?8)
+                ORDER BY rank DESC, id DESC
+                LIMIT ?7 OFFSET ?6",
+                rank, source, text_filter
+            ))?;
+            let hits = statement.query_map(
+                (
+                    &fts_query,
+                    &query.channel,
+                    &query.author,
+                    query.since,
+                    query.until,
+                    query.offset,
+                    query.limit,
+                    &user_name,
+                ),
+                |row| {
+                    Ok(SearchHit {
+                        message: message_from_row(row)?,
+                        rank: row.get("rank")?,
+                    })
+                },
+            )?;
+            let mut hits = hits.collect::<rusqlite::Result<Vec<SearchHit>>>()?;
+            load_reactions(connection, hits.iter_mut().map(|hit| &mut hit.message).collect())?;
+            Ok(hits)
+        })
+        .await
+    }
+
+    async fn save_message(
+        &self,
+        channel_name: &str,
+        user_name: &str,
+        parent_id: Option<MessageId>,
+        message: &str,
+    ) -> Result<ChatMessage, ChatError> {
+        let params = (
+            channel_name.to_string(),
+            user_name.to_string(),
+            message.to_string(),
+            unix_timestamp(),
+            parent_id,
+        );
+        self.call(move |connection| {
+            connection.query_row(
+                "INSERT INTO messages (channel_name, user_name, content, created_at, parent_id)
+                VALUES (?1, ?2, ?3, ?4, ?5)
+                RETURNING id, channel_name, user_name, created_at, content, edited_at, deleted_at,
+                    parent_id, 0 AS reply_count",
+                params,
+                message_from_row,
+            )
+        })
+        .await
+    }
+
+    async fn get_message(
+        &self,
+        channel_name: &str,
+        message_id: MessageId,
+    ) -> Result<Option<ChatMessage>, ChatError> {
+        let channel_name = channel_name.to_string();
+        self.call(move |connection| {
+            let mut message = connection
+                .query_row(
+                    "SELECT id, channel_name, user_name, created_at, coalesce(content, '') AS content,
+                        edited_at, deleted_at, parent_id,
+                        (SELECT count(*) FROM messages replies WHERE replies.parent_id = messages.id) AS reply_count
+                    FROM messages WHERE channel_name = ?1 AND id = ?2",
+                    (&channel_name, message_id),
+                    message_from_row,
+                )
+                .optional()?;
+            load_reactions(connection, message.iter_mut().collect())?;
+            Ok(message)
+        })
+        .await
+    }
+
+    async fn edit_message(
+        &self,
+        channel_name: &str,
+        message_id: MessageId,
+        edited_by: &str,
+        content: &str,
+    ) -> Result<ChatMessage, ChatError> {
+        let params = (
+            channel_name.to_string(),
+            edited_by.to_string(),
+            content.to_string(),
+            unix_timestamp(),
+        );
+        self.call(move |connection| {
+            let transaction = connection.transaction()?;
+            if !save_revision(&transaction, &params.0, message_id, &params.1, params.3)? {
+                return Ok(Err(ChatError::NoSuchMessage));
+            }
+            let mut message = transaction.query_row(
+                "UPDATE messages SET content = ?2, edited_at = ?3 WHERE id = ?1
+                RETURNING id, channel_name, user_name, created_at, content, edited_at, deleted_at,
+                    parent_id, (SELECT count(*) FROM messages replies WHERE replies.parent_id = messages.id) AS reply_count",
+                (message_id, &params.2, params.3),
+                message_from_row,
+            )?;
+            load_reactions(&transaction, vec![&mut message])?;
+            transaction.commit()?;
+            Ok(Ok(message))
+        })
+        .await?
+    }
+
+    async fn delete_message(
+        &self,
+        channel_name: &str,
+        message_id: MessageId,
+        deleted_by: &str,
+    ) -> Result<(), ChatError> {
+        let params = (
+            channel_name.to_string(),
+            deleted_by.to_string(),
+            unix_timestamp(),
+        );
+        self.call(move |connection| {
+            let transaction = connection.transaction()?;
+            if !save_revision(&transaction, &params.0, message_id, &params.1, params.2)? {
+                return Ok(Err(ChatError::NoSuchMessage));
+            }
+            transaction.execute(
+                "UPDATE messages SET content = NULL, deleted_at = ?2 WHERE id = ?1",
+                (message_id, params.2),
+            )?;
+            transaction.commit()?;
+            Ok(Ok(()))
+        })
+        .await?
+    }
+
+    async fn add_reaction(
+        &self,
+        channel_name: &str,
+        message_id: MessageId,
+        user_name: &str,
+        reaction: &str,
+    ) -> Result<bool, ChatError> {
+        let params = (
+            channel_name.to_string(),
+            message_id,
+            user_name.to_string(),
+            reaction.to_string(),
+            unix_timestamp(),
+        );
+        self.call(move |connection| {
+            let transaction = connection.transaction()?;
+            let exists = transaction
+                .query_row(
+                    "SELECT 1 FROM messages WHERE channel_name = ?1 AND id = ?2 AND deleted_at IS NULL",
+                    (&params.0, params.1),
+                    |_| Ok(()),
+                )
+                .optional()?;
+            if exists.is_none() {
+                return Ok(Err(ChatError::NoSuchMessage));
+            }
+            let added = transaction.execute(
+                "INSERT INTO reactions (message_id, user_name, reaction, created_at)
+                VALUES (?1, ?2, ?3, ?4)
+                ON CONFLICT DO NOTHING",
+                (params.1, &params.2, &params.3, params.4),
+            )?;
+            transaction.commit()?;
+            Ok(Ok(added > 0))
+        })
+        .await?
+    }
+
+    async fn remove_reaction(
+        &self,
+        channel_name: &str,
+        message_id: MessageId,
+        user_name: &str,
+        reaction: &str,
+    ) -> Result<bool, ChatError> {
+        let params = (
+            channel_name.to_string(),
+            message_id,
+            user_name.to_string(),
+            reaction.to_string(),
+        );
+        let removed = self
+            .call(move |connection| {
+                connection.execute(
+                    "DELETE FROM reactions
+                    WHERE message_id = ?2 AND user_name = ?3 AND reaction = ?4 AND message_id IN (
+                        SELECT id FROM messages WHERE channel_name = ?1)",
+                    params,
+                )
+            })
+            .await?;
+        Ok(removed > 0)
+    }
+
+    async fn pin_message(
+        &self,
+        channel_name: &str,
+        message_id: MessageId,
+        pinned_by: &str,
+    ) -> Result<bool, ChatError> {
+        let params = (
+            channel_name.to_string(),
+            message_id,
+            pinned_by.to_string(),
+            unix_timestamp(),
+        );
+        self.call(move |connection| {
+            let transaction = connection.transaction()?;
+            let exists = transaction
+                .query_row(
+                    "SELECT 1 FROM messages WHERE channel_name = ?1 AND id = ?2 AND deleted_at IS NULL",
+                    (&params.0, params.1),
+                    |_| Ok(()),
+                )
+                .optional()?;
+            if exists.is_none() {
+                return Ok(Err(ChatError::NoSuchMessage));
+            }
+            let pinned = transaction.execute(
+                "INSERT INTO pinned_messages (message_id, pinned_by, pinned_at) VALUES (?1, ?2, ?3)
+                ON CONFLICT DO NOTHING",
+                (params.1, &params.2, params.3),
+            )?;
+            transaction.commit()?;
+            Ok(Ok(pinned > 0))
+        })
+        .await?
+    }
+
+    async fn unpin_message(
+        &self,
+        channel_name: &str,
+        message_id: MessageId,
+    ) -> Result<bool, ChatError> {
+        let params = (channel_name.to_string(), message_id);
+        let unpinned = self
+            .call(move |connection| {
+                connection.execute(
+                    "DELETE FROM pinned_messages WHERE message_id = ?2 AND message_id IN (
+                        SELECT id FROM messages WHERE channel_name = ?1)",
+                    params,
+                )
+            })
+            .await?;
+        Ok(unpinned > 0)
+    }
+
+    async fn get_pinned_messages(
+        &self,
+        channel_name: &str,
+    ) -> Result<Vec<PinnedMessage>, ChatError> {
+        let channel_name = channel_name.to_string();
+        self.call(move |connection| {
+            let mut statement = connection.prepare(
+                "SELECT messages.id, messages.channel_name, messages.user_name, messages.created_at,
+                    coalesce(messages.content, '') AS content, messages.edited_at, messages.deleted_at,
+                    messages.parent_id,
+                    (SELECT count(*) FROM messages replies WHERE replies.parent_id = messages.id) AS reply_count,
+                    pinned_messages.pinned_by, pinned_messages.pinned_at
+                FROM pinned_messages
+                JOIN messages ON messages.id = pinned_messages.message_id
+                WHERE messages.channel_name = ?1 AND messages.deleted_at IS NULL
+                ORDER BY pinned_messages.pinned_at DESC, messages.id DESC",
+            )?;
+            let pins = statement.query_map([&channel_name], |row| {
+                Ok(PinnedMessage {
+                    message: message_from_row(row)?,
+                    pinned_by: row.get("pinned_by")?,
+                    pinned_at: row.get("pinned_at")?,
+                })
+            })?;
+            let mut pins = pins.collect::<rusqlite::Result<Vec<PinnedMessage>>>()?;
+            load_reactions(connection, pins.iter_mut().map(|pin| &mut pin.message).collect())?;
+            Ok(pins)
+        })
+        .await
+    }
+
+    async fn get_message_revisions(
+        &self,
+        message_id: MessageId,
+    ) -> Result<Vec<MessageRevision>, ChatError> {
+        self.call(move |connection| {
+            let mut statement = connection.prepare(
+                "SELECT message_id, coalesce(content, '') AS content, revised_by, revised_at
+                FROM message_revisions WHERE message_id = ?1 ORDER BY id",
+            )?;
+            let revisions = statement.query_map([message_id], revision_from_row)?;
+            revisions.collect()
+        })
+        .await
+    }
+
+    async fn list_threads(
+        &self,
+        channel_name: &str,
+        user_name: &str,
+    ) -> Result<Vec<ThreadInfo>, ChatError> {
+        let params = (channel_name.to_string(), user_name.to_string());
+        self.call(move |connection| {
+            let mut statement = connection.prepare(
+                "SELECT messages.id, messages.channel_name, messages.user_name, messages.created_at,
+                    coalesce(messages.content, '') AS content, messages.edited_at, messages.deleted_at,
+                    messages.parent_id, count(replies.id) AS reply_count,
+                    count(replies.id) FILTER (WHERE replies.id > coalesce(thread_history.message_id, -1)
+                        AND replies.deleted_at IS NULL) AS unread_count,
+                    max(replies.created_at) AS last_activity
+                FROM messages
+                JOIN messages replies ON replies.parent_id = messages.id
+                LEFT JOIN thread_history ON thread_history.parent_id = messages.id
+                    AND thread_history.user_name = ?2
+                WHERE messages.channel_name = ?1
+                GROUP BY messages.id
+                HAVING messages.user_name = ?2 OR max(replies.user_name = ?2)
+                    OR thread_history.message_id IS NOT NULL
+                ORDER BY last_activity DESC, messages.id DESC",
+            )?;
+            let threads = statement.query_map(params, |row| {
+                Ok(ThreadInfo {
+                    parent: message_from_row(row)?,
+                    unread_count: row.get("unread_count")?,
+                    last_activity: row.get("last_activity")?,
+                })
+            })?;
+            let mut threads = threads.collect::<rusqlite::Result<Vec<ThreadInfo>>>()?;
+            load_reactions(
+                connection,
+                threads.iter_mut().map(|thread| &mut thread.parent).collect(),
+            )?;
+            Ok(threads)
+        })
+        .await
+    }
+
+    async fn mark_thread_read(
+        &self,
+        channel_name: &str,
+        user_name: &str,
+        parent_id: MessageId,
+        message_id: MessageId,
+    ) -> Result<(), ChatError> {
+        let params = (
+            user_name.to_string(),
+            channel_name.to_string(),
+            parent_id,
+            message_id,
+        );
+        self.call(move |connection| {
+            let transaction = connection.transaction()?;
+            let marked = transaction.execute(
+                "INSERT INTO thread_history (user_name, parent_id, message_id)
+                SELECT ?1, parent_id, id FROM messages
+                WHERE id = ?4 AND parent_id = ?3 AND channel_name = ?2
+                ON CONFLICT (user_name, parent_id) DO UPDATE
+                    SET message_id = max(thread_history.message_id, excluded.message_id)",
+                (&params.0, &params.1, params.2, params.3),
+            )?;
+            if marked == 0 {
+                return Ok(Err(ChatError::NoSuchMessage));
+            }
+            transaction.execute(
+                "UPDATE mentions SET read_at = ?4
+                WHERE user_name = ?1 AND read_at IS NULL AND message_id IN (
+                    SELECT id FROM messages WHERE parent_id = ?2 AND id <= ?3)",
+                (&params.0, params.2, params.3, unix_timestamp()),
+            )?;
+            transaction.commit()?;
+            Ok(Ok(()))
+        })
+        .await?
+    }
+
+    async fn save_mentions(
+        &self,
+        message_id: MessageId,
+        user_names: &[String],
+        whole_channel: bool,
+    ) -> Result<Vec<String>, ChatError> {
+        let user_names = user_names.to_vec();
+        self.call(move |connection| {
+            let transaction = connection.transaction()?;
+            let mut mentioned = Vec::new();
+            {
+                let insert = |candidates: &str| {
+                    format!(
+                        "INSERT INTO mentions (message_id, user_name)
+                        SELECT messages.id, users.name
+                        FROM messages JOIN channels ON channels.name = messages.channel_name, users
+                        WHERE messages.id = ?1 AND users.name <> messages.user_name
+                            AND users.name IN ({})
+                            AND (channels.visibility = 'public' OR EXISTS (
+                                SELECT 1 FROM channel_members
+                                WHERE channel_name = messages.channel_name AND user_name = users.name))
+                        ON CONFLICT DO NOTHING
+                        RETURNING user_name",
+                        candidates
+                    )
+                };
+                let mut statement = transaction.prepare(&insert("?2"))?;
+                for user_name in &user_names {
+                    let names = statement.query_map((message_id, user_name), |row| row.get(0))?;
+                    for name in names {
+                        mentioned.push(name?);
+                    }
+                }
+                if whole_channel {
+                    let mut statement = transaction.prepare(&insert(
+                        "SELECT user_name FROM history WHERE channel_name = messages.channel_name
+                        UNION
+                        SELECT user_name FROM channel_members WHERE channel_name = messages.channel_name",
+                    ))?;
+                    let names = statement.query_map([message_id], |row| row.get(0))?;
+                    for name in names {
+                        mentioned.push(name?);
+                    }
+                }
+            }
+            transaction.commit()?;
+            Ok(mentioned)
+        })
+        .await
+    }
+
+    async fn list_mentions(
+        &self,
+        user_name: &str,
+        unread_only: bool,
+        before_id: Option<MessageId>,
+        limit: u32,
+    ) -> Result<Vec<Mention>, ChatError> {
+        let params = (
+            user_name.to_string(),
+            unread_only,
+            before_id.unwrap_or(MessageId::MAX),
+            limit,
+        );
+        self.call(move |connection| {
+            let mut statement = connection.prepare(
+                "SELECT messages.id, messages.channel_name, messages.user_name, messages.created_at,
+                    coalesce(messages.content, '') AS content, messages.edited_at, messages.deleted_at,
+                    messages.parent_id,
+                    (SELECT count(*) FROM messages replies WHERE replies.parent_id = messages.id) AS reply_count,
+                    mentions.read_at IS NOT NULL AS read
+                FROM mentions
+                JOIN messages ON messages.id = mentions.message_id
+                JOIN channels ON channels.name = messages.channel_name
+                WHERE mentions.user_name = ?1 AND (NOT ?2 OR mentions.read_at IS NULL)
+                    AND mentions.message_id < ?3 AND messages.deleted_at IS NULL
+                    AND (channels.visibility = 'public' OR EXISTS (
+                        SELECT 1 FROM channel_members
+                        WHERE channel_name = channels.name AND user_name = ?1))
+                ORDER BY mentions.message_id DESC LIMIT ?4",
+            )?;
+            let mentions = statement.query_map(params, |row| {
+                Ok(Mention {
+                    message: message_from_row(row)?,
+                    read: row.get("read")?,
+                })
+            })?;
+            let mut mentions = mentions.collect::<rusqlite::Result<Vec<Mention>>>()?;
+            mentions.reverse();
+            load_reactions(
+                connection,
+                mentions.iter_mut().map(|mention| &mut mention.message).collect(),
+            )?;
+            Ok(mentions)
+        })
+        .await
+    }
+
+    async fn mark_mentions_read(
+        &self,
+        user_name: &str,
+        message_id: MessageId,
+    ) -> Result<(), ChatError> {
+        let params = (user_name.to_string(), message_id, unix_timestamp());
+        self.call(move |connection| {
+            connection.execute(
+                "UPDATE mentions SET read_at = ?3
+                WHERE user_name = ?1 AND message_id <= ?2 AND read_at IS NULL",
+                params,
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn open_conversation(&self, members: &[String]) -> Result<ConversationId, ChatError> {
+        let members = members.to_vec();
+        let key = direct::member_key(&members);
+        let now = unix_timestamp();
+        self.call(move |connection| {
+            let transaction = connection.transaction()?;
+            for member in members.iter() {
+                let exists = transaction
+                    .query_row("SELECT 1 FROM users WHERE name = ?1", [member], |_| Ok(()))
+                    .optional()?;
+                if exists.is_none() {
+                    return Ok(Err(ChatError::NoSuchUser));
+                }
+            }
+            let inserted = transaction.execute(
+                "INSERT INTO conversations (member_key, created_at) VALUES (?1, ?2)
+                ON CONFLICT (member_key) DO NOTHING",
+                (&key, now),
+            )?;
+            let id: ConversationId = transaction.query_row(
+                "SELECT id FROM conversations WHERE member_key = ?1",
+                [&key],
+                |row| row.get(0),
+            )?;
+            if inserted > 0 {
+                for member in members.iter() {
+                    transaction.execute(
+                        "INSERT INTO conversation_members (conversation_id, user_name) VALUES (?1, ?2)",
+                        (id, member),
+                    )?;
+                }
+            }
+            transaction.commit()?;
+            Ok(Ok(id))
+        })
+        .await?
+    }
+
+    async fn get_conversation_members(
+        &self,
+        conversation_id: ConversationId,
+    ) -> Result<Option<Vec<String>>, ChatError> {
+        let key: Option<String> = self
+            .call(move |connection| {
+                connection
+                    .query_row(
+                        "SELECT member_key FROM conversations WHERE id = ?1",
+                        [conversation_id],
+                        |row| row.get(0),
+                    )
+                    .optional()
+            })
+            .await?;
+        Ok(key.map(|key| direct::members_from_key(&key)))
+    }
+
+    async fn list_conversations(
+        &self,
+        user_name: &str,
+    ) -> Result<Vec<ConversationInfo>, ChatError> {
+        let user_name = user_name.to_string();
+        self.call(move |connection| {
+            let mut statement = connection.prepare(
+                "SELECT conversations.id, conversations.member_key,
+                    count(direct_messages.id) FILTER (WHERE direct_messages.id > me.last_read_id)
+                        AS unread_count,
+                    max(direct_messages.created_at) AS last_activity
+                FROM conversation_members me
+                JOIN conversations ON conversations.id = me.conversation_id
+                LEFT JOIN direct_messages ON direct_messages.conversation_id = conversations.id
+                WHERE me.user_name = ?1
+                GROUP BY conversations.id, me.last_read_id
+                ORDER BY last_activity IS NULL, last_activity DESC, conversations.id DESC",
+            )?;
+            let conversations = statement.query_map([&user_name], |row| {
+                Ok(ConversationInfo {
+                    id: row.get("id")?,
+                    members: direct::members_from_key(&row.get::<_, String>("member_key")?),
+                    unread_count: row.get("unread_count")?,
+                    last_activity: row.get("last_activity")?,
+                })
+            })?;
+            conversations.collect()
+        })
+        .await
+    }
+
+    async fn save_direct_message(
+        &self,
+        conversation_id: ConversationId,
+        user_name: &str,
+        message: &str,
+    ) -> Result<DirectMessage, ChatError> {
+        let params = (
+            conversation_id,
+            user_name.to_string(),
+            unix_timestamp(),
+            message.to_string(),
+        );
+        self.call(move |connection| {
+            connection.query_row(
+                "INSERT INTO direct_messages (conversation_id, user_name, created_at, content)
+                VALUES (?1, ?2, ?3, ?4)
+                RETURNING id, conversation_id, user_name, created_at, content",
+                params,
+                direct_message_from_row,
+            )
+        })
+        .await
+    }
+
+    async fn get_unseen_direct_messages(
+        &self,
+        user_name: &str,
+    ) -> Result<Vec<DirectMessage>, ChatError> {
+        let user_name = user_name.to_string();
+        self.call(move |connection| {
+            let mut statement = connection.prepare(
+                "SELECT direct_messages.id, direct_messages.conversation_id, direct_messages.user_name,
+                    direct_messages.created_at, direct_messages.content
+                FROM direct_messages
+                JOIN conversation_members me ON me.conversation_id = direct_messages.conversation_id
+                WHERE me.user_name = ?1 AND direct_messages.id > me.last_read_id
+                ORDER BY direct_messages.id",
+            )?;
+            let messages = statement.query_map([&user_name], direct_message_from_row)?;
+            messages.collect()
+        })
+        .await
+    }
+
+    async fn mark_conversation_read(
+        &self,
+        conversation_id: ConversationId,
+        user_name: &str,
+        message_id: i32,
+    ) -> Result<(), ChatError> {
+        let params = (conversation_id, user_name.to_string(), message_id);
+        let marked = self
+            .call(move |connection| {
+                connection.execute(
+                    "UPDATE conversation_members SET last_read_id = max(last_read_id, ?3)
+                    WHERE conversation_id = ?1 AND user_name = ?2 AND EXISTS (
+                        SELECT 1 FROM direct_messages WHERE id = ?3 AND conversation_id = ?1)",
+                    params,
+                )
+            })
+            .await?;
+        if marked == 0 {
+            return Err(ChatError::NoSuchMessage);
+        }
+        Ok(())
+    }
+
+    async fn mark_read(
+        &self,
+        channel_name: &str,
+        user_name: &str,
+        message_id: MessageId,
+    ) -> Result<(), ChatError> {
+        let params = (user_name.to_string(), channel_name.to_string(), message_id);
+        self.call(move |connection| {
+            let transaction = connection.transaction()?;
+            let marked = transaction.execute(
+                "INSERT INTO history (user_name, channel_name, message_id)
+                SELECT ?1, ?2, id FROM messages WHERE id = ?3 AND channel_name = ?2
+                ON CONFLICT (user_name, channel_name) DO UPDATE
+                    SET message_id = max(history.message_id, excluded.message_id)",
+                (&params.0, &params.1, params.2),
+            )?;
+            if marked == 0 {
+                return Ok(Err(ChatError::NoSuchMessage));
+            }
+            transaction.execute(
+                "UPDATE mentions SET read_at = ?4
+                WHERE user_name = ?1 AND read_at IS NULL AND message_id IN (
+                    SELECT id FROM messages
+                    WHERE channel_name = ?2 AND parent_id IS NULL AND id <= ?3)",
+                (&params.0, &params.1, params.2, unix_timestamp()),
+            )?;
+            transaction.commit()?;
+            Ok(Ok(()))
+        })
+        .await?
+    }
+}
+
+// Copies current content of a message that was not deleted to its revisions.
+// Returns false if there is no such message in the channel.
+fn save_revision(
+    transaction: &Transaction,
+    channel_name: &str,
+    message_id: MessageId,
+    revised_by: &str,
+    revised_at: i64,
+) -> rusqlite::Result<bool> {
+    let saved = transaction.execute(
+        "INSERT INTO message_revisions (message_id, content, revised_by, revised_at)
+        SELECT id, content, ?3, ?4 FROM messages
+        WHERE channel_name = ?1 AND id = ?2 AND deleted_at IS NULL",
+        (channel_name, message_id, revised_by, revised_at),
+    )?;
+    Ok(saved > 0)
+}
+
+// Fills reactions of given messages
+fn load_reactions(
+    connection: &Connection,
+    messages: Vec<&mut ChatMessage>,
+) -> rusqlite::Result<()> {
+    let mut statement = connection.prepare_cached(
+        "SELECT reaction, count(*) AS count FROM reactions WHERE message_id = ?1
+        GROUP BY reaction
+        ORDER BY min(created_at), reaction",
+    )?;
+    for message in messages {
+        message.reactions = statement
+            .query_map([message.id], |row| {
+                Ok(ReactionCount {
+                    reaction: row.get("reaction")?,
+                    count: row.get("count")?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<ReactionCount>>>()?;
+    }
+    Ok(())
+}
+
+fn channel_from_row(row: &Row) -> rusqlite::Result<StoredChannel> {
+    let visibility: String = row.get("visibility")?;
+    Ok(StoredChannel {
+        name: row.get("name")?,
+        owner: row.get("owner")?,
+        visibility: visibility.parse().map_err(|e: ChatError| {
+            rusqlite::Error::FromSqlConversionFailure(2, Type::Text, Box::new(e))
+        })?,
+        topic: ChannelTopic {
+            topic: row.get("topic")?,
+            description: row.get("description")?,
+        },
+        archived_at: row.get("archived_at")?,
+    })
+}
+
+fn sanction_from_row(row: &Row) -> rusqlite::Result<Sanction> {
+    let kind: String = row.get("kind")?;
+    Ok(Sanction {
+        kind: kind.parse().map_err(|e: ChatError| {
+            rusqlite::Error::FromSqlConversionFailure(0, Type::Text, Box::new(e))
+        })?,
+        user_name: row.get("user_name")?,
+        channel: row.get("channel_name")?,
+        issued_by: row.get("issued_by")?,
+        reason: row.get("reason")?,
+        created_at: row.get("created_at")?,
+        expires_at: row.get("expires_at")?,
+    })
+}
+
+fn session_from_row(row: &Row) -> rusqlite::Result<Session> {
+    Ok(Session {
+        id: row.get("id")?,
+        user_name: row.get("user_name")?,
+        cookie_hash: row.get("cookie_hash")?,
+        issued_at: row.get("issued_at")?,
+        last_used_at: row.get("last_used_at")?,
+    })
+}
+
+fn message_from_row(row: &Row) -> rusqlite::Result<ChatMessage> {
+    Ok(ChatMessage {
+        id: row.get("id")?,
+        channel: row.get("channel_name")?,
+        author: row.get("user_name")?,
+        created_at: row.get("created_at")?,
+        body: row.get("content")?,
+        edited_at: row.get("edited_at")?,
+        deleted_at: row.get("deleted_at")?,
+        parent_id: row.get("parent_id")?,
+        reply_count: row.get("reply_count")?,
+        reactions: Vec::new(),
+    })
+}
+
+fn revision_from_row(row: &Row) -> rusqlite::Result<MessageRevision> {
+    Ok(MessageRevision {
+        message_id: row.get("message_id")?,
+        body: row.get("content")?,
+        revised_by: row.get("revised_by")?,
+        revised_at: row.get("revised_at")?,
+    })
+}
+
+fn invitation_from_row(row: &Row) -> rusqlite::Result<ChannelInvitation> {
+    Ok(ChannelInvitation {
+        channel: row.get("channel_name")?,
+        invited_by: row.get("invited_by")?,
+        created_at: row.get("created_at")?,
+    })
+}
+
+fn direct_message_from_row(row: &Row) -> rusqlite::Result<DirectMessage> {
+    Ok(DirectMessage {
+        id: row.get("id")?,
+        conversation_id: row.get("conversation_id")?,
+        author: row.get("user_name")?,
+        created_at: row.get("created_at")?,
+        body: row.get("content")?,
+    })
+}
@@ -0,0 +1,50 @@
+use std::collections::BTreeSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::MAX_CONVERSATION_MEMBERS;
+use crate::utils::ChatError;
+
+pub type ConversationId = i32;
+
+// Message of a direct conversation, kept apart from channel messages
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DirectMessage {
+    pub id: i32,
+    pub conversation_id: ConversationId,
+    pub author: String,
+    // unix seconds, assigned by the server when message is saved
+    pub created_at: i64,
+    pub body: String,
+}
+
+// Direct conversation as seen by one of its members
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ConversationInfo {
+    pub id: ConversationId,
+    // sorted, including the user who asked
+    pub members: Vec<String>,
+    pub unread_count: i64,
+    // unix seconds of the newest message, None if nothing was written yet
+    pub last_activity: Option<i64>,
+}
+
+// Sorted, deduplicated members of conversation started by owner with others.
+// There is exactly one conversation for every set of members.
+pub fn conversation_members(owner: &str, others: &[String]) -> Result<Vec<String>, ChatError> {
+    let mut members: BTreeSet<String> = others.iter().cloned().collect();
+    members.insert(owner.to_string());
+    if members.len() < 2 || members.len() > MAX_CONVERSATION_MEMBERS {
+        return Err(ChatError::InvalidMessage);
+    }
+    Ok(members.into_iter().collect())
+}
+
+// Unique key of a member set, stored with the conversation so it can be found by its members
+pub fn member_key(members: &[String]) -> String {
+    serde_json::to_string(members).expect("list of strings is always serializable")
+}
+
+pub fn members_from_key(key: &str) -> Vec<String> {
+    serde_json::from_str(key).unwrap_or_default()
+}
@@ -1,5 +1,14 @@
 pub mod channel;
 pub mod config;
 pub mod database;
+pub mod direct;
+pub mod mentions;
 pub mod messages;
+pub mod migrations;
+pub mod moderation;
+pub mod password;
+pub mod permissions;
+pub mod presence;
+pub mod search;
+pub mod session;
 pub mod utils;
@@ -0,0 +1,56 @@
+use std::collections::BTreeSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::messages::ChatMessage;
+
+// Who a message addresses, as written by its author. Names are not checked against users here.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Mentions {
+    pub users: BTreeSet<String>,
+    // @channel, everybody who reads the channel
+    pub channel: bool,
+    // @here, everybody in the channel at the moment
+    pub here: bool,
+}
+
+impl Mentions {
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty() && !self.channel && !self.here
+    }
+}
+
+// Channel message mentioning the user, as listed in their mentions
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Mention {
+    pub message: ChatMessage,
+    // marked read directly or by reading the channel or thread past the message
+    pub read: bool,
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '-' || c == '.'
+}
+
+// @ starts a mention at the beginning of the message or after a character which cannot be in a name,
+// so e-mail addresses do not mention anybody. Dots ending a name are taken as punctuation.
+pub fn parse(content: &str) -> Mentions {
+    let mut mentions = Mentions::default();
+    let mut previous = None;
+    for (index, c) in content.char_indices() {
+        if c == '@' && !previous.is_some_and(is_name_char) {
+            let rest = &content[index + 1..];
+            let end = rest.find(|c| !is_name_char(c)).unwrap_or(rest.len());
+            match rest[..end].trim_end_matches('.') {
+                "" => {}
+                "channel" => mentions.channel = true,
+                "here" => mentions.here = true,
+                name => {
+                    mentions.users.insert(name.to_string());
+                }
+            }
+        }
+        previous = Some(c);
+    }
+    mentions
+}
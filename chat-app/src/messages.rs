@@ -1,6 +1,87 @@
 use serde::{Deserialize, Serialize};
 
-use crate::{channel::ChannelInfo, database::AuthenticationToken};
+use crate::{
+    channel::{ChannelInfo, ChannelInvitation, ChannelTopic, ClosedReason, Visibility},
+    database::AuthenticationToken,
+    direct::{ConversationId, ConversationInfo, DirectMessage},
+    mentions::Mention,
+    moderation::Sanction,
+    permissions::Role,
+    presence::{MemberInfo, Status},
+    search::{SearchHit, SearchQuery},
+    session::{SessionId, SessionInfo},
+    utils::ChatError,
+};
+
+pub type MessageId = i32;
+
+// Message posted in a channel as stored by the server, rendering is left to the client
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChatMessage {
+    pub id: MessageId,
+    pub channel: String,
+    pub author: String,
+    // unix seconds, assigned by the server when message is saved
+    pub created_at: i64,
+    // empty for deleted messages
+    pub body: String,
+    // unix seconds of the last edit, None if message was never edited
+    pub edited_at: Option<i64>,
+    // unix seconds, deleted message stays in history as a tombstone
+    pub deleted_at: Option<i64>,
+    // first message of the thread for replies, None for messages posted to the channel itself
+    pub parent_id: Option<MessageId>,
+    // replies in the thread this message started, deleted ones included. Always 0 for replies.
+    pub reply_count: i64,
+    // in order they were first used on the message
+    pub reactions: Vec<ReactionCount>,
+}
+
+// How many users put the reaction on a message
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ReactionCount {
+    pub reaction: String,
+    pub count: i64,
+}
+
+// Content message had before it was edited or deleted
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MessageRevision {
+    pub message_id: MessageId,
+    pub body: String,
+    pub revised_by: String,
+    // unix seconds of the edit or delete which replaced this content
+    pub revised_at: i64,
+}
+
+// Thread user started or took part in, unread replies are counted from their thread read marker
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ThreadInfo {
+    pub parent: ChatMessage,
+    pub unread_count: i64,
+    // unix seconds of the newest reply
+    pub last_activity: i64,
+}
+
+// Message a moderator pinned to its channel
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PinnedMessage {
+    pub message: ChatMessage,
+    pub pinned_by: String,
+    // unix seconds
+    pub pinned_at: i64,
+}
+
+// Machine readable error category, so client can react without parsing descriptions
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    InvalidMessage,
+    Unauthenticated,
+    SessionExpired,
+    Forbidden,
+    NotFound,
+    Other,
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum ServerMessage {
@@ -15,10 +96,208 @@ pub enum ServerMessage {
         error: Option<String>,
     },
 
-    // text messages send in channel
+    // response to requests not tied to any channel
     TextMessage {
         content: String,
     },
+
+    // message posted in channel, client can be in many channels at once
+    ChannelMessage {
+        message: ChatMessage,
+    },
+
+    // reply was posted in the thread started by the message, it carries the new reply_count
+    ThreadUpdated {
+        message: ChatMessage,
+    },
+
+    // somebody added or removed a reaction, carries all reactions the message has now
+    ReactionsChanged {
+        channel: String,
+        message_id: MessageId,
+        reactions: Vec<ReactionCount>,
+    },
+
+    // author changed the message, it carries the new content
+    MessageEdited {
+        message: ChatMessage,
+    },
+
+    // message became a tombstone, its content is gone
+    MessageDeleted {
+        channel: String,
+        message_id: MessageId,
+        deleted_by: String,
+    },
+
+    // moderator pinned a message to the channel
+    MessagePinned {
+        channel: String,
+        pin: PinnedMessage,
+    },
+
+    // moderator took a pin down, the message itself stays
+    MessageUnpinned {
+        channel: String,
+        message_id: MessageId,
+        unpinned_by: String,
+    },
+
+    // response to ListPins, also sent on join before unseen messages. Most recently pinned first.
+    Pins {
+        channel: String,
+        pins: Vec<PinnedMessage>,
+    },
+
+    // response to FetchRevisions, oldest first
+    Revisions {
+        channel: String,
+        message_id: MessageId,
+        revisions: Vec<MessageRevision>,
+    },
+
+    // user entered the channel, sent once even if they join from several connections
+    MemberJoined {
+        channel: String,
+        user_name: String,
+    },
+
+    // last connection of the user left the channel
+    MemberLeft {
+        channel: String,
+        user_name: String,
+    },
+
+    // user in the channel went away or came back
+    StatusChanged {
+        channel: String,
+        user_name: String,
+        status: Status,
+    },
+
+    // direct message, live or replayed after login if it was not read yet
+    DirectMessage {
+        message: DirectMessage,
+    },
+
+    // message of a channel mentioned token owner, sent to all their connections
+    Mentioned {
+        message: ChatMessage,
+    },
+
+    // token owner was invited to a private channel, sent to all their connections
+    Invited {
+        invitation: ChannelInvitation,
+    },
+
+    // moderator kicked, muted or banned token owner, sent to all their connections.
+    // Kicked or banned user is no longer in the channel, server wide ban also ends their sessions.
+    Sanctioned {
+        sanction: Sanction,
+    },
+
+    // response to ListInvitations, oldest first
+    Invitations {
+        invitations: Vec<ChannelInvitation>,
+    },
+
+    // response to OpenConversation
+    ConversationOpened {
+        id: ConversationId,
+        members: Vec<String>,
+    },
+
+    // response to ListConversations, most recently active first
+    Conversations {
+        conversations: Vec<ConversationInfo>,
+    },
+
+    // response to ListMembers
+    Members {
+        channel: String,
+        members: Vec<MemberInfo>,
+    },
+
+    // response to Join, followed by unseen messages of the channel
+    Joined {
+        channel: String,
+        topic: ChannelTopic,
+    },
+
+    // topic or description of the channel was changed
+    TopicChanged {
+        channel: String,
+        topic: ChannelTopic,
+        changed_by: String,
+    },
+
+    // response to Leave, no more messages from channel will follow
+    Left {
+        channel: String,
+    },
+
+    // channel disconnected everybody in it, no more messages from it will follow
+    ChannelClosed {
+        channel: String,
+        reason: ClosedReason,
+    },
+
+    // response to FetchHistory, messages are oldest first
+    History {
+        channel: String,
+        messages: Vec<ChatMessage>,
+        // there are older messages than the first one in this page
+        has_more: bool,
+    },
+
+    // response to FetchThread, replies are oldest first
+    Thread {
+        channel: String,
+        parent: ChatMessage,
+        replies: Vec<ChatMessage>,
+        // there are older replies than the first one in this page
+        has_more: bool,
+    },
+
+    // response to ListThreads, most recently active first
+    Threads {
+        channel: String,
+        threads: Vec<ThreadInfo>,
+    },
+
+    // response to ListMentions, oldest first
+    Mentions {
+        mentions: Vec<Mention>,
+        // there are older mentions than the first one in this page
+        has_more: bool,
+    },
+
+    // response to Search
+    SearchResults {
+        hits: Vec<SearchHit>,
+        // there are more hits after this page
+        has_more: bool,
+    },
+
+    // request could not be handled
+    Error {
+        kind: ErrorKind,
+        description: String,
+    },
+
+    // response to ListSessions
+    Sessions {
+        sessions: Vec<SessionInfo>,
+    },
+}
+
+impl ServerMessage {
+    pub fn error(error: &ChatError) -> ServerMessage {
+        ServerMessage::Error {
+            kind: error.kind(),
+            description: error.to_string(),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -29,21 +308,170 @@ pub enum UserMessage {
         password: String,
     },
 
-    // user sends when they want to join channel
+    // user sends when they want to join channel, channels they are already in are kept
     Join {
         token: AuthenticationToken,
+        channel: String,
     },
 
-    // text messages send in channel
+    // user sends when they want to leave one of their channels
+    Leave {
+        token: AuthenticationToken,
+        channel: String,
+    },
+
+    // text messages send in channel, user has to join it first
     TextMessage {
         token: AuthenticationToken,
+        channel: String,
+        content: String,
+    },
+
+    // Like TextMessage, posted in the thread started by parent_id.
+    // Replying to a reply continues the thread it belongs to.
+    Reply {
+        token: AuthenticationToken,
+        channel: String,
+        parent_id: MessageId,
+        content: String,
+    },
+
+    // Author, channel owner, moderator or admin replaces content of message, user has to join the channel first
+    EditMessage {
+        token: AuthenticationToken,
+        channel: String,
+        message_id: MessageId,
         content: String,
     },
 
-    // Message with channel name to create
+    // Author, channel owner, moderator or admin deletes message, user has to join the channel first
+    DeleteMessage {
+        token: AuthenticationToken,
+        channel: String,
+        message_id: MessageId,
+    },
+
+    // Channel owner, moderator or admin pins message, user has to join the channel first
+    PinMessage {
+        token: AuthenticationToken,
+        channel: String,
+        message_id: MessageId,
+    },
+
+    // Same as PinMessage, takes the pin down
+    UnpinMessage {
+        token: AuthenticationToken,
+        channel: String,
+        message_id: MessageId,
+    },
+
+    // Puts a short token like :+1: on a message, user has to join the channel first.
+    // Adding reaction user already put on the message changes nothing.
+    AddReaction {
+        token: AuthenticationToken,
+        channel: String,
+        message_id: MessageId,
+        reaction: String,
+    },
+
+    // Takes back reaction of token owner, user has to join the channel first
+    RemoveReaction {
+        token: AuthenticationToken,
+        channel: String,
+        message_id: MessageId,
+        reaction: String,
+    },
+
+    // Previous contents of a message, for its author and whoever may moderate the channel
+    FetchRevisions {
+        token: AuthenticationToken,
+        channel: String,
+        message_id: MessageId,
+    },
+
+    // Message with channel name to create, token owner becomes its owner
     CreateChannel {
         token: AuthenticationToken,
         name: String,
+        visibility: Visibility,
+    },
+
+    // Admin only, channel keeps its history, members and read markers under the new name
+    RenameChannel {
+        token: AuthenticationToken,
+        channel: String,
+        new_name: String,
+    },
+
+    // Admin only, channel becomes read only, its history can still be fetched and searched
+    ArchiveChannel {
+        token: AuthenticationToken,
+        channel: String,
+    },
+
+    // Admin only, removes channel with all its messages
+    DeleteChannel {
+        token: AuthenticationToken,
+        channel: String,
+    },
+
+    // Channel owner, moderator or admin changes topic or description, None keeps current one
+    SetTopic {
+        token: AuthenticationToken,
+        channel: String,
+        topic: Option<String>,
+        description: Option<String>,
+    },
+
+    // Owner of a private channel invites another user to become its member
+    InviteToChannel {
+        token: AuthenticationToken,
+        channel: String,
+        user_name: String,
+    },
+
+    // Pending invitations of token owner
+    ListInvitations {
+        token: AuthenticationToken,
+    },
+
+    // Token owner becomes member of the channel they were invited to, they still have to Join it
+    AcceptInvitation {
+        token: AuthenticationToken,
+        channel: String,
+    },
+
+    // Drops invitation without becoming a member
+    DeclineInvitation {
+        token: AuthenticationToken,
+        channel: String,
+    },
+
+    // Channel owner, moderator or admin disconnects user from the channel, they can join again
+    Kick {
+        token: AuthenticationToken,
+        channel: String,
+        user_name: String,
+        reason: String,
+    },
+
+    // Like Kick, user stays in the channel but cannot post in it for duration_secs
+    Mute {
+        token: AuthenticationToken,
+        channel: String,
+        user_name: String,
+        reason: String,
+        duration_secs: u64,
+    },
+
+    // Like Kick, user cannot join again until the ban expires, None duration_secs never expires.
+    // Only admins can ban from the whole server, by leaving channel None.
+    Ban {
+        token: AuthenticationToken,
+        channel: Option<String>,
+        user_name: String,
+        reason: String,
+        duration_secs: Option<u64>,
     },
 
     // Message with user name and password to create
@@ -53,8 +481,142 @@ pub enum UserMessage {
         password: String,
     },
 
-    // Request to get channels info list
+    // Client has displayed every message of the channel up to message_id.
+    // Messages after the highest marked one are sent again on next Join, no response is sent.
+    MarkRead {
+        token: AuthenticationToken,
+        channel: String,
+        message_id: MessageId,
+    },
+
+    // Page of channel messages older than before_id, or the newest ones if it is None.
+    // limit is capped by the server.
+    FetchHistory {
+        token: AuthenticationToken,
+        channel: String,
+        before_id: Option<MessageId>,
+        limit: u32,
+    },
+
+    // Like FetchHistory, for replies in the thread started by parent_id
+    FetchThread {
+        token: AuthenticationToken,
+        channel: String,
+        parent_id: MessageId,
+        before_id: Option<MessageId>,
+        limit: u32,
+    },
+
+    // Like MarkRead, for replies in the thread started by parent_id
+    MarkThreadRead {
+        token: AuthenticationToken,
+        channel: String,
+        parent_id: MessageId,
+        message_id: MessageId,
+    },
+
+    // Threads of the channel token owner started or took part in, with their unread counts
+    ListThreads {
+        token: AuthenticationToken,
+        channel: String,
+    },
+
+    // Pinned messages of a channel token owner can see
+    ListPins {
+        token: AuthenticationToken,
+        channel: String,
+    },
+
+    // Like FetchHistory, for messages mentioning token owner in channels they can see
+    ListMentions {
+        token: AuthenticationToken,
+        unread_only: bool,
+        before_id: Option<MessageId>,
+        limit: u32,
+    },
+
+    // Marks every mention of token owner up to message_id read, no response is sent
+    MarkMentionsRead {
+        token: AuthenticationToken,
+        message_id: MessageId,
+    },
+
+    // Finds messages by text and filters in channels visible to token owner,
+    // limit is capped by the server
+    Search {
+        token: AuthenticationToken,
+        query: SearchQuery,
+    },
+
+    // Users currently in the channel, user does not have to be in it
+    ListMembers {
+        token: AuthenticationToken,
+        channel: String,
+    },
+
+    // Marks token owner as away or back online, Offline cannot be set
+    SetStatus {
+        token: AuthenticationToken,
+        status: Status,
+    },
+
+    // Finds or creates direct conversation of token owner with given users
+    OpenConversation {
+        token: AuthenticationToken,
+        members: Vec<String>,
+    },
+
+    // Message to every member of a direct conversation, token owner must be one of them
+    DirectMessage {
+        token: AuthenticationToken,
+        conversation_id: ConversationId,
+        content: String,
+    },
+
+    // Like MarkRead, for direct conversations. Unread direct messages are sent after login.
+    MarkConversationRead {
+        token: AuthenticationToken,
+        conversation_id: ConversationId,
+        message_id: i32,
+    },
+
+    // Direct conversations of token owner with their unread counts
+    ListConversations {
+        token: AuthenticationToken,
+    },
+
+    // Request to get info list of channels visible to token owner
     GetChannels {
         token: AuthenticationToken,
     },
+
+    // Ends session of given token
+    Logout {
+        token: AuthenticationToken,
+    },
+
+    // Request to get all live sessions of token owner
+    ListSessions {
+        token: AuthenticationToken,
+    },
+
+    // Ends one of token owner's sessions
+    RevokeSession {
+        token: AuthenticationToken,
+        session_id: SessionId,
+    },
+
+    // Admin only, gives user a new role
+    GrantRole {
+        token: AuthenticationToken,
+        user_name: String,
+        role: Role,
+    },
+
+    // Admin only, takes role away from user making them a plain member
+    RevokeRole {
+        token: AuthenticationToken,
+        user_name: String,
+        role: Role,
+    },
 }
@@ -0,0 +1,594 @@
+use rusqlite::TransactionBehavior;
+use tokio_postgres::Client;
+
+use anyhow::{Context, Result};
+
+use crate::utils::ChatError;
+
+pub const DEFAULT_CHANNELS: [&str; 3] = ["RED", "BLUE", "BROWN"];
+pub const DEFAULT_ADMIN_NAME: &str = "ADMIN";
+pub const DEFAULT_ADMIN_PASSWORD: &str = "ADMIN";
+
+// Single step of schema evolution, applied exactly once in ascending version order.
+// Every step must be safe to run against a database created before versioning existed,
+// so statements use IF NOT EXISTS / IF EXISTS where possible.
+// Each step is written once per supported SQL dialect, versions are shared.
+#[derive(Debug)]
+pub struct Migration {
+    pub version: i32,
+    pub description: &'static str,
+    pub postgres: &'static str,
+    pub sqlite: &'static str,
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "initial schema",
+        postgres: "
+        CREATE TABLE IF NOT EXISTS channels (
+            name        TEXT NOT NULL PRIMARY KEY
+        );
+        CREATE TABLE IF NOT EXISTS users (
+            name        TEXT NOT NULL PRIMARY KEY,
+            password    BIGINT
+        );
+        CREATE TABLE IF NOT EXISTS messages (
+            id              SERIAL PRIMARY KEY,
+            channel_name    TEXT NOT NULL,
+            user_name       TEXT NOT NULL,
+            content         TEXT,
+            CONSTRAINT      fk_channel FOREIGN KEY(channel_name) REFERENCES channels(name),
+            CONSTRAINT      fk_user FOREIGN KEY(user_name) REFERENCES users(name)
+        );
+        CREATE TABLE IF NOT EXISTS history (
+            user_name       TEXT NOT NULL,
+            channel_name    TEXT NOT NULL,
+            message_id      INT NOT NULL,
+            CONSTRAINT      pk PRIMARY KEY(user_name, channel_name),
+            CONSTRAINT      fk_message FOREIGN KEY(message_id) REFERENCES messages(id)
+        );",
+        // history.message_id is -1 for channels without messages, so it cannot reference messages
+        sqlite: "
+        CREATE TABLE IF NOT EXISTS channels (
+            name        TEXT NOT NULL PRIMARY KEY
+        );
+        CREATE TABLE IF NOT EXISTS users (
+            name        TEXT NOT NULL PRIMARY KEY,
+            password    BIGINT
+        );
+        CREATE TABLE IF NOT EXISTS messages (
+            id              INTEGER PRIMARY KEY AUTOINCREMENT,
+            channel_name    TEXT NOT NULL REFERENCES channels(name),
+            user_name       TEXT NOT NULL REFERENCES users(name),
+            content         TEXT
+        );
+        CREATE TABLE IF NOT EXISTS history (
+            user_name       TEXT NOT NULL,
+            channel_name    TEXT NOT NULL,
+            message_id      INT NOT NULL,
+            PRIMARY KEY(user_name, channel_name)
+        );",
+    },
+    // legacy users.password stays until the user logs in and gets rehashed
+    Migration {
+        version: 2,
+        description: "argon2 password hashes",
+        postgres: "ALTER TABLE users ADD COLUMN IF NOT EXISTS password_hash TEXT;",
+        sqlite: "ALTER TABLE users ADD COLUMN password_hash TEXT;",
+    },
+    // expires_at duplicates what Session computes, it lets expired rows be purged in one query
+    Migration {
+        version: 3,
+        description: "persistent sessions",
+        postgres: "
+        CREATE TABLE IF NOT EXISTS sessions (
+            id              TEXT NOT NULL PRIMARY KEY,
+            user_name       TEXT NOT NULL,
+            cookie_hash     TEXT NOT NULL,
+            issued_at       BIGINT NOT NULL,
+            last_used_at    BIGINT NOT NULL,
+            expires_at      BIGINT NOT NULL,
+            CONSTRAINT      fk_user FOREIGN KEY(user_name) REFERENCES users(name) ON DELETE CASCADE
+        );
+        CREATE INDEX IF NOT EXISTS sessions_user_name ON sessions(user_name);",
+        sqlite: "
+        CREATE TABLE IF NOT EXISTS sessions (
+            id              TEXT NOT NULL PRIMARY KEY,
+            user_name       TEXT NOT NULL REFERENCES users(name) ON DELETE CASCADE,
+            cookie_hash     TEXT NOT NULL,
+            issued_at       BIGINT NOT NULL,
+            last_used_at    BIGINT NOT NULL,
+            expires_at      BIGINT NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS sessions_user_name ON sessions(user_name);",
+    },
+    Migration {
+        version: 4,
+        description: "user roles",
+        postgres: "
+        ALTER TABLE users ADD COLUMN IF NOT EXISTS role TEXT NOT NULL DEFAULT 'member'
+            CHECK (role IN ('admin', 'moderator', 'member'));
+        UPDATE users SET role = 'admin' WHERE name = 'ADMIN';",
+        sqlite: "
+        ALTER TABLE users ADD COLUMN role TEXT NOT NULL DEFAULT 'member'
+            CHECK (role IN ('admin', 'moderator', 'member'));
+        UPDATE users SET role = 'admin' WHERE name = 'ADMIN';",
+    },
+    // messages saved before timestamps existed get the migration time, not their real one
+    Migration {
+        version: 5,
+        description: "message timestamps",
+        postgres: "
+        ALTER TABLE messages ADD COLUMN IF NOT EXISTS created_at BIGINT NOT NULL DEFAULT 0;
+        UPDATE messages SET created_at = extract(epoch from now())::BIGINT WHERE created_at = 0;",
+        sqlite: "
+        ALTER TABLE messages ADD COLUMN created_at BIGINT NOT NULL DEFAULT 0;
+        UPDATE messages SET created_at = strftime('%s', 'now') WHERE created_at = 0;",
+    },
+    // history pages are read by channel walking ids backwards
+    Migration {
+        version: 6,
+        description: "message history index",
+        postgres: "CREATE INDEX IF NOT EXISTS messages_channel_id ON messages(channel_name, id);",
+        sqlite: "CREATE INDEX IF NOT EXISTS messages_channel_id ON messages(channel_name, id);",
+    },
+    // 'simple' configuration does no stemming, chat is not in a single language.
+    // SQLite keeps an external content FTS5 index in sync with triggers
+    Migration {
+        version: 7,
+        description: "message search",
+        postgres: "
+        ALTER TABLE messages ADD COLUMN IF NOT EXISTS search_vector tsvector
+            GENERATED ALWAYS AS (to_tsvector('simple', coalesce(content, ''))) STORED;
+        CREATE INDEX IF NOT EXISTS messages_search ON messages USING GIN(search_vector);",
+        sqlite: "
+        CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts
+            USING fts5(content, content='messages', content_rowid='id');
+        INSERT INTO messages_fts(messages_fts) VALUES ('rebuild');
+        CREATE TRIGGER IF NOT EXISTS messages_fts_insert AFTER INSERT ON messages BEGIN
+            INSERT INTO messages_fts(rowid, content) VALUES (new.id, new.content);
+        END;
+        CREATE TRIGGER IF NOT EXISTS messages_fts_delete AFTER DELETE ON messages BEGIN
+            INSERT INTO messages_fts(messages_fts, rowid, content) VALUES ('delete', old.id, old.content);
+        END;
+        CREATE TRIGGER IF NOT EXISTS messages_fts_update AFTER UPDATE OF content ON messages BEGIN
+            INSERT INTO messages_fts(messages_fts, rowid, content) VALUES ('delete', old.id, old.content);
+            INSERT INTO messages_fts(rowid, content) VALUES (new.id, new.content);
+        END;",
+    },
+    // member_key is the sorted member list, one conversation exists per set of members
+    Migration {
+        version: 8,
+        description: "direct messages",
+        postgres: "
+        CREATE TABLE IF NOT EXISTS conversations (
+            id              SERIAL PRIMARY KEY,
+            member_key      TEXT NOT NULL UNIQUE,
+            created_at      BIGINT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS conversation_members (
+            conversation_id INT NOT NULL,
+            user_name       TEXT NOT NULL,
+            last_read_id    INT NOT NULL DEFAULT 0,
+            CONSTRAINT      pk_conversation_member PRIMARY KEY(conversation_id, user_name),
+            CONSTRAINT      fk_conversation FOREIGN KEY(conversation_id) REFERENCES conversations(id) ON DELETE CASCADE,
+            CONSTRAINT      fk_user FOREIGN KEY(user_name) REFERENCES users(name) ON DELETE CASCADE
+        );
+        CREATE INDEX IF NOT EXISTS conversation_members_user_name ON conversation_members(user_name);
+        CREATE TABLE IF NOT EXISTS direct_messages (
+            id              SERIAL PRIMARY KEY,
+            conversation_id INT NOT NULL,
+            user_name       TEXT NOT NULL,
+            created_at      BIGINT NOT NULL,
+            content         TEXT NOT NULL,
+            CONSTRAINT      fk_conversation FOREIGN KEY(conversation_id) REFERENCES conversations(id) ON DELETE CASCADE,
+            CONSTRAINT      fk_user FOREIGN KEY(user_name) REFERENCES users(name)
+        );
+        CREATE INDEX IF NOT EXISTS direct_messages_conversation_id ON direct_messages(conversation_id, id);",
+        sqlite: "
+        CREATE TABLE IF NOT EXISTS conversations (
+            id              INTEGER PRIMARY KEY AUTOINCREMENT,
+            member_key      TEXT NOT NULL UNIQUE,
+            created_at      BIGINT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS conversation_members (
+            conversation_id INT NOT NULL REFERENCES conversations(id) ON DELETE CASCADE,
+            user_name       TEXT NOT NULL REFERENCES users(name) ON DELETE CASCADE,
+            last_read_id    INT NOT NULL DEFAULT 0,
+            PRIMARY KEY(conversation_id, user_name)
+        );
+        CREATE INDEX IF NOT EXISTS conversation_members_user_name ON conversation_members(user_name);
+        CREATE TABLE IF NOT EXISTS direct_messages (
+            id              INTEGER PRIMARY KEY AUTOINCREMENT,
+            conversation_id INT NOT NULL REFERENCES conversations(id) ON DELETE CASCADE,
+            user_name       TEXT NOT NULL REFERENCES users(name),
+            created_at      BIGINT NOT NULL,
+            content         TEXT NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS direct_messages_conversation_id ON direct_messages(conversation_id, id);",
+    },
+    // existing channels stay public and without owner, channel_members lists members of private ones
+    Migration {
+        version: 9,
+        description: "private channels",
+        postgres: "
+        ALTER TABLE channels ADD COLUMN IF NOT EXISTS owner TEXT
+            REFERENCES users(name) ON DELETE SET NULL;
+        ALTER TABLE channels ADD COLUMN IF NOT EXISTS visibility TEXT NOT NULL DEFAULT 'public'
+            CHECK (visibility IN ('public', 'private'));
+        CREATE TABLE IF NOT EXISTS channel_members (
+            channel_name    TEXT NOT NULL,
+            user_name       TEXT NOT NULL,
+            joined_at       BIGINT NOT NULL,
+            CONSTRAINT      pk_channel_member PRIMARY KEY(channel_name, user_name),
+            CONSTRAINT      fk_channel FOREIGN KEY(channel_name) REFERENCES channels(name) ON DELETE CASCADE,
+            CONSTRAINT      fk_user FOREIGN KEY(user_name) REFERENCES users(name) ON DELETE CASCADE
+        );
+        CREATE INDEX IF NOT EXISTS channel_members_user_name ON channel_members(user_name);
+        CREATE TABLE IF NOT EXISTS channel_invitations (
+            channel_name    TEXT NOT NULL,
+            user_name       TEXT NOT NULL,
+            invited_by      TEXT NOT NULL,
+            created_at      BIGINT NOT NULL,
+            CONSTRAINT      pk_channel_invitation PRIMARY KEY(channel_name, user_name),
+            CONSTRAINT      fk_channel FOREIGN KEY(channel_name) REFERENCES channels(name) ON DELETE CASCADE,
+            CONSTRAINT      fk_user FOREIGN KEY(user_name) REFERENCES users(name) ON DELETE CASCADE,
+            CONSTRAINT      fk_invited_by FOREIGN KEY(invited_by) REFERENCES users(name) ON DELETE CASCADE
+        );
+        CREATE INDEX IF NOT EXISTS channel_invitations_user_name ON channel_invitations(user_name);",
+        sqlite: "
+        ALTER TABLE channels ADD COLUMN owner TEXT REFERENCES users(name) ON DELETE SET NULL;
+        ALTER TABLE channels ADD COLUMN visibility TEXT NOT NULL DEFAULT 'public'
+            CHECK (visibility IN ('public', 'private'));
+        CREATE TABLE IF NOT EXISTS channel_members (
+            channel_name    TEXT NOT NULL REFERENCES channels(name) ON DELETE CASCADE,
+            user_name       TEXT NOT NULL REFERENCES users(name) ON DELETE CASCADE,
+            joined_at       BIGINT NOT NULL,
+            PRIMARY KEY(channel_name, user_name)
+        );
+        CREATE INDEX IF NOT EXISTS channel_members_user_name ON channel_members(user_name);
+        CREATE TABLE IF NOT EXISTS channel_invitations (
+            channel_name    TEXT NOT NULL REFERENCES channels(name) ON DELETE CASCADE,
+            user_name       TEXT NOT NULL REFERENCES users(name) ON DELETE CASCADE,
+            invited_by      TEXT NOT NULL REFERENCES users(name) ON DELETE CASCADE,
+            created_at      BIGINT NOT NULL,
+            PRIMARY KEY(channel_name, user_name)
+        );
+        CREATE INDEX IF NOT EXISTS channel_invitations_user_name ON channel_invitations(user_name);",
+    },
+    // Postgres cascades renames and deletes of channels to rows referencing them.
+    // SQLite cannot change existing foreign keys, its store moves and deletes those rows itself
+    Migration {
+        version: 10,
+        description: "channel lifecycle",
+        postgres: "
+        ALTER TABLE channels ADD COLUMN IF NOT EXISTS archived_at BIGINT;
+        ALTER TABLE messages DROP CONSTRAINT IF EXISTS fk_channel,
+            ADD CONSTRAINT fk_channel FOREIGN KEY(channel_name) REFERENCES channels(name)
+                ON UPDATE CASCADE ON DELETE CASCADE;
+        ALTER TABLE history DROP CONSTRAINT IF EXISTS fk_message,
+            ADD CONSTRAINT fk_message FOREIGN KEY(message_id) REFERENCES messages(id) ON DELETE CASCADE;
+        ALTER TABLE channel_members DROP CONSTRAINT IF EXISTS fk_channel,
+            ADD CONSTRAINT fk_channel FOREIGN KEY(channel_name) REFERENCES channels(name)
+                ON UPDATE CASCADE ON DELETE CASCADE;
+        ALTER TABLE channel_invitations DROP CONSTRAINT IF EXISTS fk_channel,
+            ADD CONSTRAINT fk_channel FOREIGN KEY(channel_name) REFERENCES channels(name)
+                ON UPDATE CASCADE ON DELETE CASCADE;",
+        sqlite: "ALTER TABLE channels ADD COLUMN archived_at BIGINT;",
+    },
+    Migration {
+        version: 11,
+        description: "channel topics",
+        postgres: "
+        ALTER TABLE channels ADD COLUMN IF NOT EXISTS topic TEXT NOT NULL DEFAULT '';
+        ALTER TABLE channels ADD COLUMN IF NOT EXISTS description TEXT NOT NULL DEFAULT '';",
+        sqlite: "
+        ALTER TABLE channels ADD COLUMN topic TEXT NOT NULL DEFAULT '';
+        ALTER TABLE channels ADD COLUMN description TEXT NOT NULL DEFAULT '';",
+    },
+    // channel_name is NULL for server wide bans, expires_at for kicks and sanctions without end
+    Migration {
+        version: 12,
+        description: "moderation",
+        postgres: "
+        CREATE TABLE IF NOT EXISTS sanctions (
+            id              SERIAL PRIMARY KEY,
+            kind            TEXT NOT NULL CHECK (kind IN ('kick', 'mute', 'ban')),
+            user_name       TEXT NOT NULL,
+            channel_name    TEXT,
+            issued_by       TEXT NOT NULL,
+            reason          TEXT NOT NULL,
+            created_at      BIGINT NOT NULL,
+            expires_at      BIGINT,
+            CONSTRAINT      fk_user FOREIGN KEY(user_name) REFERENCES users(name) ON DELETE CASCADE,
+            CONSTRAINT      fk_channel FOREIGN KEY(channel_name) REFERENCES channels(name)
+                ON UPDATE CASCADE ON DELETE CASCADE,
+            CONSTRAINT      fk_issued_by FOREIGN KEY(issued_by) REFERENCES users(name) ON DELETE CASCADE
+        );
+        CREATE INDEX IF NOT EXISTS sanctions_user_name ON sanctions(user_name, kind);",
+        sqlite: "
+        CREATE TABLE IF NOT EXISTS sanctions (
+            id              INTEGER PRIMARY KEY AUTOINCREMENT,
+            kind            TEXT NOT NULL CHECK (kind IN ('kick', 'mute', 'ban')),
+            user_name       TEXT NOT NULL REFERENCES users(name) ON DELETE CASCADE,
+            channel_name    TEXT REFERENCES channels(name) ON DELETE CASCADE,
+            issued_by       TEXT NOT NULL REFERENCES users(name) ON DELETE CASCADE,
+            reason          TEXT NOT NULL,
+            created_at      BIGINT NOT NULL,
+            expires_at      BIGINT
+        );
+        CREATE INDEX IF NOT EXISTS sanctions_user_name ON sanctions(user_name, kind);",
+    },
+    // revisions keep content replaced by every edit or delete, deleted message stays as a tombstone without content
+    Migration {
+        version: 13,
+        description: "message edits",
+        postgres: "
+        ALTER TABLE messages ADD COLUMN IF NOT EXISTS edited_at BIGINT;
+        ALTER TABLE messages ADD COLUMN IF NOT EXISTS deleted_at BIGINT;
+        CREATE TABLE IF NOT EXISTS message_revisions (
+            id              SERIAL PRIMARY KEY,
+            message_id      INT NOT NULL,
+            content         TEXT,
+            revised_by      TEXT NOT NULL,
+            revised_at      BIGINT NOT NULL,
+            CONSTRAINT      fk_message FOREIGN KEY(message_id) REFERENCES messages(id) ON DELETE CASCADE,
+            CONSTRAINT      fk_user FOREIGN KEY(revised_by) REFERENCES users(name) ON DELETE CASCADE
+        );
+        CREATE INDEX IF NOT EXISTS message_revisions_message_id ON message_revisions(message_id);",
+        sqlite: "
+        ALTER TABLE messages ADD COLUMN edited_at BIGINT;
+        ALTER TABLE messages ADD COLUMN deleted_at BIGINT;
+        CREATE TABLE IF NOT EXISTS message_revisions (
+            id              INTEGER PRIMARY KEY AUTOINCREMENT,
+            message_id      INT NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+            content         TEXT,
+            revised_by      TEXT NOT NULL REFERENCES users(name) ON DELETE CASCADE,
+            revised_at      BIGINT NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS message_revisions_message_id ON message_revisions(message_id);",
+    },
+    // replies point at the first message of their thread, thread_history keeps read markers per thread like history does per channel
+    Migration {
+        version: 14,
+        description: "threads",
+        postgres: "
+        ALTER TABLE messages ADD COLUMN IF NOT EXISTS parent_id INT;
+        ALTER TABLE messages DROP CONSTRAINT IF EXISTS fk_parent,
+            ADD CONSTRAINT fk_parent FOREIGN KEY(parent_id) REFERENCES messages(id) ON DELETE CASCADE;
+        CREATE INDEX IF NOT EXISTS messages_parent_id ON messages(parent_id, id);
+        CREATE TABLE IF NOT EXISTS thread_history (
+            user_name       TEXT NOT NULL,
+            parent_id       INT NOT NULL,
+            message_id      INT NOT NULL,
+            PRIMARY KEY(user_name, parent_id),
+            CONSTRAINT      fk_user FOREIGN KEY(user_name) REFERENCES users(name) ON DELETE CASCADE,
+            CONSTRAINT      fk_parent FOREIGN KEY(parent_id) REFERENCES messages(id) ON DELETE CASCADE,
+            CONSTRAINT      fk_message FOREIGN KEY(message_id) REFERENCES messages(id) ON DELETE CASCADE
+        );",
+        sqlite: "
+        ALTER TABLE messages ADD COLUMN parent_id INT REFERENCES messages(id) ON DELETE CASCADE;
+        CREATE INDEX IF NOT EXISTS messages_parent_id ON messages(parent_id, id);
+        CREATE TABLE IF NOT EXISTS thread_history (
+            user_name       TEXT NOT NULL REFERENCES users(name) ON DELETE CASCADE,
+            parent_id       INT NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+            message_id      INT NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+            PRIMARY KEY(user_name, parent_id)
+        );",
+    },
+    // every user can put each reaction on a message once
+    Migration {
+        version: 15,
+        description: "reactions",
+        postgres: "
+        CREATE TABLE IF NOT EXISTS reactions (
+            message_id      INT NOT NULL,
+            user_name       TEXT NOT NULL,
+            reaction        TEXT NOT NULL,
+            created_at      BIGINT NOT NULL,
+            PRIMARY KEY(message_id, user_name, reaction),
+            CONSTRAINT      fk_message FOREIGN KEY(message_id) REFERENCES messages(id) ON DELETE CASCADE,
+            CONSTRAINT      fk_user FOREIGN KEY(user_name) REFERENCES users(name) ON DELETE CASCADE
+        );",
+        sqlite: "
+        CREATE TABLE IF NOT EXISTS reactions (
+            message_id      INT NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+            user_name       TEXT NOT NULL REFERENCES users(name) ON DELETE CASCADE,
+            reaction        TEXT NOT NULL,
+            created_at      BIGINT NOT NULL,
+            PRIMARY KEY(message_id, user_name, reaction)
+        );",
+    },
+    // users mentioned by each message, resolved when it is posted. read_at is NULL until the user reads it
+    Migration {
+        version: 16,
+        description: "mentions",
+        postgres: "
+        CREATE TABLE IF NOT EXISTS mentions (
+            message_id      INT NOT NULL,
+            user_name       TEXT NOT NULL,
+            read_at         BIGINT,
+            PRIMARY KEY(message_id, user_name),
+            CONSTRAINT      fk_message FOREIGN KEY(message_id) REFERENCES messages(id) ON DELETE CASCADE,
+            CONSTRAINT      fk_user FOREIGN KEY(user_name) REFERENCES users(name) ON DELETE CASCADE
+        );
+        CREATE INDEX IF NOT EXISTS mentions_user_name ON mentions(user_name, message_id);",
+        sqlite: "
+        CREATE TABLE IF NOT EXISTS mentions (
+            message_id      INT NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+            user_name       TEXT NOT NULL REFERENCES users(name) ON DELETE CASCADE,
+            read_at         BIGINT,
+            PRIMARY KEY(message_id, user_name)
+        );
+        CREATE INDEX IF NOT EXISTS mentions_user_name ON mentions(user_name, message_id);",
+    },
+    // messages moderators pinned, their channel is the channel of the message
+    Migration {
+        version: 17,
+        description: "pinned messages",
+        postgres: "
+        CREATE TABLE IF NOT EXISTS pinned_messages (
+            message_id      INT PRIMARY KEY,
+            pinned_by       TEXT NOT NULL,
+            pinned_at       BIGINT NOT NULL,
+            CONSTRAINT      fk_message FOREIGN KEY(message_id) REFERENCES messages(id) ON DELETE CASCADE,
+            CONSTRAINT      fk_user FOREIGN KEY(pinned_by) REFERENCES users(name) ON DELETE CASCADE
+        );",
+        sqlite: "
+        CREATE TABLE IF NOT EXISTS pinned_messages (
+            message_id      INT PRIMARY KEY REFERENCES messages(id) ON DELETE CASCADE,
+            pinned_by       TEXT NOT NULL REFERENCES users(name) ON DELETE CASCADE,
+            pinned_at       BIGINT NOT NULL
+        );",
+    },
+];
+
+pub fn latest_version() -> i32 {
+    MIGRATIONS.last().map_or(0, |migration| migration.version)
+}
+
+fn pending_migrations(current_version: i32) -> Result<impl Iterator<Item = &'static Migration>> {
+    if current_version > latest_version() {
+        return Err(ChatError::UnsupportedSchemaVersion {
+            found: current_version,
+            supported: latest_version(),
+        }
+        .into());
+    }
+    Ok(MIGRATIONS
+        .iter()
+        .filter(move |migration| migration.version > current_version))
+}
+
+fn log_migration(migration: &Migration) {
+    tracing::info!(
+        "Applying migration {}: {}",
+        migration.version,
+        migration.description
+    );
+}
+
+// Brings the database up to the newest schema this binary knows about.
+// Whole run happens in one transaction holding a lock on schema_version,
+// so two servers starting at once cannot apply the same step twice.
+pub async fn migrate(client: &mut Client) -> Result<()> {
+    client
+        .batch_execute(
+            "CREATE TABLE IF NOT EXISTS schema_version (
+            version         INT NOT NULL PRIMARY KEY,
+            description     TEXT NOT NULL,
+            applied_at      TIMESTAMPTZ NOT NULL DEFAULT now())",
+        )
+        .await
+        .context("Error creating schema_version table!")?;
+
+    let transaction = client.transaction().await?;
+    transaction
+        .batch_execute("LOCK TABLE schema_version IN EXCLUSIVE MODE")
+        .await?;
+
+    let current_version: i32 = transaction
+        .query_one("SELECT coalesce(MAX(version), 0) FROM schema_version", &[])
+        .await
+        .context("Error reading schema version!")?
+        .get(0);
+
+    for migration in pending_migrations(current_version)? {
+        log_migration(migration);
+        transaction
+            .batch_execute(migration.postgres)
+            .await
+            .with_context(|| format!("Error applying migration {}!", migration.version))?;
+        transaction
+            .execute(
+                "INSERT INTO schema_version (version, description) VALUES ($1, $2)",
+                &[&migration.version, &migration.description],
+            )
+            .await?;
+    }
+
+    transaction.commit().await?;
+    Ok(())
+}
+
+// Inserts default channels and ADMIN account, leaving existing rows untouched.
+pub async fn seed(client: &Client, admin_password_hash: &str) -> Result<()> {
+    for channel_name in DEFAULT_CHANNELS {
+        client
+            .execute(
+                "INSERT INTO channels (name) VALUES ($1) ON CONFLICT DO NOTHING",
+                &[&channel_name],
+            )
+            .await
+            .context("Error seeding default channels!")?;
+    }
+
+    client
+        .execute(
+            "INSERT INTO users (name, password_hash, role) VALUES ($1, $2, 'admin')
+            ON CONFLICT DO NOTHING",
+            &[&DEFAULT_ADMIN_NAME, &admin_password_hash],
+        )
+        .await
+        .context("Error seeding admin user!")?;
+
+    Ok(())
+}
+
+// SQLite counterpart of migrate, IMMEDIATE transaction takes the write lock up front.
+pub fn migrate_sqlite(connection: &mut rusqlite::Connection) -> Result<()> {
+    connection
+        .execute_batch(
+            "CREATE TABLE IF NOT EXISTS schema_version (
+            version         INT NOT NULL PRIMARY KEY,
+            description     TEXT NOT NULL,
+            applied_at      TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP)",
+        )
+        .context("Error creating schema_version table!")?;
+
+    let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+    let current_version: i32 = transaction
+        .query_row(
+            "SELECT coalesce(MAX(version), 0) FROM schema_version",
+            [],
+            |row| row.get(0),
+        )
+        .context("Error reading schema version!")?;
+
+    for migration in pending_migrations(current_version)? {
+        log_migration(migration);
+        transaction
+            .execute_batch(migration.sqlite)
+            .with_context(|| format!("Error applying migration {}!", migration.version))?;
+        transaction.execute(
+            "INSERT INTO schema_version (version, description) VALUES (?1, ?2)",
+            (migration.version, migration.description),
+        )?;
+    }
+
+    transaction.commit()?;
+    Ok(())
+}
+
+pub fn seed_sqlite(connection: &rusqlite::Connection, admin_password_hash: &str) -> Result<()> {
+    for channel_name in DEFAULT_CHANNELS {
+        connection
+            .execute(
+                "INSERT INTO channels (name) VALUES (?1) ON CONFLICT DO NOTHING",
+                [channel_name],
+            )
+            .context("Error seeding default channels!")?;
+    }
+
+    connection
+        .execute(
+            "INSERT INTO users (name, password_hash, role) VALUES (?1, ?2, 'admin')
+            ON CONFLICT DO NOTHING",
+            (DEFAULT_ADMIN_NAME, admin_password_hash),
+        )
+        .context("Error seeding admin user!")?;
+
+    Ok(())
+}
@@ -0,0 +1,102 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::MAX_REASON_LENGTH;
+use crate::utils::{unix_timestamp, ChatError};
+
+// What a moderator did to a user
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SanctionKind {
+    // disconnected from the channel, they can join again right away
+    Kick,
+    // can stay in the channel, but cannot post in it
+    Mute,
+    // cannot join the channel, or log in at all for a server wide ban
+    Ban,
+}
+
+impl SanctionKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SanctionKind::Kick => "kick",
+            SanctionKind::Mute => "mute",
+            SanctionKind::Ban => "ban",
+        }
+    }
+}
+
+impl FromStr for SanctionKind {
+    type Err = ChatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "kick" => Ok(SanctionKind::Kick),
+            "mute" => Ok(SanctionKind::Mute),
+            "ban" => Ok(SanctionKind::Ban),
+            _ => Err(ChatError::InvalidMessage),
+        }
+    }
+}
+
+impl fmt::Display for SanctionKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// Moderation action taken against a user, kept as a record after it expires
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Sanction {
+    pub kind: SanctionKind,
+    pub user_name: String,
+    // None when it applies to the whole server
+    pub channel: Option<String>,
+    pub issued_by: String,
+    pub reason: String,
+    // unix seconds
+    pub created_at: i64,
+    // unix seconds, None for sanctions lasting until further notice.
+    // Kicks take effect once, they expire as soon as they are issued.
+    pub expires_at: Option<i64>,
+}
+
+impl Sanction {
+    // Sanction starting now. Only bans may apply to the whole server and only mutes and bans last.
+    pub fn new(
+        kind: SanctionKind,
+        user_name: &str,
+        channel: Option<String>,
+        issued_by: &str,
+        reason: &str,
+        duration_secs: Option<u64>,
+    ) -> Result<Sanction, ChatError> {
+        let valid = match kind {
+            SanctionKind::Kick => channel.is_some() && duration_secs.is_none(),
+            SanctionKind::Mute => channel.is_some() && duration_secs.is_some_and(|secs| secs > 0),
+            SanctionKind::Ban => duration_secs != Some(0),
+        };
+        if !valid || reason.chars().count() > MAX_REASON_LENGTH {
+            return Err(ChatError::InvalidMessage);
+        }
+        let created_at = unix_timestamp();
+        Ok(Sanction {
+            kind,
+            user_name: user_name.to_string(),
+            channel,
+            issued_by: issued_by.to_string(),
+            reason: reason.to_string(),
+            created_at,
+            expires_at: match kind {
+                SanctionKind::Kick => Some(created_at),
+                _ => duration_secs
+                    .map(|secs| created_at.saturating_add(i64::try_from(secs).unwrap_or(i64::MAX))),
+            },
+        })
+    }
+
+    pub fn is_active(&self, now: i64) -> bool {
+        self.expires_at.is_none_or(|expires_at| expires_at > now)
+    }
+}
@@ -0,0 +1,99 @@
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+use rand::{thread_rng, RngCore};
+
+use crate::utils::{calculate_hash, ChatError};
+
+// What is stored in users table for a single account.
+// Accounts created before Argon2 was introduced only have legacy DefaultHasher value,
+// they are rehashed on first successful login and legacy value is dropped.
+#[derive(Debug, Clone, Default)]
+pub struct StoredPassword {
+    pub hash: Option<String>,
+    pub legacy: Option<i64>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verification {
+    Invalid,
+    Valid,
+    // password matched, but stored hash is outdated and should be replaced with this one
+    ValidRehashed(String),
+}
+
+fn hasher() -> Argon2<'static> {
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, Params::default())
+}
+
+fn hash_password_blocking(password: &str) -> Result<String, ChatError> {
+    let mut salt = [0u8; 16];
+    thread_rng().fill_bytes(&mut salt);
+    let salt = SaltString::encode_b64(&salt).map_err(|_| ChatError::RuntimeError)?;
+    let hash = hasher()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|_| ChatError::RuntimeError)?;
+    Ok(hash.to_string())
+}
+
+// Hash uses current algorithm and cost parameters
+fn is_current(hash: &PasswordHash) -> bool {
+    let current = Params::default();
+    hash.algorithm == Algorithm::Argon2id.ident()
+        && hash.version == Some(Version::V0x13.into())
+        && Params::try_from(hash).is_ok_and(|params| {
+            params.m_cost() == current.m_cost()
+                && params.t_cost() == current.t_cost()
+                && params.p_cost() == current.p_cost()
+        })
+}
+
+fn verify_password_blocking(
+    password: &str,
+    stored: &StoredPassword,
+) -> Result<Verification, ChatError> {
+    let up_to_date = match (&stored.hash, stored.legacy) {
+        (Some(hash), _) => {
+            let hash = PasswordHash::new(hash).map_err(|_| ChatError::RuntimeError)?;
+            if hasher()
+                .verify_password(password.as_bytes(), &hash)
+                .is_err()
+            {
+                return Ok(Verification::Invalid);
+            }
+            is_current(&hash)
+        }
+        (None, Some(legacy)) => {
+            if calculate_hash(&password) as i64 != legacy {
+                return Ok(Verification::Invalid);
+            }
+            false
+        }
+        (None, None) => return Ok(Verification::Invalid),
+    };
+
+    if up_to_date {
+        Ok(Verification::Valid)
+    } else {
+        Ok(Verification::ValidRehashed(hash_password_blocking(
+            password,
+        )?))
+    }
+}
+
+// Argon2 is deliberately slow, so both operations run outside of async worker threads
+pub async fn hash_password(password: &str) -> Result<String, ChatError> {
+    let password = password.to_string();
+    tokio::task::spawn_blocking(move || hash_password_blocking(&password))
+        .await
+        .map_err(|_| ChatError::RuntimeError)?
+}
+
+pub async fn verify_password(
+    password: &str,
+    stored: StoredPassword,
+) -> Result<Verification, ChatError> {
+    let password = password.to_string();
+    tokio::task::spawn_blocking(move || verify_password_blocking(&password, &stored))
+        .await
+        .map_err(|_| ChatError::RuntimeError)?
+}
@@ -0,0 +1,76 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::utils::ChatError;
+
+// Role stored with every user, decides which privileged requests they can make
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Role {
+    Admin,
+    Moderator,
+    Member,
+}
+
+// Privileged operations, everything not listed here is allowed to any authenticated user
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    CreateUser,
+    CreateChannel,
+    // rename, archive and delete channels
+    ManageChannels,
+    // what channel owners can do in their channel, in any channel
+    ModerateChannels,
+    ManageRoles,
+    // ban users from the whole server
+    BanUsers,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::Moderator => "moderator",
+            Role::Member => "member",
+        }
+    }
+
+    pub fn allows(&self, permission: Permission) -> bool {
+        self.grants().contains(&permission)
+    }
+
+    fn grants(&self) -> &'static [Permission] {
+        match self {
+            Role::Admin => &[
+                Permission::CreateUser,
+                Permission::CreateChannel,
+                Permission::ManageChannels,
+                Permission::ModerateChannels,
+                Permission::ManageRoles,
+                Permission::BanUsers,
+            ],
+            Role::Moderator => &[Permission::ModerateChannels],
+            Role::Member => &[],
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = ChatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "admin" => Ok(Role::Admin),
+            "moderator" => Ok(Role::Moderator),
+            "member" => Ok(Role::Member),
+            _ => Err(ChatError::InvalidMessage),
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::channel::Tx;
+use crate::utils::unix_timestamp;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Online,
+    Away,
+    Offline,
+}
+
+// What others can see about a user in channel roster
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MemberInfo {
+    pub user_name: String,
+    pub status: Status,
+    // unix seconds of the last request made by the user
+    pub last_seen: i64,
+}
+
+#[derive(Debug)]
+struct UserPresence {
+    connections: HashMap<SocketAddr, Tx>,
+    away: bool,
+    last_seen: i64,
+}
+
+impl UserPresence {
+    fn status(&self) -> Status {
+        match (self.connections.len(), self.away) {
+            (0, _) => Status::Offline,
+            (_, true) => Status::Away,
+            (_, false) => Status::Online,
+        }
+    }
+}
+
+// Server wide registry of connected users, user is online as long as any of their connections is.
+// Also lets messages addressed to a user, not a channel, reach all their connections.
+// Kept in process only, after restart everybody starts offline.
+#[derive(Debug, Default)]
+pub struct Presence {
+    users: DashMap<String, UserPresence>,
+}
+
+impl Presence {
+    pub fn connect(&self, user_name: &str, addr: SocketAddr, tx: Tx) {
+        let mut user = self
+            .users
+            .entry(user_name.to_string())
+            .or_insert(UserPresence {
+                connections: HashMap::new(),
+                away: false,
+                last_seen: 0,
+            });
+        user.connections.insert(addr, tx);
+        user.last_seen = unix_timestamp();
+    }
+
+    // Away flag is cleared when the last connection goes away
+    pub fn disconnect(&self, user_name: &str, addr: SocketAddr) {
+        if let Some(mut user) = self.users.get_mut(user_name) {
+            user.connections.remove(&addr);
+            user.last_seen = unix_timestamp();
+            if user.connections.is_empty() {
+                user.away = false;
+            }
+        }
+    }
+
+    // Sends already encoded message to every connection of the user, if they are online
+    pub fn deliver(&self, user_name: &str, message: &str) {
+        if let Some(user) = self.users.get(user_name) {
+            for tx in user.connections.values() {
+                let _ = tx.send(message.into());
+            }
+        }
+    }
+
+    pub fn touch(&self, user_name: &str) {
+        if let Some(mut user) = self.users.get_mut(user_name) {
+            user.last_seen = unix_timestamp();
+        }
+    }
+
+    // Returns new status if it changed
+    pub fn set_away(&self, user_name: &str, away: bool) -> Option<Status> {
+        let mut user = self.users.get_mut(user_name)?;
+        if user.connections.is_empty() || user.away == away {
+            return None;
+        }
+        user.away = away;
+        Some(user.status())
+    }
+
+    pub fn member_info(&self, user_name: &str) -> MemberInfo {
+        let (status, last_seen) = match self.users.get(user_name) {
+            Some(user) => (user.status(), user.last_seen),
+            None => (Status::Offline, 0),
+        };
+        MemberInfo {
+            user_name: user_name.to_string(),
+            status,
+            last_seen,
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use serde::{Deserialize, Serialize};
+
+use crate::messages::ChatMessage;
+
+// Filters of a message search, all given ones must match.
+// Empty text matches every message, then hits are just the newest messages passing other filters.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SearchQuery {
+    pub text: String,
+    pub channel: Option<String>,
+    pub author: Option<String>,
+    // unix seconds, inclusive
+    pub since: Option<i64>,
+    // unix seconds, exclusive
+    pub until: Option<i64>,
+    // hits to skip, for fetching next pages
+    pub offset: u32,
+    pub limit: u32,
+}
+
+// Found message, hits are ordered by rank descending, newer first on ties.
+// Ranks are only comparable within one search.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SearchHit {
+    pub message: ChatMessage,
+    pub rank: f64,
+}
+
+impl SearchQuery {
+    // Filters other than text
+    pub fn matches_filters(&self, message: &ChatMessage) -> bool {
+        self.channel
+            .as_ref()
+            .is_none_or(|channel| *channel == message.channel)
+            && self
+                .author
+                .as_ref()
+                .is_none_or(|author| *author == message.author)
+            && self.since.is_none_or(|since| message.created_at >= since)
+            && self.until.is_none_or(|until| message.created_at < until)
+    }
+}
+
+// Lowercased words, the same way for query and messages
+pub fn terms(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+// Every word of the query must appear in the message, rank grows with the number of occurrences.
+// Returns None if message does not match.
+pub fn rank(query_terms: &[String], body: &str) -> Option<f64> {
+    let body_terms = terms(body);
+    let mut occurrences = 0;
+    for term in query_terms {
+        match body_terms.iter().filter(|word| *word == term).count() {
+            0 => return None,
+            count => occurrences += count,
+        }
+    }
+    Some(occurrences as f64 / (1 + body_terms.len()) as f64)
+}
+
+// SQLite FTS5 query matching all words of text, each quoted so words like NOT or NEAR
+// are not taken for FTS operators
+pub fn fts5_query(text: &str) -> String {
+    terms(text)
+        .iter()
+        .map(|term| format!("\"{}\"", term))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
@@ -0,0 +1,1346 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use chat_app::channel::{Channel, Channels, Rx, Tx, Visibility};
+use chat_app::config::{MAX_HISTORY_PAGE, MAX_SEARCH_PAGE};
+use chat_app::database::{AuthenticationToken, ChatStore};
+use chat_app::direct::{self, ConversationId};
+use chat_app::mentions;
+use chat_app::messages::{ChatMessage, MessageId, ServerMessage, UserMessage};
+use chat_app::moderation::{Sanction, SanctionKind};
+use chat_app::permissions::{Permission, Role};
+use chat_app::presence::{Presence, Status};
+use chat_app::search::SearchQuery;
+use chat_app::utils::{get_next_user_message, send_to, ChatError};
+
+use futures::SinkExt;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_util::codec::{Framed, LinesCodec};
+
+use anyhow::Result;
+
+// Channel the connection is in
+struct Membership<S: ChatStore> {
+    channel: Arc<Channel<S>>,
+    user_name: String,
+}
+
+// Single client connected to the main server port.
+// Requests and traffic of all joined channels share the socket,
+// messages from channel peers arrive through rx.
+pub struct Connection<S: ChatStore> {
+    addr: SocketAddr,
+    lines: Framed<TcpStream, LinesCodec>,
+    chat_db: Arc<S>,
+    channels: Arc<Channels<S>>,
+    presence: Arc<Presence>,
+    // who logged in on this connection, None if login failed
+    user_name: Option<String>,
+    tx: Tx,
+    rx: Rx,
+    memberships: BTreeMap<String, Membership<S>>,
+}
+
+// Authenticates user and serves their requests until they disconnect
+pub async fn handle_new_user<S: ChatStore>(
+    stream: TcpStream,
+    addr: SocketAddr,
+    chat_db: Arc<S>,
+    channels: Arc<Channels<S>>,
+    presence: Arc<Presence>,
+) -> Result<()> {
+    let mut lines = Framed::new(stream, LinesCodec::new());
+
+    let mut user_name = None;
+    match get_next_user_message(&mut lines).await {
+        Some(Ok(UserMessage::Connect { name, password })) => {
+            match chat_db.authenticate_user(&name, &password).await {
+                Ok(token) => {
+                    user_name = Some(token.user_name.clone());
+                    send_to(
+                        &mut lines,
+                        &ServerMessage::ConnectResponse {
+                            token: Some(token),
+                            error: None,
+                        },
+                    )
+                    .await?;
+                }
+                Err(err) => {
+                    send_to(
+                        &mut lines,
+                        &ServerMessage::ConnectResponse {
+                            token: None,
+                            error: Some(err.to_string()),
+                        },
+                    )
+                    .await?;
+                }
+            }
+        }
+        _ => {
+            tracing::error!(
+                "[MAIN_SERVER] Failed to get connect message. Client {} disconnected.",
+                addr
+            );
+            return Ok(());
+        }
+    }
+
+    let (tx, rx) = mpsc::unbounded_channel();
+    let mut connection = Connection {
+        addr,
+        lines,
+        chat_db,
+        channels,
+        presence,
+        user_name,
+        tx,
+        rx,
+        memberships: BTreeMap::new(),
+    };
+    if let Some(user_name) = &connection.user_name {
+        connection
+            .presence
+            .connect(user_name, addr, connection.tx.clone());
+    }
+    let result = match connection.send_unseen_direct_messages().await {
+        Ok(()) => connection.serve().await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = connection.leave_all_channels().await {
+        tracing::info!("[MAIN_SERVER] error leaving channels: {:?}", e);
+    }
+    if let Some(user_name) = &connection.user_name {
+        connection.presence.disconnect(user_name, addr);
+    }
+    result
+}
+
+impl<S: ChatStore> Connection<S> {
+    async fn serve(&mut self) -> Result<()> {
+        loop {
+            tokio::select! {
+                Some(channel_member_message) = self.rx.recv() => {
+                    self.lines.send(&channel_member_message).await?;
+                }
+                user_message = get_next_user_message(&mut self.lines) => match user_message {
+                    Some(Ok(message)) => {
+                        if let Some(user_name) = &self.user_name {
+                            self.presence.touch(user_name);
+                        }
+                        if !self.handle_message(message).await? {
+                            return Ok(());
+                        }
+                    }
+                    _ => {
+                        tracing::info!("Client {:?} disconnected", self.addr);
+                        return Ok(());
+                    }
+                },
+            }
+        }
+    }
+
+    // Returns false when connection should be closed
+    async fn handle_message(&mut self, message: UserMessage) -> Result<bool> {
+        self.prune_memberships();
+        match message {
+            UserMessage::Join { token, channel } => {
+                self.authorize_connection(&token).await?;
+                self.join_channel(&token.user_name, &channel).await?;
+            }
+            UserMessage::Leave { token, channel } => {
+                self.authorize_connection(&token).await?;
+                if self.leave_channel(&channel).await? {
+                    self.send(&ServerMessage::Left { channel }).await?;
+                } else {
+                    self.send_error(&ChatError::NotInChannel).await?;
+                }
+            }
+            UserMessage::TextMessage {
+                token,
+                channel,
+                content,
+            } => {
+                self.authorize_connection(&token).await?;
+                self.post_message(&token.user_name, &channel, None, &content)
+                    .await?;
+            }
+            UserMessage::Reply {
+                token,
+                channel,
+                parent_id,
+                content,
+            } => {
+                self.authorize_connection(&token).await?;
+                self.post_message(&token.user_name, &channel, Some(parent_id), &content)
+                    .await?;
+            }
+            UserMessage::EditMessage {
+                token,
+                channel,
+                message_id,
+                content,
+            } => {
+                self.authorize_connection(&token).await?;
+                let edited = match self.memberships.get(&channel) {
+                    Some(membership) => {
+                        let channel = Arc::clone(&membership.channel);
+                        let may_moderate = self.may_moderate(&token.user_name, &channel).await?;
+                        channel
+                            .edit_message(&token.user_name, message_id, &content, may_moderate)
+                            .await
+                    }
+                    None => Err(ChatError::NotInChannel),
+                };
+                self.report_channel_error(edited).await?;
+            }
+            UserMessage::DeleteMessage {
+                token,
+                channel,
+                message_id,
+            } => {
+                self.authorize_connection(&token).await?;
+                let deleted = match self.memberships.get(&channel) {
+                    Some(membership) => {
+                        let channel = Arc::clone(&membership.channel);
+                        let may_moderate = self.may_moderate(&token.user_name, &channel).await?;
+                        channel
+                            .delete_message(&token.user_name, message_id, may_moderate)
+                            .await
+                    }
+                    None => Err(ChatError::NotInChannel),
+                };
+                self.report_channel_error(deleted).await?;
+            }
+            UserMessage::PinMessage {
+                token,
+                channel,
+                message_id,
+            } => {
+                self.authorize_connection(&token).await?;
+                let pinned = match self.memberships.get(&channel) {
+                    Some(membership) => {
+                        let channel = Arc::clone(&membership.channel);
+                        let may_moderate = self.may_moderate(&token.user_name, &channel).await?;
+                        channel
+                            .pin(&token.user_name, message_id, true, may_moderate)
+                            .await
+                    }
+                    None => Err(ChatError::NotInChannel),
+                };
+                self.report_channel_error(pinned).await?;
+            }
+            UserMessage::UnpinMessage {
+                token,
+                channel,
+                message_id,
+            } => {
+                self.authorize_connection(&token).await?;
+                let unpinned = match self.memberships.get(&channel) {
+                    Some(membership) => {
+                        let channel = Arc::clone(&membership.channel);
+                        let may_moderate = self.may_moderate(&token.user_name, &channel).await?;
+                        channel
+                            .pin(&token.user_name, message_id, false, may_moderate)
+                            .await
+                    }
+                    None => Err(ChatError::NotInChannel),
+                };
+                self.report_channel_error(unpinned).await?;
+            }
+            UserMessage::AddReaction {
+                token,
+                channel,
+                message_id,
+                reaction,
+            } => {
+                self.authorize_connection(&token).await?;
+                let added = match self.memberships.get(&channel) {
+                    Some(membership) => {
+                        membership
+                            .channel
+                            .react(&token.user_name, message_id, &reaction, true)
+                            .await
+                    }
+                    None => Err(ChatError::NotInChannel),
+                };
+                self.report_channel_error(added).await?;
+            }
+            UserMessage::RemoveReaction {
+                token,
+                channel,
+                message_id,
+                reaction,
+            } => {
+                self.authorize_connection(&token).await?;
+                let removed = match self.memberships.get(&channel) {
+                    Some(membership) => {
+                        membership
+                            .channel
+                            .react(&token.user_name, message_id, &reaction, false)
+                            .await
+                    }
+                    None => Err(ChatError::NotInChannel),
+                };
+                self.report_channel_error(removed).await?;
+            }
+            UserMessage::FetchRevisions {
+                token,
+                channel,
+                message_id,
+            } => {
+                self.authorize_connection(&token).await?;
+                self.fetch_revisions(&token.user_name, channel, message_id)
+                    .await?;
+            }
+            UserMessage::CreateChannel {
+                token,
+                name,
+                visibility,
+            } => {
+                if !self
+                    .authorize_request(&token, Permission::CreateChannel)
+                    .await?
+                {
+                    return Ok(true);
+                }
+                let content = match self
+                    .channels
+                    .create(&name, &token.user_name, visibility)
+                    .await
+                {
+                    Ok(()) => format!("Successfully created channel {}", name),
+                    Err(e) => format!("{:?}", e),
+                };
+                self.send(&ServerMessage::TextMessage { content }).await?;
+            }
+            UserMessage::RenameChannel {
+                token,
+                channel,
+                new_name,
+            } => {
+                if !self
+                    .authorize_request(&token, Permission::ManageChannels)
+                    .await?
+                {
+                    return Ok(true);
+                }
+                let result = self.channels.rename(&channel, &new_name).await;
+                self.confirm(
+                    result,
+                    format!("Renamed channel {} to {}", channel, new_name),
+                )
+                .await?;
+            }
+            UserMessage::ArchiveChannel { token, channel } => {
+                if !self
+                    .authorize_request(&token, Permission::ManageChannels)
+                    .await?
+                {
+                    return Ok(true);
+                }
+                let result = self.channels.archive(&channel).await;
+                self.confirm(result, format!("Archived channel {}", channel))
+                    .await?;
+            }
+            UserMessage::DeleteChannel { token, channel } => {
+                if !self
+                    .authorize_request(&token, Permission::ManageChannels)
+                    .await?
+                {
+                    return Ok(true);
+                }
+                let result = self.channels.delete(&channel).await;
+                self.confirm(result, format!("Deleted channel {}", channel))
+                    .await?;
+            }
+            UserMessage::SetTopic {
+                token,
+                channel,
+                topic,
+                description,
+            } => {
+                self.authorize_connection(&token).await?;
+                self.set_topic(&token.user_name, &channel, topic, description)
+                    .await?;
+            }
+            UserMessage::InviteToChannel {
+                token,
+                channel,
+                user_name,
+            } => {
+                self.authorize_connection(&token).await?;
+                self.invite_to_channel(&token.user_name, &channel, &user_name)
+                    .await?;
+            }
+            UserMessage::ListInvitations { token } => {
+                self.authorize_connection(&token).await?;
+                let invitations = self.chat_db.list_invitations(&token.user_name).await?;
+                self.send(&ServerMessage::Invitations { invitations })
+                    .await?;
+            }
+            UserMessage::AcceptInvitation { token, channel } => {
+                self.authorize_connection(&token).await?;
+                self.answer_invitation(&token.user_name, channel, true)
+                    .await?;
+            }
+            UserMessage::DeclineInvitation { token, channel } => {
+                self.authorize_connection(&token).await?;
+                self.answer_invitation(&token.user_name, channel, false)
+                    .await?;
+            }
+            UserMessage::Kick {
+                token,
+                channel,
+                user_name,
+                reason,
+            } => {
+                self.authorize_connection(&token).await?;
+                self.sanction(
+                    SanctionKind::Kick,
+                    &token.user_name,
+                    &user_name,
+                    Some(channel),
+                    &reason,
+                    None,
+                )
+                .await?;
+            }
+            UserMessage::Mute {
+                token,
+                channel,
+                user_name,
+                reason,
+                duration_secs,
+            } => {
+                self.authorize_connection(&token).await?;
+                self.sanction(
+                    SanctionKind::Mute,
+                    &token.user_name,
+                    &user_name,
+                    Some(channel),
+                    &reason,
+                    Some(duration_secs),
+                )
+                .await?;
+            }
+            UserMessage::Ban {
+                token,
+                channel,
+                user_name,
+                reason,
+                duration_secs,
+            } => {
+                self.authorize_connection(&token).await?;
+                self.sanction(
+                    SanctionKind::Ban,
+                    &token.user_name,
+                    &user_name,
+                    channel,
+                    &reason,
+                    duration_secs,
+                )
+                .await?;
+            }
+            UserMessage::CreateUser {
+                token,
+                name,
+                password,
+            } => {
+                if !self
+                    .authorize_request(&token, Permission::CreateUser)
+                    .await?
+                {
+                    return Ok(true);
+                }
+                let content = match self.chat_db.create_user(&name, &password).await {
+                    Ok(()) => {
+                        format!(
+                            "Successfully created new user {} with password {}",
+                            name, password
+                        )
+                    }
+                    Err(e) => format!("{:?}", e),
+                };
+                self.send(&ServerMessage::TextMessage { content }).await?;
+            }
+            UserMessage::MarkRead {
+                token,
+                channel,
+                message_id,
+            } => {
+                self.authorize_connection(&token).await?;
+                if self
+                    .visible_channel(&token.user_name, &channel)
+                    .await?
+                    .is_none()
+                {
+                    return Ok(true);
+                }
+                if let Err(e) = self
+                    .chat_db
+                    .mark_read(&channel, &token.user_name, message_id)
+                    .await
+                {
+                    self.send_error(&e).await?;
+                }
+            }
+            UserMessage::FetchHistory {
+                token,
+                channel,
+                before_id,
+                limit,
+            } => {
+                self.authorize_connection(&token).await?;
+                self.fetch_history(&token.user_name, channel, before_id, limit)
+                    .await?;
+            }
+            UserMessage::FetchThread {
+                token,
+                channel,
+                parent_id,
+                before_id,
+                limit,
+            } => {
+                self.authorize_connection(&token).await?;
+                self.fetch_thread(&token.user_name, channel, parent_id, before_id, limit)
+                    .await?;
+            }
+            UserMessage::MarkThreadRead {
+                token,
+                channel,
+                parent_id,
+                message_id,
+            } => {
+                self.authorize_connection(&token).await?;
+                if self
+                    .visible_channel(&token.user_name, &channel)
+                    .await?
+                    .is_none()
+                {
+                    return Ok(true);
+                }
+                if let Err(e) = self
+                    .chat_db
+                    .mark_thread_read(&channel, &token.user_name, parent_id, message_id)
+                    .await
+                {
+                    self.send_error(&e).await?;
+                }
+            }
+            UserMessage::ListThreads { token, channel } => {
+                self.authorize_connection(&token).await?;
+                if self
+                    .visible_channel(&token.user_name, &channel)
+                    .await?
+                    .is_none()
+                {
+                    return Ok(true);
+                }
+                let threads = self
+                    .chat_db
+                    .list_threads(&channel, &token.user_name)
+                    .await?;
+                self.send(&ServerMessage::Threads { channel, threads })
+                    .await?;
+            }
+            UserMessage::ListPins { token, channel } => {
+                self.authorize_connection(&token).await?;
+                let pins = match self.visible_channel(&token.user_name, &channel).await? {
+                    Some(visible) => visible.pins().await?,
+                    None => return Ok(true),
+                };
+                self.send(&ServerMessage::Pins { channel, pins }).await?;
+            }
+            UserMessage::ListMentions {
+                token,
+                unread_only,
+                before_id,
+                limit,
+            } => {
+                self.authorize_connection(&token).await?;
+                self.list_mentions(&token.user_name, unread_only, before_id, limit)
+                    .await?;
+            }
+            UserMessage::MarkMentionsRead { token, message_id } => {
+                self.authorize_connection(&token).await?;
+                if let Err(e) = self
+                    .chat_db
+                    .mark_mentions_read(&token.user_name, message_id)
+                    .await
+                {
+                    self.send_error(&e).await?;
+                }
+            }
+            UserMessage::Search { token, query } => {
+                self.authorize_connection(&token).await?;
+                self.search(&token.user_name, query).await?;
+            }
+            UserMessage::ListMembers { token, channel } => {
+                self.authorize_connection(&token).await?;
+                if let Some(found) = self.visible_channel(&token.user_name, &channel).await? {
+                    let members = found
+                        .member_names()
+                        .iter()
+                        .map(|user_name| self.presence.member_info(user_name))
+                        .collect();
+                    self.send(&ServerMessage::Members { channel, members })
+                        .await?;
+                }
+            }
+            UserMessage::SetStatus { token, status } => {
+                self.authorize_connection(&token).await?;
+                let away = match status {
+                    Status::Online => false,
+                    Status::Away => true,
+                    Status::Offline => {
+                        self.send_error(&ChatError::InvalidMessage).await?;
+                        return Ok(true);
+                    }
+                };
+                if let Some(status) = self.presence.set_away(&token.user_name, away) {
+                    self.channels
+                        .notify_status(&token.user_name, status)
+                        .await?;
+                }
+            }
+            UserMessage::OpenConversation { token, members } => {
+                self.authorize_connection(&token).await?;
+                let opened = match direct::conversation_members(&token.user_name, &members) {
+                    Ok(members) => self
+                        .chat_db
+                        .open_conversation(&members)
+                        .await
+                        .map(|id| ServerMessage::ConversationOpened { id, members }),
+                    Err(e) => Err(e),
+                };
+                match opened {
+                    Ok(message) => self.send(&message).await?,
+                    Err(e) => self.send_error(&e).await?,
+                }
+            }
+            UserMessage::DirectMessage {
+                token,
+                conversation_id,
+                content,
+            } => {
+                self.authorize_connection(&token).await?;
+                if let Err(e) = self
+                    .send_direct_message(&token.user_name, conversation_id, &content)
+                    .await
+                {
+                    self.send_error(&e).await?;
+                }
+            }
+            UserMessage::MarkConversationRead {
+                token,
+                conversation_id,
+                message_id,
+            } => {
+                self.authorize_connection(&token).await?;
+                if let Err(e) = self
+                    .chat_db
+                    .mark_conversation_read(conversation_id, &token.user_name, message_id)
+                    .await
+                {
+                    self.send_error(&e).await?;
+                }
+            }
+            UserMessage::ListConversations { token } => {
+                self.authorize_connection(&token).await?;
+                let conversations = self.chat_db.list_conversations(&token.user_name).await?;
+                self.send(&ServerMessage::Conversations { conversations })
+                    .await?;
+            }
+            UserMessage::GetChannels { token } => {
+                self.authorize_connection(&token).await?;
+                let channels_info_message = ServerMessage::ChannelsInfo {
+                    channels: self.channels.infos_for(&token.user_name).await?,
+                };
+                self.send(&channels_info_message).await?;
+            }
+            UserMessage::ListSessions { token } => {
+                self.authorize_connection(&token).await?;
+                let sessions = self.chat_db.list_sessions(&token.user_name).await?;
+                self.send(&ServerMessage::Sessions { sessions }).await?;
+            }
+            UserMessage::RevokeSession { token, session_id } => {
+                self.authorize_connection(&token).await?;
+                let content = match self
+                    .chat_db
+                    .revoke_session(&token.user_name, &session_id)
+                    .await
+                {
+                    Ok(true) => format!("Revoked session {}", session_id),
+                    Ok(false) => format!("No session {}", session_id),
+                    Err(e) => format!("{:?}", e),
+                };
+                self.send(&ServerMessage::TextMessage { content }).await?;
+            }
+            UserMessage::GrantRole {
+                token,
+                user_name,
+                role,
+            } => {
+                if !self
+                    .authorize_request(&token, Permission::ManageRoles)
+                    .await?
+                {
+                    return Ok(true);
+                }
+                let content = self.change_role(&token, &user_name, None, role).await;
+                self.send(&ServerMessage::TextMessage { content }).await?;
+            }
+            UserMessage::RevokeRole {
+                token,
+                user_name,
+                role,
+            } => {
+                if !self
+                    .authorize_request(&token, Permission::ManageRoles)
+                    .await?
+                {
+                    return Ok(true);
+                }
+                let content = self
+                    .change_role(&token, &user_name, Some(role), Role::Member)
+                    .await;
+                self.send(&ServerMessage::TextMessage { content }).await?;
+            }
+            UserMessage::Logout { token } => {
+                self.authorize_connection(&token).await?;
+                self.chat_db.logout(&token).await?;
+                tracing::info!("[MAIN_SERVER] {} logged out", token.user_name);
+                self.send(&ServerMessage::TextMessage {
+                    content: "Logged out".to_string(),
+                })
+                .await?;
+                return Ok(false);
+            }
+            UserMessage::Connect { .. } => tracing::debug!("Unimpleneted"),
+        }
+        Ok(true)
+    }
+
+    async fn send(&mut self, message: &ServerMessage) -> Result<()> {
+        send_to(&mut self.lines, message).await
+    }
+
+    async fn send_error(&mut self, error: &ChatError) -> Result<()> {
+        self.send(&ServerMessage::error(error)).await
+    }
+
+    // Answers request with content if it succeeded, with its error otherwise
+    async fn confirm(&mut self, result: Result<(), ChatError>, content: String) -> Result<()> {
+        match result {
+            Ok(()) => self.send(&ServerMessage::TextMessage { content }).await,
+            Err(e) => self.send_error(&e).await,
+        }
+    }
+
+    // Errors of requests made in a joined channel are answered, anything else ends the connection.
+    // Channel could have been archived or closed since memberships were pruned.
+    async fn report_channel_error(&mut self, result: Result<(), ChatError>) -> Result<()> {
+        match result {
+            Ok(()) => Ok(()),
+            Err(
+                e @ (ChatError::NotInChannel
+                | ChatError::InvalidMessage
+                | ChatError::NoSuchChannel
+                | ChatError::ChannelArchived
+                | ChatError::Muted { .. }
+                | ChatError::NoSuchMessage
+                | ChatError::Forbidden),
+            ) => self.send_error(&e).await,
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn authorize_connection(&mut self, token: &AuthenticationToken) -> Result<()> {
+        match self.chat_db.authorize_connection(token).await {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                tracing::debug!("Unauthorized connection! Disconnecting: {}", e);
+                self.send_error(&e).await?;
+                Err(anyhow::Error::new(e))
+            }
+        }
+    }
+
+    // Like authorize_connection, but also checks that token owner may do what they requested.
+    // Forbidden requests are answered with an error and connection stays open, then false is returned.
+    async fn authorize_request(
+        &mut self,
+        token: &AuthenticationToken,
+        permission: Permission,
+    ) -> Result<bool> {
+        self.authorize_connection(token).await?;
+        match self
+            .chat_db
+            .check_permission(&token.user_name, permission)
+            .await
+        {
+            Ok(()) => Ok(true),
+            Err(ChatError::Forbidden) => {
+                tracing::info!(
+                    "[MAIN_SERVER] {} is not allowed to {:?}",
+                    token.user_name,
+                    permission
+                );
+                self.send_error(&ChatError::Forbidden).await?;
+                Ok(false)
+            }
+            Err(e) => {
+                self.send_error(&e).await?;
+                Err(anyhow::Error::new(e))
+            }
+        }
+    }
+
+    // Channel if it exists and user may see it, otherwise NoSuchChannel is sent and None returned
+    async fn visible_channel(
+        &mut self,
+        user_name: &str,
+        channel_name: &str,
+    ) -> Result<Option<Arc<Channel<S>>>> {
+        if let Some(channel) = self.channels.get(channel_name) {
+            if channel.is_visible_to(user_name).await? {
+                return Ok(Some(channel));
+            }
+        }
+        self.send_error(&ChatError::NoSuchChannel).await?;
+        Ok(None)
+    }
+
+    // Joining channel connection is already in only confirms it, without replaying messages.
+    // Joined goes straight to the socket, so it comes before pins and unseen messages queued by the channel.
+    async fn join_channel(&mut self, user_name: &str, channel_name: &str) -> Result<()> {
+        let channel = match self.channels.get(channel_name) {
+            Some(channel) => channel,
+            None => return self.send_error(&ChatError::NoSuchChannel).await,
+        };
+
+        let joined = ServerMessage::Joined {
+            channel: channel_name.to_string(),
+            topic: channel.topic(),
+        };
+        if self.memberships.contains_key(channel_name) {
+            return self.send(&joined).await;
+        }
+        match channel.join(user_name, self.addr, self.tx.clone()).await {
+            Ok(()) => {}
+            Err(
+                e @ (ChatError::NoSuchChannel
+                | ChatError::ChannelArchived
+                | ChatError::Banned { .. }),
+            ) => return self.send_error(&e).await,
+            Err(e) => return Err(e.into()),
+        }
+        self.send(&joined).await?;
+        self.memberships.insert(
+            channel_name.to_string(),
+            Membership {
+                channel,
+                user_name: user_name.to_string(),
+            },
+        );
+        Ok(())
+    }
+
+    // Returns false if connection was not in the channel.
+    // Messages of the channel already queued in rx are still delivered, client drops them by channel name.
+    async fn leave_channel(&mut self, channel_name: &str) -> Result<bool> {
+        match self.memberships.remove(channel_name) {
+            Some(membership) => {
+                membership
+                    .channel
+                    .leave(&membership.user_name, self.addr)
+                    .await?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    // Forgets channels which disconnected this connection on their own,
+    // they already told the client why
+    fn prune_memberships(&mut self) {
+        let addr = self.addr;
+        self.memberships
+            .retain(|_, membership| membership.channel.has_peer(addr));
+    }
+
+    async fn leave_all_channels(&mut self) -> Result<()> {
+        for (_, membership) in std::mem::take(&mut self.memberships) {
+            membership
+                .channel
+                .leave(&membership.user_name, self.addr)
+                .await?;
+        }
+        Ok(())
+    }
+
+    // Unread direct messages are queued before anything else, right after login
+    async fn send_unseen_direct_messages(&mut self) -> Result<()> {
+        let user_name = match &self.user_name {
+            Some(user_name) => user_name,
+            None => return Ok(()),
+        };
+        let unseen_messages = self.chat_db.get_unseen_direct_messages(user_name).await?;
+        for message in unseen_messages {
+            self.tx
+                .send(serde_json::to_string(&ServerMessage::DirectMessage {
+                    message,
+                })?)?;
+        }
+        Ok(())
+    }
+
+    // Delivered to every connection of every member, sender included.
+    // Errors are for the sender to see, they do not end the connection.
+    async fn send_direct_message(
+        &self,
+        user_name: &str,
+        conversation_id: ConversationId,
+        content: &str,
+    ) -> Result<(), ChatError> {
+        let members = self
+            .chat_db
+            .get_conversation_members(conversation_id)
+            .await?
+            .ok_or(ChatError::NoSuchConversation)?;
+        if !members.iter().any(|member| member == user_name) {
+            return Err(ChatError::Forbidden);
+        }
+        let message = self
+            .chat_db
+            .save_direct_message(conversation_id, user_name, content)
+            .await?;
+        let message = serde_json::to_string(&ServerMessage::DirectMessage { message })
+            .map_err(|_| ChatError::RuntimeError)?;
+        for member in members.iter() {
+            self.presence.deliver(member, &message);
+        }
+        Ok(())
+    }
+
+    // Message or reply to parent_id in a joined channel, mentioned users are notified once it is sent
+    async fn post_message(
+        &mut self,
+        user_name: &str,
+        channel_name: &str,
+        parent_id: Option<MessageId>,
+        content: &str,
+    ) -> Result<()> {
+        let channel = match self.memberships.get(channel_name) {
+            Some(membership) => Arc::clone(&membership.channel),
+            None => {
+                return self
+                    .report_channel_error(Err(ChatError::NotInChannel))
+                    .await
+            }
+        };
+        let sent = match parent_id {
+            Some(parent_id) => channel.reply(user_name, parent_id, content).await,
+            None => channel.send_message(user_name, content).await,
+        };
+        match sent {
+            Ok(message) => self.notify_mentions(&channel, &message).await,
+            Err(e) => self.report_channel_error(Err(e)).await,
+        }
+    }
+
+    // @here and @channel reach everybody in the channel right now, @channel also everybody
+    // who has read it before. Only mentions written when the message is posted count.
+    async fn notify_mentions(&self, channel: &Channel<S>, message: &ChatMessage) -> Result<()> {
+        let mentions = mentions::parse(&message.body);
+        if mentions.is_empty() {
+            return Ok(());
+        }
+        let mut user_names: Vec<String> = mentions.users.into_iter().collect();
+        if mentions.here || mentions.channel {
+            user_names.extend(channel.member_names());
+        }
+        let mentioned = self
+            .chat_db
+            .save_mentions(message.id, &user_names, mentions.channel)
+            .await?;
+        let notification = serde_json::to_string(&ServerMessage::Mentioned {
+            message: message.clone(),
+        })?;
+        for user_name in mentioned.iter() {
+            self.presence.deliver(user_name, &notification);
+        }
+        Ok(())
+    }
+
+    async fn has_permission(&self, user_name: &str, permission: Permission) -> Result<bool> {
+        match self.chat_db.check_permission(user_name, permission).await {
+            Ok(()) => Ok(true),
+            Err(ChatError::Forbidden) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Owners can moderate their channel, moderators and admins every channel
+    async fn may_moderate(&self, user_name: &str, channel: &Channel<S>) -> Result<bool> {
+        if channel.is_owner(user_name) {
+            return Ok(true);
+        }
+        self.has_permission(user_name, Permission::ModerateChannels)
+            .await
+    }
+
+    // Records sanction, tells the user about it and disconnects them where it applies.
+    // Whoever could moderate the channel themselves cannot be sanctioned in it,
+    // server wide bans are up to admins.
+    async fn sanction(
+        &mut self,
+        kind: SanctionKind,
+        issued_by: &str,
+        user_name: &str,
+        channel_name: Option<String>,
+        reason: &str,
+        duration_secs: Option<u64>,
+    ) -> Result<()> {
+        // server wide moderators reach private channels they are not members of
+        let channel = match &channel_name {
+            Some(channel_name) => {
+                let moderated = match self.channels.get(channel_name) {
+                    Some(channel)
+                        if self
+                            .has_permission(issued_by, Permission::ModerateChannels)
+                            .await? =>
+                    {
+                        Some(channel)
+                    }
+                    _ => self.visible_channel(issued_by, channel_name).await?,
+                };
+                match moderated {
+                    Some(channel) => Some(channel),
+                    None => return Ok(()),
+                }
+            }
+            None => None,
+        };
+        let allowed = match &channel {
+            Some(channel) => self.may_moderate(issued_by, channel).await?,
+            None => self.has_permission(issued_by, Permission::BanUsers).await?,
+        };
+        let protected = match self.chat_db.get_role(user_name).await? {
+            Some(role) => {
+                role.allows(Permission::ModerateChannels)
+                    || channel
+                        .as_ref()
+                        .is_some_and(|channel| channel.is_owner(user_name))
+            }
+            None => return self.send_error(&ChatError::NoSuchUser).await,
+        };
+        if !allowed || protected || user_name == issued_by {
+            return self.send_error(&ChatError::Forbidden).await;
+        }
+        let sanction = match Sanction::new(
+            kind,
+            user_name,
+            channel_name,
+            issued_by,
+            reason,
+            duration_secs,
+        ) {
+            Ok(sanction) => sanction,
+            Err(e) => return self.send_error(&e).await,
+        };
+        if let Err(e) = self.chat_db.add_sanction(&sanction).await {
+            return self.send_error(&e).await;
+        }
+        let place = match &sanction.channel {
+            Some(channel_name) => channel_name.clone(),
+            None => "the server".to_string(),
+        };
+        tracing::info!(
+            "[MAIN_SERVER] {} issued {} against {} in {}",
+            issued_by,
+            kind,
+            user_name,
+            place
+        );
+
+        let sanctioned = serde_json::to_string(&ServerMessage::Sanctioned { sanction })?;
+        self.presence.deliver(user_name, &sanctioned);
+        match (kind, channel) {
+            (SanctionKind::Mute, _) => {}
+            (_, Some(channel)) => {
+                channel.kick(user_name).await?;
+            }
+            (_, None) => {
+                self.channels.kick_everywhere(user_name).await?;
+                for session in self.chat_db.list_sessions(user_name).await? {
+                    self.chat_db.revoke_session(user_name, &session.id).await?;
+                }
+            }
+        }
+        let content = match kind {
+            SanctionKind::Kick => format!("Kicked {} from {}", user_name, place),
+            SanctionKind::Mute => format!("Muted {} in {}", user_name, place),
+            SanctionKind::Ban => format!("Banned {} from {}", user_name, place),
+        };
+        self.send(&ServerMessage::TextMessage { content }).await
+    }
+
+    async fn set_topic(
+        &mut self,
+        user_name: &str,
+        channel_name: &str,
+        topic: Option<String>,
+        description: Option<String>,
+    ) -> Result<()> {
+        let channel = match self.visible_channel(user_name, channel_name).await? {
+            Some(channel) => channel,
+            None => return Ok(()),
+        };
+        if !self.may_moderate(user_name, &channel).await? {
+            return self.send_error(&ChatError::Forbidden).await;
+        }
+        let result = channel.set_topic(user_name, topic, description).await;
+        self.confirm(result, format!("Changed topic of {}", channel_name))
+            .await
+    }
+
+    // Only owner of a private channel can invite, invited user is told right away if online
+    async fn invite_to_channel(
+        &mut self,
+        owner: &str,
+        channel_name: &str,
+        user_name: &str,
+    ) -> Result<()> {
+        let channel = match self.visible_channel(owner, channel_name).await? {
+            Some(channel) => channel,
+            None => return Ok(()),
+        };
+        if channel.visibility() != Visibility::Private {
+            return self.send_error(&ChatError::InvalidMessage).await;
+        }
+        if !channel.is_owner(owner) {
+            return self.send_error(&ChatError::Forbidden).await;
+        }
+        match self
+            .chat_db
+            .invite_to_channel(channel_name, user_name, owner)
+            .await
+        {
+            Ok(invitation) => {
+                tracing::info!(
+                    "[MAIN_SERVER] {} invited {} to {}",
+                    owner,
+                    user_name,
+                    channel_name
+                );
+                let invited = serde_json::to_string(&ServerMessage::Invited { invitation })?;
+                self.presence.deliver(user_name, &invited);
+                self.send(&ServerMessage::TextMessage {
+                    content: format!("Invited {} to {}", user_name, channel_name),
+                })
+                .await
+            }
+            Err(e) => self.send_error(&e).await,
+        }
+    }
+
+    async fn answer_invitation(
+        &mut self,
+        user_name: &str,
+        channel: String,
+        accept: bool,
+    ) -> Result<()> {
+        match self
+            .chat_db
+            .answer_invitation(&channel, user_name, accept)
+            .await
+        {
+            Ok(()) if accept => {
+                self.send(&ServerMessage::TextMessage {
+                    content: format!("You are now a member of {}", channel),
+                })
+                .await
+            }
+            Ok(()) => {
+                self.send(&ServerMessage::TextMessage {
+                    content: format!("Declined invitation to {}", channel),
+                })
+                .await
+            }
+            Err(e) => self.send_error(&e).await,
+        }
+    }
+
+    async fn fetch_history(
+        &mut self,
+        user_name: &str,
+        channel: String,
+        before_id: Option<MessageId>,
+        limit: u32,
+    ) -> Result<()> {
+        if self.visible_channel(user_name, &channel).await?.is_none() {
+            return Ok(());
+        }
+        let limit = limit.min(MAX_HISTORY_PAGE);
+        // one extra message tells whether there is anything beyond this page
+        let mut messages = self
+            .chat_db
+            .get_messages_before(&channel, None, before_id, limit + 1)
+            .await?;
+        let has_more = messages.len() > limit as usize;
+        if has_more {
+            messages.remove(0);
+        }
+        self.send(&ServerMessage::History {
+            channel,
+            messages,
+            has_more,
+        })
+        .await
+    }
+
+    async fn list_mentions(
+        &mut self,
+        user_name: &str,
+        unread_only: bool,
+        before_id: Option<MessageId>,
+        limit: u32,
+    ) -> Result<()> {
+        let limit = limit.min(MAX_HISTORY_PAGE);
+        // one extra mention tells whether there is anything beyond this page
+        let mut mentions = self
+            .chat_db
+            .list_mentions(user_name, unread_only, before_id, limit + 1)
+            .await?;
+        let has_more = mentions.len() > limit as usize;
+        if has_more {
+            mentions.remove(0);
+        }
+        self.send(&ServerMessage::Mentions { mentions, has_more })
+            .await
+    }
+
+    // Asking for the thread of a reply gives the whole thread it belongs to
+    async fn fetch_thread(
+        &mut self,
+        user_name: &str,
+        channel: String,
+        parent_id: MessageId,
+        before_id: Option<MessageId>,
+        limit: u32,
+    ) -> Result<()> {
+        if self.visible_channel(user_name, &channel).await?.is_none() {
+            return Ok(());
+        }
+        let parent = match self.chat_db.get_message(&channel, parent_id).await? {
+            Some(ChatMessage {
+                parent_id: Some(thread_id),
+                ..
+            }) => self.chat_db.get_message(&channel, thread_id).await?,
+            message => message,
+        };
+        let parent = match parent {
+            Some(parent) => parent,
+            None => return self.send_error(&ChatError::NoSuchMessage).await,
+        };
+        let limit = limit.min(MAX_HISTORY_PAGE);
+        // one extra reply tells whether there is anything beyond this page
+        let mut replies = self
+            .chat_db
+            .get_messages_before(&channel, Some(parent.id), before_id, limit + 1)
+            .await?;
+        let has_more = replies.len() > limit as usize;
+        if has_more {
+            replies.remove(0);
+        }
+        self.send(&ServerMessage::Thread {
+            channel,
+            parent,
+            replies,
+            has_more,
+        })
+        .await
+    }
+
+    // Deleted messages keep their revisions, so moderators can still see what was removed
+    async fn fetch_revisions(
+        &mut self,
+        user_name: &str,
+        channel_name: String,
+        message_id: MessageId,
+    ) -> Result<()> {
+        let channel = match self.visible_channel(user_name, &channel_name).await? {
+            Some(channel) => channel,
+            None => return Ok(()),
+        };
+        let message = match self.chat_db.get_message(&channel_name, message_id).await? {
+            Some(message) => message,
+            None => return self.send_error(&ChatError::NoSuchMessage).await,
+        };
+        if message.author != user_name && !self.may_moderate(user_name, &channel).await? {
+            return self.send_error(&ChatError::Forbidden).await;
+        }
+        let revisions = self.chat_db.get_message_revisions(message_id).await?;
+        self.send(&ServerMessage::Revisions {
+            channel: channel_name,
+            message_id,
+            revisions,
+        })
+        .await
+    }
+
+    async fn search(&mut self, user_name: &str, mut query: SearchQuery) -> Result<()> {
+        let limit = query.limit.min(MAX_SEARCH_PAGE);
+        // one extra hit tells whether there is a next page
+        query.limit = limit + 1;
+        let mut hits = self.chat_db.search_messages(&query, user_name).await?;
+        let has_more = hits.len() > limit as usize;
+        hits.truncate(limit as usize);
+        self.send(&ServerMessage::SearchResults { hits, has_more })
+            .await
+    }
+
+    // Sets user's role, if expected is given only when user currently has that role.
+    // Returns description of what happened for the requester.
+    async fn change_role(
+        &self,
+        token: &AuthenticationToken,
+        user_name: &str,
+        expected: Option<Role>,
+        role: Role,
+    ) -> String {
+        if user_name == token.user_name {
+            return "You cannot change your own role".to_string();
+        }
+        match self.chat_db.get_role(user_name).await {
+            Ok(Some(current)) if expected.is_some_and(|expected| expected != current) => {
+                format!(
+                    "User {} is {}, not {}",
+                    user_name,
+                    current,
+                    expected.unwrap()
+                )
+            }
+            Ok(Some(_)) => match self.chat_db.set_role(user_name, role).await {
+                Ok(_) => {
+                    tracing::info!(
+                        "[MAIN_SERVER] {} set role of {} to {}",
+                        token.user_name,
+                        user_name,
+                        role
+                    );
+                    format!("User {} is now {}", user_name, role)
+                }
+                Err(e) => format!("{:?}", e),
+            },
+            Ok(None) => format!("No user {}", user_name),
+            Err(e) => format!("{:?}", e),
+        }
+    }
+}
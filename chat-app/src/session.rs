@@ -0,0 +1,158 @@
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::config::{SESSION_ABSOLUTE_TIMEOUT, SESSION_IDLE_TIMEOUT};
+use crate::database::AuthenticationToken;
+use crate::utils::{unix_timestamp, ChatError};
+
+pub type SessionId = String;
+
+fn random_string(len: usize) -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+// Only hash of the cookie is kept by the server, so leaked session storage cannot be replayed
+pub fn hash_cookie(cookie: &str) -> String {
+    format!("{:x}", Sha256::digest(cookie.as_bytes()))
+}
+
+// Single login of a user, one user can have many of them at the same time
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub id: SessionId,
+    pub user_name: String,
+    pub cookie_hash: String,
+    pub issued_at: i64,
+    pub last_used_at: i64,
+}
+
+impl Session {
+    // New session together with the token handed to its owner
+    pub fn new(user_name: &str) -> (Session, AuthenticationToken) {
+        let now = unix_timestamp();
+        let cookie = random_string(32);
+        let session = Session {
+            id: random_string(16),
+            user_name: user_name.to_string(),
+            cookie_hash: hash_cookie(&cookie),
+            issued_at: now,
+            last_used_at: now,
+        };
+        let token = AuthenticationToken {
+            user_name: user_name.to_string(),
+            session_id: session.id.clone(),
+            cookie,
+        };
+        (session, token)
+    }
+
+    // Session dies after being unused for idle timeout or after absolute timeout since login,
+    // whichever comes first
+    pub fn expires_at(&self) -> i64 {
+        std::cmp::min(
+            self.last_used_at + SESSION_IDLE_TIMEOUT.as_secs() as i64,
+            self.issued_at + SESSION_ABSOLUTE_TIMEOUT.as_secs() as i64,
+        )
+    }
+
+    pub fn is_expired(&self, now: i64) -> bool {
+        now >= self.expires_at()
+    }
+
+    // Token matches this session and session is still alive
+    pub fn check(&self, token: &AuthenticationToken, now: i64) -> Result<(), ChatError> {
+        if self.user_name != token.user_name || self.cookie_hash != hash_cookie(&token.cookie) {
+            return Err(ChatError::UnauthenticatedConnection);
+        }
+        if self.is_expired(now) {
+            return Err(ChatError::SessionExpired);
+        }
+        Ok(())
+    }
+
+    pub fn info(&self) -> SessionInfo {
+        SessionInfo {
+            id: self.id.clone(),
+            issued_at: self.issued_at,
+            last_used_at: self.last_used_at,
+            expires_at: self.expires_at(),
+        }
+    }
+}
+
+// What user can see about their sessions, without the secret cookie
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SessionInfo {
+    pub id: SessionId,
+    pub issued_at: i64,
+    pub last_used_at: i64,
+    pub expires_at: i64,
+}
+
+// In process registry of live sessions, for stores without persistent storage
+#[derive(Debug, Default)]
+pub struct Sessions {
+    sessions: RwLock<HashMap<SessionId, Session>>,
+}
+
+impl Sessions {
+    pub fn create(&self, user_name: &str) -> AuthenticationToken {
+        let (session, token) = Session::new(user_name);
+        self.sessions
+            .write()
+            .unwrap()
+            .insert(session.id.clone(), session);
+        token
+    }
+
+    // Checks token and marks its session as used, expired sessions are dropped
+    pub fn validate(&self, token: &AuthenticationToken) -> Result<(), ChatError> {
+        let now = unix_timestamp();
+        let mut sessions = self.sessions.write().unwrap();
+        let session = sessions
+            .get_mut(&token.session_id)
+            .ok_or(ChatError::UnauthenticatedConnection)?;
+        if let Err(e) = session.check(token, now) {
+            if let ChatError::SessionExpired = e {
+                sessions.remove(&token.session_id);
+            }
+            return Err(e);
+        }
+        session.last_used_at = now;
+        Ok(())
+    }
+
+    // Returns false if user has no such session
+    pub fn revoke(&self, user_name: &str, session_id: &str) -> bool {
+        let mut sessions = self.sessions.write().unwrap();
+        match sessions.get(session_id) {
+            Some(session) if session.user_name == user_name => {
+                sessions.remove(session_id);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn list(&self, user_name: &str) -> Vec<SessionInfo> {
+        let now = unix_timestamp();
+        let mut sessions = self.sessions.write().unwrap();
+        sessions.retain(|_, session| !session.is_expired(now));
+        let mut infos: Vec<SessionInfo> = sessions
+            .values()
+            .filter(|session| session.user_name == user_name)
+            .map(Session::info)
+            .collect();
+        infos.sort_by_key(|info| info.issued_at);
+        infos
+    }
+}
@@ -1,5 +1,6 @@
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use futures::SinkExt;
 use serde::Serialize;
@@ -10,14 +11,22 @@ use tokio_util::codec::{Framed, LinesCodec};
 use anyhow::Result;
 use thiserror::Error;
 
-use crate::messages::{ServerMessage, UserMessage};
+use crate::messages::{ErrorKind, ServerMessage, UserMessage};
 
+// Legacy password hash, kept only to verify accounts created before argon2 was introduced
 pub fn calculate_hash<T: Hash>(t: &T) -> u64 {
     let mut s = DefaultHasher::new();
     t.hash(&mut s);
     s.finish()
 }
 
+// Seconds since unix epoch, used for all timestamps stored by the server
+pub fn unix_timestamp() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs() as i64)
+}
+
 pub async fn get_next_server_message(
     lines: &mut Framed<TcpStream, LinesCodec>,
 ) -> Option<Result<ServerMessage>> {
@@ -72,10 +81,62 @@ pub enum ChatError {
     NameUsed,
     #[error("Unauthorized connection")]
     UnauthenticatedConnection,
+    #[error("Session expired, log in again")]
+    SessionExpired,
+    #[error("Operation not permitted")]
+    Forbidden,
+    #[error("No such channel")]
+    NoSuchChannel,
+    #[error("Join the channel first")]
+    NotInChannel,
+    #[error("No such message")]
+    NoSuchMessage,
+    #[error("No such user")]
+    NoSuchUser,
+    #[error("No such conversation")]
+    NoSuchConversation,
+    #[error("No such invitation")]
+    NoSuchInvitation,
+    #[error("User is already a member of the channel")]
+    AlreadyMember,
+    #[error("Channel is archived, it can only be read")]
+    ChannelArchived,
+    #[error("You are banned: {reason}")]
+    Banned { reason: String },
+    #[error("You are muted: {reason}")]
+    Muted { reason: String },
+    #[error("Database schema version {found} is newer than supported version {supported}")]
+    UnsupportedSchemaVersion { found: i32, supported: i32 },
     #[error("Runtime error")]
     RuntimeError,
     #[error(transparent)]
     Other(#[from] anyhow::Error),
     #[error("Database error")]
     DatabaseError(#[from] tokio_postgres::Error),
+    #[error("Database error")]
+    SqliteError(#[from] rusqlite::Error),
+}
+
+impl ChatError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            ChatError::InvalidMessage
+            | ChatError::NotInChannel
+            | ChatError::AlreadyMember
+            | ChatError::ChannelArchived => ErrorKind::InvalidMessage,
+            ChatError::InvalidPassword | ChatError::UnauthenticatedConnection => {
+                ErrorKind::Unauthenticated
+            }
+            ChatError::SessionExpired => ErrorKind::SessionExpired,
+            ChatError::Forbidden | ChatError::Banned { .. } | ChatError::Muted { .. } => {
+                ErrorKind::Forbidden
+            }
+            ChatError::NoSuchChannel
+            | ChatError::NoSuchMessage
+            | ChatError::NoSuchUser
+            | ChatError::NoSuchConversation
+            | ChatError::NoSuchInvitation => ErrorKind::NotFound,
+            _ => ErrorKind::Other,
+        }
+    }
 }